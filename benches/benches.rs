@@ -6,7 +6,7 @@ use criterion::{Criterion, criterion_group, criterion_main};
 use rand::{distributions::Alphanumeric, Rng, SeedableRng};
 use tempfile::TempDir;
 
-use kvs::{KvsEngine, KvStore, SledKvsEngine};
+use kvs::{EngineConfig, EngineRegistry};
 
 fn get_random_write_data() -> HashMap<String, String> {
     let mut key_val: HashMap<String, String> = HashMap::new();
@@ -42,46 +42,32 @@ fn get_random_read_keys(data: &HashMap<String, String>) -> Vec<String> {
 fn engine_benchmark(c: &mut Criterion) {
     let random_write_data = get_random_write_data();
     let random_read_keys = get_random_read_keys(&random_write_data);
-    let kvs_temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let sled_temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let registry = EngineRegistry::default();
+    let mut config = EngineConfig::new();
+    config.set("snapshot", "true");
 
-    c.bench_function("kvs write", |b| {
-        b.iter(|| {
-            let mut store = KvStore::open(kvs_temp_dir.path()).expect("unable to open db");
-            for key_val in random_write_data.iter() {
-                store.set(key_val.0.to_string(), key_val.1.to_string()).unwrap();
-            }
-        });
-    });
-
-    c.bench_function("sled write", |b| {
-        b.iter(|| {
-            let mut store = SledKvsEngine::open(sled_temp_dir.path()).expect("unable to open db");
-            for key_val in random_write_data.iter() {
-                store.set(key_val.0.to_string(), key_val.1.to_string()).unwrap();
-            }
-        });
-    });
+    for engine_name in registry.names() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    c.bench_function("kvs read", |b| {
-        b.iter(|| {
-            let store = KvStore::open(kvs_temp_dir.path()).expect("unable to open db");
-            for key in random_read_keys.iter() {
-                let val = store.get(key.to_string()).unwrap().unwrap();
-                assert_eq!(val, random_write_data.get(key).unwrap().to_string());
-            }
+        c.bench_function(&format!("{} write", engine_name), |b| {
+            b.iter(|| {
+                let store = registry.open(engine_name, temp_dir.path(), &config).expect("unable to open db");
+                for key_val in random_write_data.iter() {
+                    store.set(key_val.0, key_val.1).unwrap();
+                }
+            });
         });
-    });
 
-    c.bench_function("sled read", |b| {
-        b.iter(|| {
-            let store = SledKvsEngine::open(sled_temp_dir.path()).expect("unable to open db");
-            for key in random_read_keys.iter() {
-                let val = store.get(key.to_string()).unwrap().unwrap();
-                assert_eq!(val, random_write_data.get(key).unwrap().to_string());
-            }
+        c.bench_function(&format!("{} read", engine_name), |b| {
+            b.iter(|| {
+                let store = registry.open(engine_name, temp_dir.path(), &config).expect("unable to open db");
+                for key in random_read_keys.iter() {
+                    let val = store.get(key).unwrap().unwrap();
+                    assert_eq!(val, random_write_data.get(key).unwrap().to_string());
+                }
+            });
         });
-    });
+    }
 }
 
 criterion_group!(benches, engine_benchmark);
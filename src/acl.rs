@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::{DEFAULT_DATABASE, KvsError, Result};
+
+/// What a request does with a key.
+///
+/// `Admin` on a prefix allows reading and writing it too, requests that are not
+/// about a key need `Admin` on the empty prefix.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    Admin,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Grant {
+    prefix: String,
+    access: Vec<Access>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct User {
+    #[serde(skip)]
+    name: String,
+    password: String,
+    grants: Vec<Grant>,
+    // Besides the default one
+    #[serde(default)]
+    databases: Vec<String>,
+}
+
+impl User {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn allows(&self, access: Access, key: &str) -> bool {
+        self.grants.iter().any(|grant| {
+            key.starts_with(&grant.prefix)
+                && grant.access.iter().any(|granted| *granted == access || *granted == Access::Admin)
+        })
+    }
+
+    /// Fails with `PermissionDenied` unless the user may do `access` on `key`.
+    pub fn check(&self, access: Access, key: &str) -> Result<()> {
+        if self.allows(access, key) {
+            Ok(())
+        } else {
+            Err(KvsError::PermissionDenied(format!("user `{}` has no {:?} access to `{}`", self.name, access, key)))
+        }
+    }
+
+    /// Every user may use `DEFAULT_DATABASE`, and the databases listed for it.
+    pub fn may_select(&self, db: &str) -> bool {
+        db == DEFAULT_DATABASE || self.databases.iter().any(|granted| granted == db)
+    }
+
+    /// Fails with `PermissionDenied` unless the user may use the database `db`.
+    pub fn check_database(&self, db: &str) -> Result<()> {
+        if self.may_select(db) {
+            Ok(())
+        } else {
+            Err(KvsError::PermissionDenied(format!("user `{}` has no access to database `{}`", self.name, db)))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AclFile {
+    users: BTreeMap<String, User>,
+}
+
+/// Users and what they may do, loaded from a JSON file like
+///
+/// ```json
+/// {"users": {"app": {"password": "secret", "grants": [{"prefix": "app/", "access": ["read", "write"]}], "databases": ["app"]}}}
+/// ```
+///
+/// Passwords are unique, so a password alone also identifies its user and works as a token.
+/// A user may only select the databases listed in `databases` besides the default one,
+/// its grants apply to the keys of each of them.
+#[derive(Debug)]
+pub struct Acl {
+    users: RwLock<Vec<Arc<User>>>,
+}
+
+impl Acl {
+    pub fn open(path: impl AsRef<Path>) -> Result<Acl> {
+        let contents = fs::read_to_string(path)?;
+        Acl::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Acl> {
+        let file: AclFile = serde_json::from_str(contents)?;
+        let mut users: Vec<Arc<User>> = Vec::new();
+        for (name, mut user) in file.users {
+            if let Some(other) = users.iter().find(|other| other.password == user.password) {
+                return Err(KvsError::InvalidAcl(format!("users `{}` and `{}` share a password", other.name, name)));
+            }
+            user.name = name;
+            users.push(Arc::new(user));
+        }
+        Ok(Acl { users: RwLock::new(users) })
+    }
+
+    /// Take the users of `other`, returns false if they are the same as before.
+    ///
+    /// Connections already logged in get the new grants of their user with their next
+    /// request, see `refresh`.
+    pub fn replace(&self, other: Acl) -> bool {
+        let other = other.users.into_inner().unwrap();
+        let mut users = self.users.write().unwrap();
+        if *users == other {
+            return false;
+        }
+        *users = other;
+        true
+    }
+
+    /// The current version of `user`, `None` once `replace` removed it or changed its password.
+    pub fn refresh(&self, user: &User) -> Option<Arc<User>> {
+        self.users.read().unwrap().iter()
+            .find(|candidate| candidate.name == user.name && constant_time_eq(candidate.password.as_bytes(), user.password.as_bytes()))
+            .cloned()
+    }
+
+    /// Find the user with `password`, and with the name `user` if it is given.
+    pub fn authenticate(&self, user: Option<&str>, password: &str) -> Result<Arc<User>> {
+        self.users.read().unwrap().iter()
+            .find(|candidate| {
+                user.is_none_or(|name| candidate.name == name)
+                    && constant_time_eq(candidate.password.as_bytes(), password.as_bytes())
+            })
+            .cloned()
+            .ok_or_else(|| KvsError::PermissionDenied("invalid user or password".to_string()))
+    }
+}
+
+// Doesn't stop at the first difference, so the time taken doesn't tell how much of a guess is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
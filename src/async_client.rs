@@ -0,0 +1,210 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::Deserializer;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::{BINARY_HANDSHAKE, KvsError, Protocol, ReloadReport, Request, RequestEnvelope, Response, Result, ServerStats, SlowLogEntry};
+use crate::protocol::{encode_message, IncomingResponse, MAX_FRAME_LEN, PendingResponses, refused_handshake, split_frame};
+use crate::client::{is_closed, MAX_IDLE_CONNECTIONS, resp_to_compacted, resp_to_reloaded, resp_to_removed, resp_to_slow_log, resp_to_stats, resp_to_unit, resp_to_value};
+
+struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    protocol: Protocol,
+    // Whether the last `send` got any response
+    answered: bool,
+}
+
+impl Connection {
+    async fn connect(addr: SocketAddr, protocol: Protocol) -> Result<Connection> {
+        let mut stream = TcpStream::connect(addr).await?;
+        if protocol == Protocol::Binary {
+            stream.write_all(&[BINARY_HANDSHAKE]).await?;
+            let handshake = stream.read_u8().await?;
+            if handshake != BINARY_HANDSHAKE {
+                let mut reply = vec![handshake];
+                stream.read_to_end(&mut reply).await?;
+                return Err(refused_handshake(&reply));
+            }
+        }
+        Ok(Connection { stream, buf: Vec::new(), protocol, answered: false })
+    }
+
+    async fn send(&mut self, envelopes: &[RequestEnvelope]) -> Result<Vec<Response>> {
+        self.answered = false;
+        let mut serialized_requests = Vec::new();
+        for envelope in envelopes {
+            serialized_requests.extend(encode_message(self.protocol, envelope)?);
+        }
+        self.stream.write_all(&serialized_requests).await?;
+
+        let mut pending = PendingResponses::new(envelopes);
+        while !pending.is_complete() {
+            let parsed_len = match self.protocol {
+                Protocol::Json => {
+                    let mut response_reader = Deserializer::from_slice(&self.buf).into_iter::<IncomingResponse>();
+                    while !pending.is_complete() {
+                        match response_reader.next() {
+                            Some(Ok(response)) => pending.insert(response.into_envelope()?)?,
+                            Some(Err(e)) if !e.is_eof() => return Err(KvsError::SerdeError(e)),
+                            _ => break,
+                        }
+                    }
+                    response_reader.byte_offset()
+                }
+                Protocol::Binary => {
+                    let mut parsed_len = 0;
+                    while !pending.is_complete() {
+                        match split_frame(&self.buf[parsed_len..], MAX_FRAME_LEN)? {
+                            Some((frame_len, frame)) => {
+                                pending.insert(bincode::deserialize(frame)?)?;
+                                parsed_len += frame_len;
+                            }
+                            None => break,
+                        }
+                    }
+                    parsed_len
+                }
+            };
+            self.answered |= parsed_len > 0;
+            self.buf.drain(..parsed_len);
+            if !pending.is_complete() && self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(KvsError::IOError(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        Ok(pending.into_responses())
+    }
+}
+
+/// The async version of `KvsClient`.
+///
+/// It can be shared between tasks, every task in flight uses its own connection.
+pub struct AsyncKvsClient {
+    addr: SocketAddr,
+    protocol: Protocol,
+    auth: Option<(Option<String>, String)>,
+    // Selected after logging in
+    db: Option<String>,
+    idle_connections: Mutex<Vec<Connection>>,
+    next_id: AtomicU64,
+}
+
+impl AsyncKvsClient {
+    pub fn new(addr: SocketAddr) -> AsyncKvsClient {
+        AsyncKvsClient::with_protocol(addr, Protocol::Json)
+    }
+
+    pub fn with_protocol(addr: SocketAddr, protocol: Protocol) -> AsyncKvsClient {
+        AsyncKvsClient { addr, protocol, auth: None, db: None, idle_connections: Mutex::new(Vec::new()), next_id: AtomicU64::new(0) }
+    }
+
+    /// See `KvsClient::set_auth`.
+    pub fn set_auth(&mut self, user: Option<&str>, password: &str) {
+        self.auth = Some((user.map(str::to_string), password.to_string()));
+    }
+
+    /// See `KvsClient::set_db`.
+    pub fn set_db(&mut self, db: &str) {
+        self.db = Some(db.to_string());
+    }
+
+    async fn send_command(&self, request: Request) -> Result<Response> {
+        let mut responses = self.pipeline(vec![request]).await?;
+        Ok(responses.remove(0))
+    }
+
+    /// See `KvsClient::pipeline`.
+    pub async fn pipeline(&self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let envelopes: Vec<RequestEnvelope> = requests.into_iter()
+            .map(|request| RequestEnvelope::new(self.next_id.fetch_add(1, Ordering::Relaxed), request))
+            .collect();
+
+        let idle_connection = self.idle_connections.lock().unwrap().pop();
+        if let Some(mut connection) = idle_connection {
+            match connection.send(&envelopes).await {
+                Ok(responses) => {
+                    self.release(connection);
+                    return Ok(responses);
+                }
+                // See `KvsClient::pipeline`
+                Err(e) if !connection.answered && is_closed(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut connection = self.connect().await?;
+        let responses = connection.send(&envelopes).await?;
+        self.release(connection);
+        Ok(responses)
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let mut connection = Connection::connect(self.addr, self.protocol).await?;
+        if let Some((user, password)) = &self.auth {
+            let request = Request::Auth { user: user.clone(), password: password.clone() };
+            let envelope = RequestEnvelope::new(self.next_id.fetch_add(1, Ordering::Relaxed), request);
+            resp_to_unit(connection.send(&[envelope]).await?.remove(0))?;
+        }
+        if let Some(db) = &self.db {
+            let envelope = RequestEnvelope::new(self.next_id.fetch_add(1, Ordering::Relaxed), Request::Select { db: db.clone() });
+            resp_to_unit(connection.send(&[envelope]).await?.remove(0))?;
+        }
+        Ok(connection)
+    }
+
+    fn release(&self, connection: Connection) {
+        let mut idle_connections = self.idle_connections.lock().unwrap();
+        if idle_connections.len() < MAX_IDLE_CONNECTIONS {
+            idle_connections.push(connection);
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<()> {
+        let request = Request::Set { key: key.to_string(), value: value.to_string() };
+        let resp = self.send_command(request).await?;
+        resp_to_unit(resp)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        let request = Request::Get { key: key.to_string() };
+        let resp = self.send_command(request).await?;
+        resp_to_value(resp)
+    }
+
+    pub async fn remove(&self, key: &str) -> Result<()> {
+        let request = Request::Rm { key: key.to_string() };
+        let resp = self.send_command(request).await?;
+        resp_to_removed(resp, key)
+    }
+
+    pub async fn is_key_exist(&self, key: &str) -> Result<bool> {
+        let result = self.get(key).await?.is_some();
+        Ok(result)
+    }
+
+    pub async fn stats(&self) -> Result<ServerStats> {
+        let resp = self.send_command(Request::Stats).await?;
+        resp_to_stats(resp)
+    }
+
+    /// Compact the engine of the server, returns the bytes freed.
+    pub async fn compact(&self) -> Result<u64> {
+        let resp = self.send_command(Request::Compact).await?;
+        resp_to_compacted(resp)
+    }
+
+    /// The slow requests kept by the server, the latest first.
+    pub async fn slow_log(&self) -> Result<Vec<SlowLogEntry>> {
+        let resp = self.send_command(Request::SlowLog).await?;
+        resp_to_slow_log(resp)
+    }
+
+    /// See `KvsClient::reload`.
+    pub async fn reload(&self) -> Result<ReloadReport> {
+        let resp = self.send_command(Request::Reload).await?;
+        resp_to_reloaded(resp)
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+use tokio::task;
+
+use crate::{DynKvsEngine, Request, RequestEnvelope, Response, ResponseEnvelope, Result, SharedEngine, User};
+use crate::resp::{RespHandler, RespSession, RespValue};
+use crate::server::{exec_envelope, exec_request, ServerInfo};
+
+/// Async facade over a `SharedEngine`.
+///
+/// Engine calls block, so they run on the tokio blocking pool, and at most
+/// `max_blocking` of them run at the same time.
+#[derive(Clone)]
+pub struct AsyncKvsEngine {
+    engine: SharedEngine,
+    permits: Arc<Semaphore>,
+    max_blocking: usize,
+}
+
+impl AsyncKvsEngine {
+    pub fn new(engine: SharedEngine, max_blocking: usize) -> AsyncKvsEngine {
+        assert!(max_blocking > 0);
+        AsyncKvsEngine {
+            engine,
+            permits: Arc::new(Semaphore::new(max_blocking)),
+            max_blocking,
+        }
+    }
+
+    pub fn max_blocking(&self) -> usize {
+        self.max_blocking
+    }
+
+    pub(crate) fn engine(&self) -> &SharedEngine {
+        &self.engine
+    }
+
+    // Another engine run on the same blocking threads, for the databases of a server.
+    pub(crate) fn with_engine(&self, engine: SharedEngine) -> AsyncKvsEngine {
+        AsyncKvsEngine { engine, permits: self.permits.clone(), max_blocking: self.max_blocking }
+    }
+
+    // Set the value of a string key to a string.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(&key, &value)).await
+    }
+
+    // Get the string value of a string key.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(&key)).await
+    }
+
+    // Remove a given string key.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.remove(&key)).await
+    }
+
+    // Write everything kept in memory to disk.
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|engine| engine.flush()).await
+    }
+
+    pub(crate) async fn exec_request(&self, info: Arc<ServerInfo>, request: Request, user: Option<Arc<User>>) -> Result<Response> {
+        self.run(move |engine| Ok(exec_request(engine, &info, user.as_deref(), &request))).await
+    }
+
+    pub(crate) async fn exec_envelope(&self, info: Arc<ServerInfo>, envelope: RequestEnvelope, user: Option<Arc<User>>) -> Result<ResponseEnvelope> {
+        self.run(move |engine| Ok(exec_envelope(engine, &info, user.as_deref(), &envelope))).await
+    }
+
+    // The session moves to the blocking pool and back.
+    pub(crate) async fn exec_resp(&self, handler: Arc<RespHandler>, mut session: RespSession, args: Vec<Vec<u8>>) -> Result<(RespValue, RespSession)> {
+        self.run(move |engine| Ok((handler.exec(engine, &mut session, args), session))).await
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&dyn DynKvsEngine) -> Result<T> + Send + 'static,
+              T: Send + 'static {
+        // The semaphore is never closed
+        let permit = self.permits.clone().acquire_owned().await.unwrap();
+        let engine = self.engine.clone();
+        task::spawn_blocking(move || {
+            let result = f(engine.as_ref());
+            drop(permit);
+            result
+        }).await?
+    }
+}
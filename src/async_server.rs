@@ -0,0 +1,713 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Deserializer;
+use slog_scope::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{Mutex, Notify, Semaphore};
+use tokio::{task, time};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+use crate::{Acl, AsyncKvsEngine, BINARY_HANDSHAKE, ConnectionLimits, ErrorCode, KvsError, Metrics, Protocol, RateLimit, RateLimiter, RejectReason, ReloadHandler, Rejections, Request, RequestEnvelope, Response, ResponseEnvelope, Result, ServerAddr, ServerProtocol, SharedEngine, SlowLog, SpanExporter, User};
+use crate::limits::LINGER_TIMEOUT;
+use crate::protocol::{encode_message, IncomingRequest, split_frame};
+use crate::resp::{command_name, parse_command, RespHandler, RespSession, RespValue};
+use crate::server::{bind_unix, DEFAULT_DATABASE, DEFAULT_UNIX_MODE, login, refresh_user, rejection_reply, select_database, ServerInfo, with_default};
+use crate::shutdown::{DEFAULT_SHUTDOWN_TIMEOUT, InFlight, ShutdownHandle};
+use crate::slow_log::SlowQuery;
+use crate::trace::ConnectionTrace;
+use crate::tls::ServerConfig;
+
+/// Serves the same protocol as `KvsServer` on a tokio runtime.
+///
+/// Each connection is a task instead of a pool thread, so idle connections are cheap.
+pub struct AsyncKvsServer {
+    addr: ServerAddr,
+    engine: AsyncKvsEngine,
+    databases: BTreeMap<String, SharedEngine>,
+    protocol: ServerProtocol,
+    unix_mode: u32,
+    tls: Option<TlsAcceptor>,
+    acl: Option<Arc<Acl>>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    limits: ConnectionLimits,
+    metrics: Arc<Metrics>,
+    slow_log: Arc<SlowLog>,
+    spans: Option<Arc<SpanExporter>>,
+    reload: Option<ReloadHandler>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    max_queued: Option<usize>,
+}
+
+// Shared by all connections of a server
+struct ServerState {
+    engine: AsyncKvsEngine,
+    // With the default one
+    databases: BTreeMap<String, SharedEngine>,
+    protocol: ServerProtocol,
+    info: Arc<ServerInfo>,
+    resp_handler: Arc<RespHandler>,
+    acl: Option<Arc<Acl>>,
+    shutdown: ShutdownHandle,
+    in_flight: InFlight,
+    limits: ConnectionLimits,
+    metrics: Arc<Metrics>,
+    rejections: Arc<Rejections>,
+    connections: AtomicUsize,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Requests in envelopes not answered yet
+    queued: Option<Arc<Semaphore>>,
+}
+
+impl ServerState {
+    fn check_rate(&self, user: Option<&User>, peer: Option<IpAddr>) -> Result<()> {
+        match &self.rate_limiter {
+            Some(limiter) => limiter.check(user, peer),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsyncKvsServer {
+    pub fn new(addr: impl Into<ServerAddr>, engine: AsyncKvsEngine) -> Self {
+        AsyncKvsServer::with_protocol(addr, engine, ServerProtocol::Kvs)
+    }
+
+    pub fn with_protocol(addr: impl Into<ServerAddr>, engine: AsyncKvsEngine, protocol: ServerProtocol) -> Self {
+        AsyncKvsServer {
+            addr: addr.into(),
+            engine,
+            databases: BTreeMap::new(),
+            protocol,
+            unix_mode: DEFAULT_UNIX_MODE,
+            tls: None,
+            acl: None,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            limits: ConnectionLimits::default(),
+            metrics: Arc::new(Metrics::new()),
+            slow_log: Arc::new(SlowLog::default()),
+            spans: None,
+            reload: None,
+            rate_limiter: None,
+            max_queued: None,
+        }
+    }
+
+    /// See `KvsServer::add_database`, its requests run on the blocking threads of the
+    /// `AsyncKvsEngine` of the server.
+    pub fn add_database(&mut self, name: &str, engine: SharedEngine) {
+        assert_ne!(name, DEFAULT_DATABASE);
+        self.databases.insert(name.to_string(), engine);
+    }
+
+    /// See `KvsServer::set_unix_mode`.
+    pub fn set_unix_mode(&mut self, mode: u32) {
+        self.unix_mode = mode;
+    }
+
+    /// See `KvsServer::set_tls`.
+    pub fn set_tls(&mut self, config: Arc<ServerConfig>) {
+        self.tls = Some(TlsAcceptor::from(config));
+    }
+
+    /// See `KvsServer::set_acl`.
+    pub fn set_acl(&mut self, acl: Arc<Acl>) {
+        self.acl = Some(acl);
+    }
+
+    /// See `KvsServer::set_limits`.
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.limits = limits;
+    }
+
+    /// See `KvsServer::rejections`.
+    pub fn rejections(&self) -> Arc<Rejections> {
+        self.metrics.rejections()
+    }
+
+    /// See `KvsServer::set_metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// See `KvsServer::set_slow_log`.
+    pub fn set_slow_log(&mut self, slow_log: Arc<SlowLog>) {
+        self.slow_log = slow_log;
+    }
+
+    pub fn slow_log(&self) -> Arc<SlowLog> {
+        self.slow_log.clone()
+    }
+
+    /// See `KvsServer::set_span_exporter`.
+    pub fn set_span_exporter(&mut self, spans: Arc<SpanExporter>) {
+        self.spans = Some(spans);
+    }
+
+    /// See `KvsServer::set_rate_limit`.
+    pub fn set_rate_limit(&mut self, limit: RateLimit) {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(Some(limit))));
+    }
+
+    /// See `KvsServer::set_rate_limiter`.
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// See `KvsServer::set_reload_handler`.
+    pub fn set_reload_handler(&mut self, reload: ReloadHandler) {
+        self.reload = Some(reload);
+    }
+
+    /// Answer with `ErrorCode::Busy` once `jobs` requests in envelopes are running or
+    /// waiting for the engine. Connections are cheap tasks and are not counted.
+    pub fn set_max_queued(&mut self, jobs: usize) {
+        self.max_queued = Some(jobs);
+    }
+
+    /// See `KvsServer::shutdown_handle`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// See `KvsServer::set_shutdown_timeout`.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Serve until the server is shut down, see `shutdown_handle`.
+    pub async fn handle_connection(&mut self) -> Result<()> {
+        let databases = with_default(&self.databases, self.engine.engine());
+        let state = Arc::new(ServerState {
+            engine: self.engine.clone(),
+            protocol: self.protocol,
+            info: Arc::new(ServerInfo::new(self.engine.max_blocking(), self.slow_log.clone(), self.spans.clone(), self.reload.clone())),
+            resp_handler: Arc::new(RespHandler::new(self.acl.clone(), databases.keys().cloned().collect())),
+            databases,
+            acl: self.acl.clone(),
+            shutdown: self.shutdown.clone(),
+            in_flight: InFlight::default(),
+            limits: self.limits.clone(),
+            metrics: self.metrics.clone(),
+            rejections: self.metrics.rejections(),
+            connections: AtomicUsize::new(0),
+            rate_limiter: self.rate_limiter.clone(),
+            queued: self.max_queued.map(|jobs| Arc::new(Semaphore::new(jobs))),
+        });
+        match &self.addr {
+            ServerAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                loop {
+                    tokio::select! {
+                        accepted = listener.accept() => match accepted {
+                            Ok((stream, addr)) => self.spawn_stream(&state, stream, Some(addr.ip())),
+                            Err(e) => error!("Connection error: {}", e),
+                        },
+                        _ = self.shutdown.wait() => break,
+                    }
+                }
+            }
+            ServerAddr::Unix(path) => {
+                let listener = bind_unix(path, self.unix_mode)?;
+                listener.set_nonblocking(true)?;
+                let listener = UnixListener::from_std(listener)?;
+                loop {
+                    tokio::select! {
+                        accepted = listener.accept() => match accepted {
+                            Ok((stream, _)) => self.spawn_stream(&state, stream, None),
+                            Err(e) => error!("Connection error: {}", e),
+                        },
+                        _ = self.shutdown.wait() => break,
+                    }
+                }
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        info!("Shutting down {}", self.addr);
+        let in_flight = state.in_flight.clone();
+        let timeout = self.shutdown_timeout;
+        if !task::spawn_blocking(move || in_flight.wait(timeout)).await? {
+            warn!("Requests still running after {:?}, stop waiting", timeout);
+        }
+        let mut result = Ok(());
+        for (name, engine) in state.databases.iter() {
+            if let Err(e) = self.engine.with_engine(engine.clone()).flush().await {
+                error!("Can't flush engine of database {}: {}", name, e);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    fn spawn_stream<S>(&self, state: &Arc<ServerState>, mut stream: S, peer: Option<IpAddr>)
+        where S: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+        if state.limits.max_connections.is_some_and(|max| state.connections.load(Ordering::Relaxed) >= max) {
+            state.rejections.add(RejectReason::TooManyConnections);
+            // A TLS client can't read anything before the handshake
+            if self.tls.is_none() {
+                let protocol = state.protocol;
+                tokio::spawn(async move {
+                    if let Ok(reply) = rejection_reply(protocol, RejectReason::TooManyConnections) {
+                        let _ = stream.write_all(&reply).await;
+                        let (mut reader, mut writer) = tokio::io::split(stream);
+                        linger(&mut reader, &mut writer).await;
+                    }
+                });
+            }
+            return;
+        }
+        debug!("Receive connection.");
+        let conn = ConnectionTrace::new(peer, &slog_scope::logger());
+        let state = state.clone();
+        let tls = self.tls.clone();
+        let working = state.in_flight.start();
+        state.connections.fetch_add(1, Ordering::Relaxed);
+        state.metrics.connection_opened();
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(acceptor) => match accept_tls(&state, &acceptor, stream).await {
+                    Ok(stream) => serve_stream(&state, stream, &conn).await,
+                    Err(e) => Err(e),
+                },
+                None => serve_stream(&state, stream, &conn).await,
+            };
+            if let Err(e) = result {
+                slog::error!(conn.logger(), "Connection error: {}", e);
+            }
+            state.connections.fetch_sub(1, Ordering::Relaxed);
+            state.metrics.connection_closed();
+            drop(working);
+        });
+    }
+}
+
+async fn serve_stream<S>(state: &ServerState, stream: S, conn: &ConnectionTrace) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    match state.protocol {
+        ServerProtocol::Kvs => handle_stream(state, stream, conn).await,
+        ServerProtocol::Resp => handle_resp_stream(state, stream, conn).await,
+    }
+}
+
+// Returns 0 at the end of the stream, and once the server shuts down.
+//
+// `started` is when the request at the start of `buf` started, a connection breaking
+// the limits fails with `KvsError::Rejected`.
+async fn read_more<R>(reader: &mut R, buf: &mut Vec<u8>, state: &ServerState, started: &mut Option<Instant>) -> Result<usize>
+    where R: AsyncRead + Unpin {
+    if buf.is_empty() {
+        *started = None;
+    }
+    let (timeout, reason) = match started {
+        Some(started) => (state.limits.read_timeout.map(|limit| limit.saturating_sub(started.elapsed())), RejectReason::ReadTimeout),
+        None => (state.limits.idle_timeout, RejectReason::IdleTimeout),
+    };
+    let deadline = async {
+        match timeout {
+            Some(timeout) => time::sleep(timeout).await,
+            None => future::pending().await,
+        }
+    };
+    let read = tokio::select! {
+        read = reader.read_buf(buf) => read?,
+        _ = state.shutdown.wait() => return Ok(0),
+        _ = deadline => return Err(reject(state, reason)),
+    };
+    if read > 0 && started.is_none() {
+        *started = Some(Instant::now());
+    }
+    if buf.len() > state.limits.max_request_size {
+        return Err(reject(state, RejectReason::RequestTooLarge));
+    }
+    Ok(read)
+}
+
+fn reject(state: &ServerState, reason: RejectReason) -> KvsError {
+    state.rejections.add(reason);
+    KvsError::Rejected(reason)
+}
+
+// The TLS handshake must be done within the idle timeout, as the first request must start.
+async fn accept_tls<S>(state: &ServerState, acceptor: &TlsAcceptor, stream: S) -> Result<TlsStream<S>>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let mut accept = acceptor.accept(stream);
+    let accepted = match state.limits.idle_timeout {
+        // Counted before the connection is closed
+        Some(timeout) => match time::timeout(timeout, &mut accept).await {
+            Ok(accepted) => accepted,
+            Err(_) => return Err(reject(state, RejectReason::IdleTimeout)),
+        },
+        None => accept.await,
+    };
+    Ok(accepted?)
+}
+
+// See `limits::linger`, `reader` and `writer` are the halves of a rejected connection.
+async fn linger<R, W>(reader: &mut R, writer: &mut W)
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let _ = writer.shutdown().await;
+    let mut buf = [0; 4096];
+    let _ = time::timeout(LINGER_TIMEOUT, async {
+        while let Ok(read) = reader.read(&mut buf).await {
+            if read == 0 {
+                break;
+            }
+        }
+    }).await;
+}
+
+async fn write_in_time<W>(writer: &mut W, buf: &[u8], timeout: Option<Duration>, rejections: &Rejections) -> Result<()>
+    where W: AsyncWrite + Unpin + ?Sized {
+    match timeout {
+        Some(timeout) => match time::timeout(timeout, writer.write_all(buf)).await {
+            Ok(written) => Ok(written?),
+            Err(_) => {
+                rejections.add(RejectReason::WriteTimeout);
+                Err(KvsError::Rejected(RejectReason::WriteTimeout))
+            }
+        },
+        None => Ok(writer.write_all(buf).await?),
+    }
+}
+
+struct ResponseWriter {
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    protocol: Protocol,
+    write_timeout: Option<Duration>,
+    rejections: Arc<Rejections>,
+    // Stops reading the connection once a response is not written in time
+    closed: Notify,
+}
+
+impl ResponseWriter {
+    async fn send<T: Serialize>(&self, response: &T) -> Result<()> {
+        self.write(&encode_message(self.protocol, response)?).await
+    }
+
+    async fn write(&self, buf: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        let result = write_in_time(&mut *writer, buf, self.write_timeout, &self.rejections).await;
+        if let Err(KvsError::Rejected(_)) = result {
+            self.closed.notify_one();
+        }
+        result
+    }
+}
+
+// Follows the nesting of JSON text to tell when a top level value may have ended, so
+// a request arriving in many reads is parsed once rather than after every read.
+#[derive(Default)]
+struct JsonValues {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonValues {
+    // Whether a value ended in `bytes`, or they hold something the parser should reject.
+    fn feed(&mut self, bytes: &[u8]) -> bool {
+        let mut ended = false;
+        for &b in bytes {
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                    ended |= self.depth == 0;
+                }
+                continue;
+            }
+            match b {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    ended |= self.depth == 0;
+                }
+                b' ' | b'\t' | b'\r' | b'\n' => {}
+                _ => ended |= self.depth == 0,
+            }
+        }
+        ended
+    }
+}
+
+// Records are logged with the logger of `conn`, tasks don't keep the scope of slog_scope.
+async fn handle_stream<S>(state: &ServerState, stream: S, conn: &ConnectionTrace) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    let peer = conn.peer();
+    let mut engine = state.engine.clone();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf: Vec<u8> = Vec::new();
+    let mut started = None;
+    match read_more(&mut reader, &mut buf, state, &mut started).await {
+        Ok(0) => return Ok(()),
+        Ok(_) => {}
+        Err(KvsError::Rejected(reason)) => {
+            let _ = writer.write_all(&rejection_reply(state.protocol, reason)?).await;
+            linger(&mut reader, &mut writer).await;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    let protocol = if buf[0] == BINARY_HANDSHAKE {
+        buf.drain(..1);
+        Protocol::Binary
+    } else {
+        Protocol::Json
+    };
+    let writer = Arc::new(ResponseWriter {
+        writer: Mutex::new(Box::new(writer)),
+        protocol,
+        write_timeout: state.limits.write_timeout,
+        rejections: state.rejections.clone(),
+        closed: Notify::new(),
+    });
+    if protocol == Protocol::Binary {
+        writer.write(&[BINARY_HANDSHAKE]).await?;
+    }
+    let mut user = None;
+    let mut selected_db = DEFAULT_DATABASE.to_string();
+    let mut json_values = JsonValues::default();
+    let mut scanned = 0;
+
+    loop {
+        let mut requests = Vec::new();
+        match protocol {
+            Protocol::Json => {
+                // Parsing starts over at the last unparsed request, so only once one may be complete
+                if json_values.feed(&buf[scanned..]) {
+                    let mut request_reader = Deserializer::from_slice(&buf).into_iter::<IncomingRequest>();
+                    loop {
+                        match request_reader.next() {
+                            Some(Ok(request)) => requests.push(request),
+                            Some(Err(e)) if e.is_eof() => break,
+                            Some(Err(e)) => {
+                                // There is no way to find the start of the next request
+                                slog::error!(conn.logger(), "Can't parse request: {}", e);
+                                return Ok(());
+                            }
+                            None => break,
+                        }
+                    }
+                    let parsed_len = request_reader.byte_offset();
+                    buf.drain(..parsed_len);
+                }
+                scanned = buf.len();
+            }
+            Protocol::Binary => {
+                let mut parsed_len = 0;
+                loop {
+                    let (frame_len, frame) = match split_frame(&buf[parsed_len..], state.limits.max_request_size) {
+                        Ok(Some(split)) => split,
+                        Ok(None) => break,
+                        Err(KvsError::FrameTooLarge(_, _)) => {
+                            state.rejections.add(RejectReason::RequestTooLarge);
+                            return Ok(());
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    // The next frame starts right after this one, so a broken frame is skipped
+                    match bincode::deserialize::<RequestEnvelope>(frame) {
+                        Ok(envelope) => requests.push(IncomingRequest::Envelope(envelope)),
+                        Err(e) => slog::error!(conn.logger(), "Can't parse request: {}", e),
+                    }
+                    parsed_len += frame_len;
+                }
+                buf.drain(..parsed_len);
+            }
+        }
+
+        for request in requests {
+            if let (Some(acl), Some(logged_in)) = (&state.acl, user.as_deref()) {
+                match refresh_user(acl, logged_in, &selected_db) {
+                    Ok(current) => user = Some(current),
+                    Err(e) => {
+                        writer.send(&request.reply(e.into())).await?;
+                        return Ok(());
+                    }
+                }
+            }
+            if let Err(e) = state.check_rate(user.as_deref(), peer) {
+                state.metrics.record_error(e.code());
+                writer.send(&request.reply(e.into())).await?;
+                continue;
+            }
+            let started = Instant::now();
+            if let (Some(acl), None) = (&state.acl, &user) {
+                let trace = conn.request(request.request().name());
+                let (logged_in, response) = login(acl, &request);
+                state.metrics.record(request.request().name(), started.elapsed(), response.response().error_code());
+                state.info.slow_log().record(SlowQuery::request(request.request()), started.elapsed(), peer);
+                trace.finish(response.response().error_code(), state.info.spans());
+                writer.send(&response).await?;
+                if logged_in.is_none() {
+                    return Ok(());
+                }
+                user = logged_in;
+                continue;
+            }
+            // Later requests of the connection must not run before the switch
+            if let Request::Select { db } = request.request() {
+                let trace = conn.request(request.request().name());
+                let response = match select_database(&state.databases, user.as_deref(), db) {
+                    Ok(selected) => {
+                        engine = state.engine.with_engine(selected);
+                        selected_db = db.clone();
+                        Response::Ok
+                    }
+                    Err(e) => e.into(),
+                };
+                state.metrics.record(request.request().name(), started.elapsed(), response.error_code());
+                trace.finish(response.error_code(), state.info.spans());
+                writer.send(&request.reply(response)).await?;
+                continue;
+            }
+            match request {
+                IncomingRequest::Bare(request) => {
+                    let name = request.name();
+                    let trace = conn.request(name);
+                    let query = SlowQuery::request(&request);
+                    let response = engine.exec_request(state.info.clone(), request, user.clone()).await?;
+                    state.metrics.record(name, started.elapsed(), response.error_code());
+                    state.info.slow_log().record(query.response(&response), started.elapsed(), peer);
+                    trace.finish(response.error_code(), state.info.spans());
+                    writer.send(&response).await?;
+                    slog::debug!(trace.logger(), "Send response.");
+                }
+                IncomingRequest::Envelope(envelope) => {
+                    let permit = match &state.queued {
+                        Some(queued) => match queued.clone().try_acquire_owned() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                state.metrics.record_error(ErrorCode::Busy);
+                                writer.send(&ResponseEnvelope { id: envelope.id, response: KvsError::ServerBusy.into() }).await?;
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let engine = engine.clone();
+                    let info = state.info.clone();
+                    let metrics = state.metrics.clone();
+                    let writer = writer.clone();
+                    let user = user.clone();
+                    let working = state.in_flight.start();
+                    let trace = conn.request(envelope.request.name());
+                    tokio::spawn(async move {
+                        let name = envelope.request.name();
+                        let query = SlowQuery::request(&envelope.request);
+                        let result = match engine.exec_envelope(info.clone(), envelope, user).await {
+                            Ok(response) => {
+                                metrics.record(name, started.elapsed(), response.response.error_code());
+                                info.slow_log().record(query.response(&response.response), started.elapsed(), peer);
+                                trace.finish(response.response.error_code(), info.spans());
+                                writer.send(&response).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        match result {
+                            Ok(_) => slog::debug!(trace.logger(), "Send response."),
+                            Err(e) => slog::error!(trace.logger(), "Failed to send response: {}", e),
+                        };
+                        drop(permit);
+                        drop(working);
+                    });
+                }
+            }
+        }
+
+        let read = tokio::select! {
+            read = read_more(&mut reader, &mut buf, state, &mut started) => read,
+            _ = writer.closed.notified() => return Ok(()),
+        };
+        match read {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            // Frames only carry envelopes, so a binary client is not told why it is closed
+            Err(KvsError::Rejected(reason)) => {
+                if protocol == Protocol::Json {
+                    let _ = writer.write(&rejection_reply(state.protocol, reason)?).await;
+                    linger(&mut reader, &mut *writer.writer.lock().await).await;
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn handle_resp_stream<S>(state: &ServerState, mut stream: S, conn: &ConnectionTrace) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let peer = conn.peer();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut session = RespSession::default();
+    let mut started = None;
+    loop {
+        match read_more(&mut stream, &mut buf, state, &mut started).await {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(KvsError::Rejected(reason)) => {
+                let _ = stream.write_all(&rejection_reply(state.protocol, reason)?).await;
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                linger(&mut reader, &mut writer).await;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        let mut replies = Vec::new();
+        let mut parsed_len = 0;
+        loop {
+            match parse_command(&buf[parsed_len..]) {
+                Ok(Some((command_len, args))) => {
+                    parsed_len += command_len;
+                    if !args.is_empty() {
+                        if let Err(e) = state.check_rate(session.user(), peer) {
+                            state.metrics.record_error(e.code());
+                            RespValue::failed(&e).encode(&mut replies);
+                            continue;
+                        }
+                        let started = Instant::now();
+                        let name = command_name(&args);
+                        let trace = conn.request(name);
+                        let query = SlowQuery::command(&args);
+                        let engine = state.engine.with_engine(state.databases[session.db()].clone());
+                        let (reply, next_session) = engine.exec_resp(state.resp_handler.clone(), session, args).await?;
+                        state.metrics.record(name, started.elapsed(), reply.error_code());
+                        state.info.slow_log().record(query.reply(&reply), started.elapsed(), peer);
+                        trace.finish(reply.error_code(), state.info.spans());
+                        session = next_session;
+                        reply.encode(&mut replies);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // There is no way to find the start of the next command
+                    RespValue::failed(&e).encode(&mut replies);
+                    return write_in_time(&mut stream, &replies, state.limits.write_timeout, &state.rejections).await;
+                }
+            }
+        }
+        buf.drain(..parsed_len);
+        write_in_time(&mut stream, &replies, state.limits.write_timeout, &state.rejections).await?;
+        slog::debug!(conn.logger(), "Send response.");
+    }
+}
@@ -1,12 +1,14 @@
 extern crate anyhow;
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::SystemTime;
 
 use anyhow::Result;
 use argh::FromArgs;
 
-use kvs::KvsClient;
+use kvs::{KvsClient, Protocol, ServerAddr, ServerStats, SlowLogEntry, tls};
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Kvs client
@@ -17,6 +19,33 @@ struct Args {
     #[argh(option)]
     /// IP:port, used to connect server
     addr: Option<String>,
+    #[argh(option)]
+    /// path of the Unix socket of the server, used instead of --addr
+    unix: Option<PathBuf>,
+    #[argh(option)]
+    /// wire protocol [possible values: json, binary]
+    protocol: Option<String>,
+    #[argh(option)]
+    /// PEM CA certificates, connect with TLS to a server signed by one of them
+    ca: Option<PathBuf>,
+    #[argh(option)]
+    /// name the server certificate must be valid for, default is the host of --addr
+    sni: Option<String>,
+    #[argh(option)]
+    /// PEM client certificate for servers that ask for one
+    tls_cert: Option<PathBuf>,
+    #[argh(option)]
+    /// PEM private key of --tls-cert
+    tls_key: Option<PathBuf>,
+    #[argh(option)]
+    /// user name to log in with, may be left out if the password is unique
+    user: Option<String>,
+    #[argh(option)]
+    /// password or token to log in with
+    password: Option<String>,
+    #[argh(option)]
+    /// database of the server to use instead of the default one
+    db: Option<String>,
     #[argh(switch, short = 'V')]
     /// print version information
     version: bool,
@@ -28,6 +57,10 @@ enum SubCommandEnum {
     Get(GetSubCommand),
     Set(SetSubCommand),
     Rm(RmSubCommand),
+    Stats(StatsSubCommand),
+    Compact(CompactSubCommand),
+    SlowLog(SlowLogSubCommand),
+    Reload(ReloadSubCommand),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -61,6 +94,26 @@ struct RmSubCommand {
     key: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print statistics of the server and its engine
+#[argh(subcommand, name = "stats")]
+struct StatsSubCommand {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Compact the engine of the server and print the bytes freed
+#[argh(subcommand, name = "compact")]
+struct CompactSubCommand {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print the slow requests kept by the server, the latest first
+#[argh(subcommand, name = "slowlog")]
+struct SlowLogSubCommand {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Make the server reload its settings and print the ones that changed
+#[argh(subcommand, name = "reload")]
+struct ReloadSubCommand {}
+
 
 fn main() -> Result<()> {
     let args: Args = argh::from_env();
@@ -70,15 +123,55 @@ fn main() -> Result<()> {
         exit(0);
     }
 
-    let addr = args.addr.unwrap_or("127.0.0.1:4000".to_string());
-    let socket_addr: SocketAddr = match addr.parse() {
+    let server_addr: ServerAddr = match args.unix {
+        Some(path) => path.into(),
+        None => {
+            let addr = args.addr.unwrap_or("127.0.0.1:4000".to_string());
+            match addr.parse::<SocketAddr>() {
+                Ok(val) => val.into(),
+                Err(_e) => {
+                    println!("The address {} is invalid", &addr);
+                    exit(-1);
+                }
+            }
+        }
+    };
+    let protocol = args.protocol.unwrap_or("json".to_string());
+    let protocol: Protocol = match protocol.parse() {
         Ok(val) => val,
         Err(_e) => {
-            println!("The address {} is invalid", &addr);
+            println!("The protocol {} is invalid", &protocol);
             exit(-1);
         }
     };
-    let client = KvsClient::new(socket_addr);
+    let default_sni = match &server_addr {
+        ServerAddr::Tcp(addr) => addr.ip().to_string(),
+        ServerAddr::Unix(_) => "localhost".to_string(),
+    };
+    let mut client = KvsClient::with_protocol(server_addr, protocol);
+    if let Some(ca) = args.ca {
+        let identity = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            (None, None) => None,
+            _ => {
+                println!("A client certificate needs both --tls-cert and --tls-key");
+                exit(-1);
+            }
+        };
+        let config = tls::client_config(&ca, identity)?;
+        client.set_tls(config, &args.sni.unwrap_or(default_sni))?;
+    }
+    match (&args.user, &args.password) {
+        (user, Some(password)) => client.set_auth(user.as_deref(), password),
+        (Some(_), None) => {
+            println!("--user needs --password");
+            exit(-1);
+        }
+        (None, None) => {}
+    }
+    if let Some(db) = &args.db {
+        client.set_db(db);
+    }
 
     let subcommand = match args.subcommand {
         Some(command) => command,
@@ -111,7 +204,64 @@ fn main() -> Result<()> {
             let value = command_arg.value;
             client.set(&key, &value)?;
         }
+        SubCommandEnum::Stats(_) => print_stats(&client.stats()?),
+        SubCommandEnum::Compact(_) => println!("reclaimed_bytes: {}", client.compact()?),
+        SubCommandEnum::SlowLog(_) => client.slow_log()?.iter().for_each(print_slow_log_entry),
+        SubCommandEnum::Reload(_) => {
+            let report = client.reload()?;
+            println!("applied: {}", report.applied.join(", "));
+            println!("restart_required: {}", report.restart_required.join(", "));
+        }
     };
 
     Ok(())
 }
+
+// One `name: value` line each, numbers the engine does not keep are left out.
+fn print_stats(stats: &ServerStats) {
+    let engine = &stats.engine_stats;
+    println!("version: {}", stats.version);
+    println!("engine: {}", stats.engine);
+    println!("uptime_seconds: {}", stats.uptime.as_secs());
+    println!("threads: {}", stats.threads);
+    let counts = [
+        ("keys", engine.keys),
+        ("size_bytes", engine.size_bytes),
+        ("live_bytes", engine.live_bytes),
+        ("dead_bytes", engine.dead_bytes),
+        ("since_last_compact_log_num", engine.since_last_compact_log_num),
+    ];
+    for (name, count) in counts {
+        if let Some(count) = count {
+            println!("{}: {}", name, count);
+        }
+    }
+    println!("compactions: {}", engine.compactions);
+    println!("compaction_seconds: {:.3}", engine.compaction_time.as_secs_f64());
+    println!("reclaimed_bytes: {}", engine.reclaimed_bytes);
+    if let Some(last) = engine.last_compaction {
+        let ago = SystemTime::now().duration_since(last).unwrap_or_default();
+        println!("last_compaction_seconds_ago: {}", ago.as_secs());
+    }
+    for (file, size) in engine.files.iter() {
+        println!("file {}: {}", file, size);
+    }
+}
+
+// One line per request, `name=value` pairs after the operation, unknown ones left out.
+fn print_slow_log_entry(entry: &SlowLogEntry) {
+    let mut line = entry.op.clone();
+    if let Some(key) = &entry.key {
+        line.push_str(&format!(" key={}", key));
+    }
+    if let Some(size) = entry.value_size {
+        line.push_str(&format!(" value_size={}", size));
+    }
+    line.push_str(&format!(" duration_ms={:.3}", entry.duration.as_secs_f64() * 1000.0));
+    if let Some(client) = entry.client {
+        line.push_str(&format!(" client={}", client));
+    }
+    let ago = SystemTime::now().duration_since(entry.time).unwrap_or_default();
+    line.push_str(&format!(" seconds_ago={}", ago.as_secs()));
+    println!("{}", line);
+}
@@ -2,23 +2,28 @@
 extern crate slog;
 #[macro_use]
 extern crate slog_scope;
+extern crate slog_json;
 extern crate slog_term;
-extern crate strum;
 
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::mem;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use argh::FromArgs;
-use slog::{Drain, PushFnValue, PushFnValueSerializer, Record};
+use slog::{Drain, Level, Logger, OwnedKVList, PushFnValue, PushFnValueSerializer, Record};
 
-use kvs::{get_engine_name, KvsServer, KvStore, SledKvsEngine, write_engine};
-
-#[derive(Debug, Eq, PartialEq, strum_macros::Display, strum_macros::EnumString)]
-#[strum(serialize_all = "snake_case")]
-enum Engine {
-    Kvs,
-    Sled,
-}
+use kvs::config::DatabaseSection;
+use kvs::{Acl, AsyncKvsEngine, AsyncKvsServer, ConfigFile, ConnectionLimits, DEFAULT_DATABASE, DEFAULT_UNIX_MODE, EngineConfig, EngineRegistry, HttpGateway, KvsError, KvsServer, Metrics, MetricsEndpoint, PoolKind, RateLimit, RateLimiter, ReloadHandler, ReloadReport, ServerAddr, ServerProtocol, ShutdownHandle, SlowLog, SpanExporter, tls};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
 
 #[derive(FromArgs)]
 /// Kvs server
@@ -27,16 +32,151 @@ struct Args {
     #[argh(switch, short = 'V')]
     version: bool,
 
+    /// TOML file of settings, the flags override it
+    #[argh(option)]
+    config: Option<PathBuf>,
+
     /// IP:port, used to connect server
     #[argh(option)]
     addr: Option<String>,
 
-    /// specify an engine [possible values: kvs, sled]
+    /// specify an engine [possible values: kvs, sled, memory]
+    #[argh(option)]
+    engine: Option<String>,
+
+    /// directory of the default database, the others are in its databases directory, default ./
+    #[argh(option)]
+    data_dir: Option<PathBuf>,
+
+    /// serve another database as name or name=engine, can be repeated
+    #[argh(option)]
+    db: Vec<String>,
+
+    /// engine option as key=value, can be repeated
+    #[argh(option)]
+    engine_opt: Vec<String>,
+
+    /// kvs engine: share of dead bytes in the log from which it is compacted, default 0.5
+    #[argh(option)]
+    compact_dead_ratio: Option<f64>,
+
+    /// kvs engine: dead bytes in the log from which it is compacted, default 1 MiB
+    #[argh(option)]
+    compact_dead_bytes: Option<u64>,
+
+    /// write every change to disk before answering
+    #[argh(switch)]
+    sync: bool,
+
+    /// serve with a thread pool or a tokio runtime [possible values: sync, async]
+    #[argh(option)]
+    mode: Option<String>,
+
+    /// thread pool of the sync mode [possible values: shared, rayon]
+    #[argh(option)]
+    pool: Option<String>,
+
+    /// number of threads serving connections, in async mode running engine calls, default one per CPU
+    #[argh(option)]
+    threads: Option<usize>,
+
+    /// protocol spoken to clients [possible values: kvs, resp]
+    #[argh(option)]
+    protocol: Option<String>,
+
+    /// IP:port, also serve the HTTP/JSON gateway on it
+    #[argh(option)]
+    http: Option<String>,
+
+    /// path of a Unix socket to listen on, TCP is only served as well if --addr is given
+    #[argh(option)]
+    unix: Option<PathBuf>,
+
+    /// permission bits of the Unix socket file in octal, default 660
+    #[argh(option)]
+    unix_mode: Option<String>,
+
+    /// PEM certificate chain, serve TLS with --tls-key
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificates, clients must present a certificate signed by one of them
+    #[argh(option)]
+    tls_client_ca: Option<PathBuf>,
+
+    /// JSON file of users and their grants, clients must log in when it is given
+    #[argh(option)]
+    acl: Option<PathBuf>,
+
+    /// connections served at the same time by each listener, more are rejected
+    #[argh(option)]
+    max_connections: Option<usize>,
+
+    /// seconds a connection may wait before sending a request
+    #[argh(option)]
+    idle_timeout: Option<u64>,
+
+    /// seconds a client may take to send a request once it started
+    #[argh(option)]
+    read_timeout: Option<u64>,
+
+    /// seconds a client may take to receive a response
+    #[argh(option)]
+    write_timeout: Option<u64>,
+
+    /// largest request accepted in bytes
+    #[argh(option)]
+    max_request_size: Option<usize>,
+
+    /// requests per second allowed to each client address or user
+    #[argh(option)]
+    rate_limit: Option<u32>,
+
+    /// requests a client may send at once above --rate-limit, default the rate
     #[argh(option)]
-    engine: Option<Engine>,
+    rate_burst: Option<u32>,
+
+    /// connections or requests waiting for a thread before the server answers busy
+    #[argh(option)]
+    max_queued: Option<usize>,
+
+    /// IP:port, serve Prometheus metrics on http://IP:port/metrics
+    #[argh(option)]
+    metrics: Option<String>,
+
+    /// milliseconds a request may take before it is kept in the slow log, default 10
+    #[argh(option)]
+    slowlog_threshold: Option<u64>,
+
+    /// slow requests kept, the oldest are dropped first, default 128
+    #[argh(option)]
+    slowlog_len: Option<usize>,
+
+    /// lowest level logged [possible values: critical, error, warning, info, debug, trace], default trace
+    #[argh(option)]
+    log_level: Option<String>,
+
+    /// format of log records [possible values: text, json], default text
+    #[argh(option)]
+    log_format: Option<String>,
+
+    /// log to this file instead of stderr
+    #[argh(option)]
+    log_file: Option<PathBuf>,
+
+    /// export an OpenTelemetry span of every request to this file as OTLP/JSON lines
+    #[argh(option)]
+    trace_file: Option<PathBuf>,
 }
 
 
+// Settings a reload can change while the servers run, by their name in the config file
+const LIVE_SETTINGS: [&str; 6] = ["acl", "limits.rate_limit", "limits.rate_burst", "slowlog.threshold", "slowlog.len", "log.level"];
+
 fn main() {
     let args: Args = argh::from_env();
 
@@ -45,73 +185,640 @@ fn main() {
         exit(0);
     }
 
-    let addr = args.addr.unwrap_or("127.0.0.1:4000".to_string());
-    let socket_addr: SocketAddr = match addr.parse() {
+    let (mut args, mut databases) = load_args(args).unwrap_or_else(|message| {
+        println!("{}", message);
+        exit(-1);
+    });
+    let settings = settings(&args, &databases);
+    let log_level = parse_log_level(&args).unwrap_or_else(|message| {
+        println!("{}", message);
+        exit(-1);
+    });
+    let rate_limit = parse_rate_limit(&args).unwrap_or_else(|message| {
+        println!("{}", message);
+        exit(-1);
+    });
+    let (slowlog_threshold, slowlog_len) = slow_log_settings(&args);
+    let log_format = args.log_format.unwrap_or("text".to_string());
+    if log_format.ne("text") && log_format.ne("json") {
+        println!("The log format {} is invalid, possible values: text, json", &log_format);
+        exit(-1);
+    }
+    let log_output: Box<dyn Write + Send> = match &args.log_file {
+        Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                println!("Can't open log file {}: {}", path.display(), e);
+                exit(-1);
+            }
+        },
+        None => Box::new(io::stderr()),
+    };
+    let spans = match args.trace_file.as_ref().map(SpanExporter::open) {
+        Some(Ok(spans)) => Some(Arc::new(spans)),
+        Some(Err(e)) => {
+            println!("Can't open trace file {}: {}", args.trace_file.unwrap().display(), e);
+            exit(-1);
+        }
+        None => None,
+    };
+    let data_dir = args.data_dir.take().unwrap_or_else(|| PathBuf::from("./"));
+
+    let mut addrs: Vec<ServerAddr> = Vec::new();
+    if args.addr.is_some() || args.unix.is_none() {
+        let addr = args.addr.unwrap_or("127.0.0.1:4000".to_string());
+        match addr.parse::<SocketAddr>() {
+            Ok(val) => addrs.push(val.into()),
+            Err(_e) => {
+                println!("The address {} is invalid", &addr);
+                exit(-1);
+            }
+        };
+    }
+    if let Some(path) = args.unix {
+        addrs.push(path.into());
+    }
+    let unix_mode = match args.unix_mode.as_ref().map(|mode| u32::from_str_radix(mode, 8)) {
+        Some(Ok(val)) if val <= 0o777 => val,
+        Some(_) => {
+            println!("The unix mode {} is invalid, expect octal permission bits like 660", args.unix_mode.unwrap());
+            exit(-1);
+        }
+        None => DEFAULT_UNIX_MODE,
+    };
+
+    let registry = EngineRegistry::default();
+    let engine_name = args.engine.unwrap_or("kvs".to_string());
+    if !registry.contains(&engine_name) {
+        println!("The engine {} is invalid, possible values: {}", &engine_name, registry.names().join(", "));
+        exit(-1);
+    }
+    // A flag sets the engine of a database of the config file
+    for db in args.db.iter() {
+        let (name, engine) = match db.split_once('=') {
+            Some((name, engine)) => (name, Some(engine.to_string())),
+            None => (db.as_str(), None),
+        };
+        let section = databases.entry(name.to_string()).or_default();
+        section.engine = engine.or(section.engine.take());
+    }
+    for (name, section) in databases.iter() {
+        if !is_database_name(name) {
+            println!("The database name {} is invalid, expect letters, digits, - and _ other than {}", name, DEFAULT_DATABASE);
+            exit(-1);
+        }
+        if let Some(engine) = section.engine.as_ref().filter(|engine| !registry.contains(engine)) {
+            println!("The engine {} is invalid, possible values: {}", engine, registry.names().join(", "));
+            exit(-1);
+        }
+    }
+    let mode = args.mode.unwrap_or("sync".to_string());
+    if mode.ne("sync") && mode.ne("async") {
+        println!("The mode {} is invalid, possible values: sync, async", &mode);
+        exit(-1);
+    }
+    let pool_name = args.pool.unwrap_or("shared".to_string());
+    // The naive pool spawns a thread per job without a bound, it is only fit for tests
+    let pool_kind: PoolKind = match pool_name.parse() {
+        Ok(PoolKind::Naive) | Err(_) => {
+            println!("The pool {} is invalid, possible values: shared, rayon", &pool_name);
+            exit(-1);
+        }
+        Ok(val) => val,
+    };
+    let threads = match args.threads {
+        Some(0) => {
+            println!("The number of threads must be at least 1");
+            exit(-1);
+        }
+        Some(val) => val,
+        None => num_cpus::get(),
+    };
+    let protocol_name = args.protocol.unwrap_or("kvs".to_string());
+    let protocol: ServerProtocol = match protocol_name.parse() {
         Ok(val) => val,
         Err(_e) => {
-            println!("The address {} is invalid", &addr);
+            println!("The protocol {} is invalid, possible values: kvs, resp", &protocol_name);
             exit(-1);
         }
     };
-
-    let engine_name = match args.engine {
-        Some(engine_arg) => {
-            match engine_arg {
-                Engine::Kvs => Engine::Kvs.to_string(),
-                Engine::Sled => Engine::Sled.to_string()
+    let http_addr: Option<SocketAddr> = match args.http.as_ref().map(|addr| addr.parse()) {
+        Some(Ok(val)) => Some(val),
+        Some(Err(_e)) => {
+            println!("The address {} is invalid", args.http.unwrap());
+            exit(-1);
+        }
+        None => None,
+    };
+    let metrics_addr: Option<SocketAddr> = match args.metrics.as_ref().map(|addr| addr.parse()) {
+        Some(Ok(val)) => Some(val),
+        Some(Err(_e)) => {
+            println!("The address {} is invalid", args.metrics.unwrap());
+            exit(-1);
+        }
+        None => None,
+    };
+    let tls_config = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => match tls::server_config(&cert, &key, args.tls_client_ca.as_deref()) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                println!("Can't load TLS config: {}", e);
+                exit(-1);
             }
+        },
+        (None, None) if args.tls_client_ca.is_none() => None,
+        _ => {
+            println!("TLS needs both --tls-cert and --tls-key");
+            exit(-1);
+        }
+    };
+    let acl = match args.acl.as_ref().map(Acl::open) {
+        Some(Ok(acl)) => Some(Arc::new(acl)),
+        Some(Err(e)) => {
+            println!("Can't load ACL {}: {}", args.acl.unwrap().display(), e);
+            exit(-1);
         }
-        None => "kvs".to_string()
+        None => None,
     };
+    let default_limits = ConnectionLimits::default();
+    let limits = ConnectionLimits {
+        max_connections: args.max_connections,
+        idle_timeout: args.idle_timeout.map(seconds),
+        read_timeout: args.read_timeout.map(seconds),
+        write_timeout: args.write_timeout.map(seconds),
+        max_request_size: args.max_request_size.unwrap_or(default_limits.max_request_size),
+    };
+    let mut engine_config = EngineConfig::new();
+    for option in args.engine_opt.iter() {
+        match option.split_once('=') {
+            Some((key, value)) => engine_config.set(key, value),
+            None => {
+                println!("The engine option {} is invalid, expect key=value", option);
+                exit(-1);
+            }
+        }
+    }
+    if let Some(ratio) = args.compact_dead_ratio {
+        engine_config.set("compact_dead_ratio", ratio.to_string());
+    }
+    if let Some(bytes) = args.compact_dead_bytes {
+        engine_config.set("compact_dead_bytes", bytes.to_string());
+    }
+    if args.sync {
+        engine_config.set("sync", "true");
+    }
 
-    let plain = slog_term::PlainSyncDecorator::new(std::io::stderr());
-    let logger = slog::Logger::root(
-        slog_term::FullFormat::new(plain).build().fuse(),
-        o!("src" => PushFnValue(|r: &Record, ser: PushFnValueSerializer| {
-            ser.emit(format_args!("{}:{}", r.file(), r.line()))
-        })),
-    );
+    let live_level = Arc::new(AtomicUsize::new(log_level.as_usize()));
+    let logger = if log_format.eq("json") {
+        let json = slog_json::Json::new(log_output).add_default_keys().build();
+        Logger::root(LiveLevelFilter { drain: Mutex::new(json), level: live_level.clone() }.fuse(), o!())
+    } else {
+        let plain = slog_term::PlainSyncDecorator::new(log_output);
+        Logger::root(LiveLevelFilter { drain: slog_term::FullFormat::new(plain).build(), level: live_level.clone() }.fuse(), o!())
+    };
+    let logger = logger.new(o!("src" => PushFnValue(|r: &Record, ser: PushFnValueSerializer| {
+        ser.emit(format_args!("{}:{}", r.file(), r.line()))
+    })));
     let _guard = slog_scope::set_global_logger(logger);
 
     info!("Server version: {}", env!("CARGO_PKG_VERSION"));
     info!("Run with {} engine", engine_name);
-    info!("Listening on {}", addr);
-
-    match get_engine_name("./") {
-        Ok(res) => {
-            if let Some(val) = res {
-                if val.ne(&engine_name) {
-                    error!("Wrong engine, before: {}, now: {}", val, engine_name);
-                    exit(-1);
-                }
-            }
+    info!("Run in {} mode", mode);
+    if mode.eq("sync") {
+        info!("Serve with {} pool of {} threads", pool_name, threads);
+    }
+    info!("Speak {} protocol", protocol_name);
+    for addr in addrs.iter() {
+        info!("Listening on {}", addr);
+    }
+    if tls_config.is_some() {
+        info!("Serve TLS");
+    }
+    if acl.is_some() {
+        info!("Require authentication");
+    }
+    if let Some(http_addr) = http_addr {
+        info!("HTTP gateway listening on {}", http_addr);
+    }
+    if let Some(metrics_addr) = metrics_addr {
+        info!("Metrics listening on {}", metrics_addr);
+    }
+    if let Some(path) = &args.trace_file {
+        info!("Export spans to {}", path.display());
+    }
+
+    let engine = registry.open(&engine_name, &data_dir, &engine_config).unwrap_or_else(|e| {
+        error!("Can't open {} engine: {}", engine_name, e);
+        exit(-1);
+    });
+    let databases: Vec<_> = databases.into_iter().map(|(name, section)| {
+        let db_engine_name = section.engine.clone().unwrap_or_else(|| engine_name.clone());
+        let mut db_engine_config = engine_config.clone();
+        for (key, value) in section.engine_options() {
+            db_engine_config.set(key, value);
         }
-        Err(e) => {
-            error!("Can't get engine record: {}", e);
+        let db_engine = registry.open(&db_engine_name, data_dir.join("databases").join(&name), &db_engine_config).unwrap_or_else(|e| {
+            error!("Can't open database {}: {}", name, e);
             exit(-1);
+        });
+        info!("Serve database {} with {} engine", name, db_engine_name);
+        (name, db_engine)
+    }).collect();
+    // All servers count into the same metrics and slow log
+    let metrics = Arc::new(Metrics::new());
+    let slow_log = Arc::new(SlowLog::new(slowlog_threshold, slowlog_len));
+    let rate_limiter = Arc::new(RateLimiter::new(rate_limit));
+    let live = Live {
+        log_level: live_level,
+        acl: acl.clone(),
+        rate_limiter: rate_limiter.clone(),
+        slow_log: slow_log.clone(),
+        settings: Mutex::new(settings),
+    };
+    let reload: ReloadHandler = Arc::new(move || {
+        reload_settings(&live).inspect_err(|e| error!("Can't reload settings: {}", e))
+    });
+    // The HTTP listeners stop with the servers, and are waited for before returning
+    let mut http_handles = Vec::new();
+    let mut http_threads = Vec::new();
+    if let Some(http_addr) = http_addr {
+        let mut gateway = HttpGateway::new(http_addr, engine.clone());
+        gateway.set_max_request_size(limits.max_request_size);
+        gateway.set_thread_pool(pool_kind, threads);
+        if let Some(jobs) = args.max_queued {
+            gateway.set_max_queued(jobs);
         }
+        gateway.set_rate_limiter(rate_limiter.clone());
+        gateway.set_metrics(metrics.clone());
+        gateway.set_slow_log(slow_log.clone());
+        if let Some(acl) = acl.clone() {
+            gateway.set_acl(acl);
+        }
+        http_handles.push(gateway.shutdown_handle());
+        http_threads.push(thread::spawn(move || {
+            if let Err(e) = gateway.handle_connection() {
+                error!("HTTP gateway error: {}", e);
+                exit(-1);
+            }
+        }));
+    }
+    if let Some(metrics_addr) = metrics_addr {
+        let mut endpoint = MetricsEndpoint::new(metrics_addr, metrics.clone());
+        endpoint.set_engine(engine.clone());
+        http_handles.push(endpoint.shutdown_handle());
+        http_threads.push(thread::spawn(move || {
+            if let Err(e) = endpoint.handle_connection() {
+                error!("Metrics endpoint error: {}", e);
+                exit(-1);
+            }
+        }));
+    }
+    if mode.eq("async") {
+        let blocking_threads = threads;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .max_blocking_threads(blocking_threads)
+            .build()
+            .unwrap_or_else(|e| {
+                error!("Can't build tokio runtime: {}", e);
+                exit(-1);
+            });
+        let engine = AsyncKvsEngine::new(engine, blocking_threads);
+        let servers: Vec<AsyncKvsServer> = addrs.into_iter().map(|addr| {
+            let mut server = AsyncKvsServer::with_protocol(addr, engine.clone(), protocol);
+            for (name, db_engine) in databases.iter() {
+                server.add_database(name, db_engine.clone());
+            }
+            server.set_unix_mode(unix_mode);
+            server.set_limits(limits.clone());
+            server.set_metrics(metrics.clone());
+            server.set_slow_log(slow_log.clone());
+            if let Some(spans) = spans.clone() {
+                server.set_span_exporter(spans);
+            }
+            if let Some(config) = tls_config.clone() {
+                server.set_tls(config);
+            }
+            if let Some(acl) = acl.clone() {
+                server.set_acl(acl);
+            }
+            server.set_rate_limiter(rate_limiter.clone());
+            server.set_reload_handler(reload.clone());
+            if let Some(jobs) = args.max_queued {
+                server.set_max_queued(jobs);
+            }
+            server
+        }).collect();
+        handle_signals(servers.iter().map(AsyncKvsServer::shutdown_handle).chain(http_handles).collect(), reload);
+        runtime.block_on(async move {
+            let mut tasks = JoinSet::new();
+            for mut server in servers {
+                tasks.spawn(async move { server.handle_connection().await });
+            }
+            while let Some(result) = tasks.join_next().await {
+                match result {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        error!("Server error: {}", e);
+                        exit(-1);
+                    }
+                    Err(e) => {
+                        error!("Server error: {}", e);
+                        exit(-1);
+                    }
+                }
+            }
+        });
+    } else {
+        let servers: Vec<KvsServer> = addrs.into_iter().map(|addr| {
+            let mut server = KvsServer::with_protocol(addr, engine.clone(), protocol);
+            for (name, db_engine) in databases.iter() {
+                server.add_database(name, db_engine.clone());
+            }
+            server.set_thread_pool(pool_kind, threads);
+            server.set_unix_mode(unix_mode);
+            server.set_limits(limits.clone());
+            server.set_metrics(metrics.clone());
+            server.set_slow_log(slow_log.clone());
+            if let Some(spans) = spans.clone() {
+                server.set_span_exporter(spans);
+            }
+            if let Some(config) = tls_config.clone() {
+                server.set_tls(config);
+            }
+            if let Some(acl) = acl.clone() {
+                server.set_acl(acl);
+            }
+            server.set_rate_limiter(rate_limiter.clone());
+            server.set_reload_handler(reload.clone());
+            if let Some(jobs) = args.max_queued {
+                server.set_max_queued(jobs);
+            }
+            server
+        }).collect();
+        handle_signals(servers.iter().map(KvsServer::shutdown_handle).chain(http_handles).collect(), reload);
+        let threads: Vec<_> = servers.into_iter()
+            .map(|mut server| thread::spawn(move || server.handle_connection()))
+            .collect();
+        for thread in threads {
+            if let Ok(Err(e)) = thread.join() {
+                error!("Server error: {}", e);
+                exit(-1);
+            }
+        }
+    }
+    // The gateway flushes the engine once its last request is done
+    for thread in http_threads {
+        let _ = thread.join();
+    }
+    info!("Server stopped");
+}
+
+// The flags merged with the config file they name, and the databases of that file.
+fn load_args(mut args: Args) -> Result<(Args, BTreeMap<String, DatabaseSection>), String> {
+    let mut config = match args.config.as_ref().map(ConfigFile::open) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => return Err(format!("Can't load config {}: {}", args.config.unwrap().display(), e)),
+        None => ConfigFile::default(),
+    };
+    let databases = mem::take(&mut config.databases);
+    apply_config(&mut args, config);
+    Ok((args, databases))
+}
+
+// Settings of the config file are used where no flag is given, engine options of both are merged.
+fn apply_config(args: &mut Args, config: ConfigFile) {
+    let mut engine_opt: Vec<String> = config.engine_options().map(|(key, value)| format!("{}={}", key, value)).collect();
+    engine_opt.append(&mut args.engine_opt);
+    let ConfigFile { data_dir, mode, acl, listen, engine, pool, durability, limits, slowlog, log, tls, .. } = config;
+    args.engine_opt = engine_opt;
+    args.data_dir = args.data_dir.take().or(data_dir);
+    args.engine = args.engine.take().or(engine.name);
+    args.mode = args.mode.take().or(mode);
+    args.acl = args.acl.take().or(acl);
+    args.addr = args.addr.take().or(listen.addr);
+    args.unix = args.unix.take().or(listen.unix);
+    args.unix_mode = args.unix_mode.take().or(listen.unix_mode);
+    args.protocol = args.protocol.take().or(listen.protocol);
+    args.http = args.http.take().or(listen.http);
+    args.metrics = args.metrics.take().or(listen.metrics);
+    args.pool = args.pool.take().or(pool.kind);
+    args.threads = args.threads.or(pool.threads);
+    args.max_queued = args.max_queued.or(pool.max_queued);
+    args.sync = args.sync || durability.sync.unwrap_or(false);
+    args.max_connections = args.max_connections.or(limits.max_connections);
+    args.idle_timeout = args.idle_timeout.or(limits.idle_timeout);
+    args.read_timeout = args.read_timeout.or(limits.read_timeout);
+    args.write_timeout = args.write_timeout.or(limits.write_timeout);
+    args.max_request_size = args.max_request_size.or(limits.max_request_size);
+    args.rate_limit = args.rate_limit.or(limits.rate_limit);
+    args.rate_burst = args.rate_burst.or(limits.rate_burst);
+    args.slowlog_threshold = args.slowlog_threshold.or(slowlog.threshold);
+    args.slowlog_len = args.slowlog_len.or(slowlog.len);
+    args.log_level = args.log_level.take().or(log.level);
+    args.log_format = args.log_format.take().or(log.format);
+    args.log_file = args.log_file.take().or(log.file);
+    args.trace_file = args.trace_file.take().or(log.trace_file);
+    args.tls_cert = args.tls_cert.take().or(tls.cert);
+    args.tls_key = args.tls_key.take().or(tls.key);
+    args.tls_client_ca = args.tls_client_ca.take().or(tls.client_ca);
+}
+
+fn is_database_name(name: &str) -> bool {
+    name != DEFAULT_DATABASE && !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn seconds(secs: u64) -> Duration {
+    if secs == 0 {
+        println!("Timeouts must be at least 1 second");
+        exit(-1);
+    }
+    Duration::from_secs(secs)
+}
+
+fn parse_log_level(args: &Args) -> Result<Level, String> {
+    match args.log_level.as_deref().map(str::parse::<Level>) {
+        Some(Ok(level)) => Ok(level),
+        Some(Err(_)) => Err(format!(
+            "The log level {} is invalid, possible values: critical, error, warning, info, debug, trace",
+            args.log_level.as_deref().unwrap_or_default(),
+        )),
+        None => Ok(Level::Trace),
+    }
+}
+
+fn parse_rate_limit(args: &Args) -> Result<Option<RateLimit>, String> {
+    match (args.rate_limit, args.rate_burst) {
+        (Some(0), _) | (_, Some(0)) => Err("The rate limit and burst must be at least 1".to_string()),
+        (Some(rate), burst) => Ok(Some(RateLimit { burst: burst.unwrap_or(rate), ..RateLimit::per_second(rate) })),
+        (None, Some(_)) => Err("--rate-burst needs --rate-limit".to_string()),
+        (None, None) => Ok(None),
+    }
+}
+
+fn slow_log_settings(args: &Args) -> (Duration, usize) {
+    (
+        args.slowlog_threshold.map(Duration::from_millis).unwrap_or(SlowLog::DEFAULT_THRESHOLD),
+        args.slowlog_len.unwrap_or(SlowLog::DEFAULT_CAPACITY),
+    )
+}
+
+// Every setting by its name in the config file, to tell which ones a reload changed.
+fn settings(args: &Args, databases: &BTreeMap<String, DatabaseSection>) -> Vec<(&'static str, String)> {
+    vec![
+        ("data_dir", format!("{:?}", args.data_dir)),
+        ("mode", format!("{:?}", args.mode)),
+        ("acl", format!("{:?}", args.acl)),
+        ("listen.addr", format!("{:?}", args.addr)),
+        ("listen.unix", format!("{:?}", args.unix)),
+        ("listen.unix_mode", format!("{:?}", args.unix_mode)),
+        ("listen.protocol", format!("{:?}", args.protocol)),
+        ("listen.http", format!("{:?}", args.http)),
+        ("listen.metrics", format!("{:?}", args.metrics)),
+        ("engine.name", format!("{:?}", args.engine)),
+        ("engine.options", format!("{:?} {:?} {:?}", args.engine_opt, args.compact_dead_ratio, args.compact_dead_bytes)),
+        ("pool.kind", format!("{:?}", args.pool)),
+        ("pool.threads", format!("{:?}", args.threads)),
+        ("pool.max_queued", format!("{:?}", args.max_queued)),
+        ("durability.sync", format!("{:?}", args.sync)),
+        ("limits.max_connections", format!("{:?}", args.max_connections)),
+        ("limits.idle_timeout", format!("{:?}", args.idle_timeout)),
+        ("limits.read_timeout", format!("{:?}", args.read_timeout)),
+        ("limits.write_timeout", format!("{:?}", args.write_timeout)),
+        ("limits.max_request_size", format!("{:?}", args.max_request_size)),
+        ("limits.rate_limit", format!("{:?}", args.rate_limit)),
+        ("limits.rate_burst", format!("{:?}", args.rate_burst)),
+        ("slowlog.threshold", format!("{:?}", args.slowlog_threshold)),
+        ("slowlog.len", format!("{:?}", args.slowlog_len)),
+        ("log.level", format!("{:?}", args.log_level)),
+        ("log.format", format!("{:?}", args.log_format)),
+        ("log.file", format!("{:?}", args.log_file)),
+        ("log.trace_file", format!("{:?}", args.trace_file)),
+        ("tls.cert", format!("{:?}", args.tls_cert)),
+        ("tls.key", format!("{:?}", args.tls_key)),
+        ("tls.client_ca", format!("{:?}", args.tls_client_ca)),
+        ("databases", format!("{:?} {:?}", args.db, databases)),
+    ]
+}
+
+// What a reload can change while the servers run
+struct Live {
+    log_level: Arc<AtomicUsize>,
+    acl: Option<Arc<Acl>>,
+    rate_limiter: Arc<RateLimiter>,
+    slow_log: Arc<SlowLog>,
+    // The settings in use, those needing a restart keep their value from the start
+    settings: Mutex<Vec<(&'static str, String)>>,
+}
+
+// Read the flags and config file again, and apply the live settings if they are all valid.
+fn reload_settings(live: &Live) -> kvs::Result<ReloadReport> {
+    let (args, databases) = load_args(argh::from_env()).map_err(KvsError::InvalidConfig)?;
+    let log_level = parse_log_level(&args).map_err(KvsError::InvalidConfig)?;
+    let rate_limit = parse_rate_limit(&args).map_err(KvsError::InvalidConfig)?;
+    let (slowlog_threshold, slowlog_len) = slow_log_settings(&args);
+    // The file may have changed even if its path didn't
+    let acl = match (&live.acl, &args.acl) {
+        (Some(_), Some(path)) => Some(Acl::open(path)?),
+        _ => None,
     };
-    if engine_name.eq("kvs") {
-        let kvs = KvStore::open("./").unwrap_or_else(|e| {
-            error!("Can't open KvStore: {}", e);
+
+    let mut report = ReloadReport::default();
+    let mut current = live.settings.lock().unwrap();
+    for ((name, value), (_, new_value)) in current.iter_mut().zip(settings(&args, &databases)) {
+        if *name == "acl" || *value == new_value {
+            continue;
+        }
+        if LIVE_SETTINGS.contains(name) {
+            *value = new_value;
+            report.applied.push(name.to_string());
+        } else {
+            report.restart_required.push(name.to_string());
+        }
+    }
+    // Turning authentication on or off needs a restart, changing users doesn't
+    match (&live.acl, acl) {
+        (Some(live_acl), Some(acl)) => {
+            if live_acl.replace(acl) {
+                report.applied.push("acl".to_string());
+            }
+        }
+        _ if live.acl.is_none() && args.acl.is_none() => {}
+        _ => report.restart_required.push("acl".to_string()),
+    }
+    live.log_level.store(log_level.as_usize(), Ordering::Relaxed);
+    live.rate_limiter.set_limit(rate_limit);
+    live.slow_log.set_threshold(slowlog_threshold);
+    live.slow_log.set_capacity(slowlog_len);
+
+    info!("Reloaded settings"; "applied" => report.applied.join(", "));
+    if !report.restart_required.is_empty() {
+        warn!("Restart to apply {}", report.restart_required.join(", "));
+    }
+    Ok(report)
+}
+
+// A `LevelFilter` whose level a reload can change.
+struct LiveLevelFilter<D> {
+    drain: D,
+    level: Arc<AtomicUsize>,
+}
+
+impl<D: Drain> Drain for LiveLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = Option<D::Err>;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let level = Level::from_usize(self.level.load(Ordering::Relaxed)).unwrap_or(Level::Trace);
+        if record.level().is_at_least(level) {
+            self.drain.log(record, values).map(Some).map_err(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Servers finish the requests they have read and flush the engine on SIGTERM or Ctrl-C,
+// and reload their settings on SIGHUP.
+fn handle_signals(handles: Vec<ShutdownHandle>, reload: ReloadHandler) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap_or_else(|e| {
+            error!("Can't build tokio runtime: {}", e);
             exit(-1);
         });
-        write_engine(&engine_name, "./").unwrap_or_else(|e| {
-            error!("Can't write engine record: {}", e);
+    // Registered before returning, so no signal is missed
+    let (mut terminate, mut hangup) = {
+        let _guard = runtime.enter();
+        let terminate = signal(SignalKind::terminate()).unwrap_or_else(|e| {
+            error!("Can't handle SIGTERM: {}", e);
             exit(-1);
         });
-        let mut server = KvsServer::new(socket_addr, kvs);
-        server.handle_connection();
-    } else {
-        let sled = SledKvsEngine::open("./").unwrap_or_else(|e| {
-            error!("Can't open Sled: {}", e);
+        let hangup = signal(SignalKind::hangup()).unwrap_or_else(|e| {
+            error!("Can't handle SIGHUP: {}", e);
             exit(-1);
         });
-        write_engine(&engine_name, "./").unwrap_or_else(|e| {
-            error!("Can't write engine record: {}", e);
-            exit(-1);
+        (terminate, hangup)
+    };
+    thread::spawn(move || {
+        runtime.block_on(async {
+            loop {
+                tokio::select! {
+                    _ = hangup.recv() => {
+                        info!("Reloading settings");
+                        // Failures are logged by the handler
+                        let _ = reload();
+                    }
+                    _ = terminate.recv() => break,
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
         });
-        let mut server = KvsServer::new(socket_addr, sled);
-        server.handle_connection();
-    }
+        info!("Shutting down");
+        for handle in handles {
+            handle.shutdown();
+        }
+    });
 }
@@ -1,66 +1,323 @@
-use std::net::{SocketAddr, TcpStream};
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rustls::ClientConnection;
+use rustls::pki_types::ServerName;
 use serde::Deserialize;
-use serde_json::{Deserializer, to_writer};
-use crate::{KvsError, Request, Response, Result};
+use serde_json::Deserializer;
+use serde_json::de::IoRead;
+
+use crate::{BINARY_HANDSHAKE, KvsError, Protocol, ReloadReport, Request, RequestEnvelope, Response, Result, ServerAddr, ServerStats, SlowLogEntry};
+use crate::protocol::{encode_message, IncomingResponse, MAX_FRAME_LEN, PendingResponses, read_frame, refused_handshake, Stream};
+use crate::tls::{self, ClientConfig, TlsStream};
+
+// Connections kept open for later requests, more are opened when needed.
+pub(crate) const MAX_IDLE_CONNECTIONS: usize = 8;
+
+type BoxedReader = BufReader<Box<dyn Read + Send>>;
+
+enum ResponseReader {
+    Json(Deserializer<IoRead<BoxedReader>>),
+    Binary(BoxedReader),
+}
+
+struct Connection {
+    reader: ResponseReader,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    protocol: Protocol,
+    // Whether the last `send` got any response
+    answered: bool,
+}
+
+struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl Connection {
+    fn connect(addr: &ServerAddr, protocol: Protocol, tls: Option<&ClientTls>) -> Result<Connection> {
+        match addr {
+            ServerAddr::Tcp(addr) => Connection::from_stream(TcpStream::connect(addr)?, protocol, tls),
+            ServerAddr::Unix(path) => Connection::from_stream(UnixStream::connect(path)?, protocol, tls),
+        }
+    }
+
+    fn from_stream<S: Stream>(stream: S, protocol: Protocol, tls: Option<&ClientTls>) -> Result<Connection> {
+        match tls {
+            Some(tls) => {
+                let conn = ClientConnection::new(tls.config.clone(), tls.server_name.clone())?;
+                Connection::handshake(TlsStream::new(conn, stream), protocol)
+            }
+            None => Connection::handshake(stream, protocol),
+        }
+    }
+
+    fn handshake<S: Stream>(stream: S, protocol: Protocol) -> Result<Connection> {
+        let mut writer: BufWriter<Box<dyn Write + Send>> = BufWriter::new(Box::new(stream.try_clone()?));
+        let stream: Box<dyn Read + Send> = Box::new(stream);
+        let reader = match protocol {
+            Protocol::Json => ResponseReader::Json(Deserializer::from_reader(BufReader::new(stream))),
+            Protocol::Binary => {
+                writer.write_all(&[BINARY_HANDSHAKE])?;
+                writer.flush()?;
+                let mut reader = BufReader::new(stream);
+                let mut handshake = [0; 1];
+                reader.read_exact(&mut handshake)?;
+                if handshake[0] != BINARY_HANDSHAKE {
+                    let mut reply = handshake.to_vec();
+                    reader.read_to_end(&mut reply)?;
+                    return Err(refused_handshake(&reply));
+                }
+                ResponseReader::Binary(reader)
+            }
+        };
+        Ok(Connection { reader, writer, protocol, answered: false })
+    }
+
+    fn send(&mut self, envelopes: &[RequestEnvelope]) -> Result<Vec<Response>> {
+        self.answered = false;
+        for envelope in envelopes {
+            self.writer.write_all(&encode_message(self.protocol, envelope)?)?;
+        }
+        self.writer.flush()?;
 
+        let mut pending = PendingResponses::new(envelopes);
+        while !pending.is_complete() {
+            let envelope = match &mut self.reader {
+                ResponseReader::Json(reader) => IncomingResponse::deserialize(reader)?.into_envelope()?,
+                ResponseReader::Binary(reader) => match read_frame(reader, MAX_FRAME_LEN)? {
+                    Some(frame) => bincode::deserialize(&frame)?,
+                    None => return Err(KvsError::IOError(io::ErrorKind::UnexpectedEof.into())),
+                },
+            };
+            self.answered = true;
+            pending.insert(envelope)?;
+        }
+        Ok(pending.into_responses())
+    }
+}
+
+// The server closed the connection, as it does with idle ones.
+pub(crate) fn is_closed(e: &KvsError) -> bool {
+    let kind = match e {
+        KvsError::IOError(e) => e.kind(),
+        KvsError::SerdeError(e) if e.is_eof() => return true,
+        KvsError::SerdeError(e) => match e.io_error_kind() {
+            Some(kind) => kind,
+            None => return false,
+        },
+        _ => return false,
+    };
+    matches!(kind, io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe)
+}
+
+/// A blocking client that keeps its connections open between requests.
+///
+/// It can be shared between threads, every thread in flight uses its own connection.
 pub struct KvsClient {
-    addr: SocketAddr,
+    addr: ServerAddr,
+    protocol: Protocol,
+    tls: Option<ClientTls>,
+    // User name and password sent first on every new connection
+    auth: Option<(Option<String>, String)>,
+    // Selected after logging in
+    db: Option<String>,
+    idle_connections: Mutex<Vec<Connection>>,
+    next_id: AtomicU64,
 }
 
 impl KvsClient {
-    pub fn new(addr: SocketAddr) -> KvsClient {
-        KvsClient { addr }
+    pub fn new(addr: impl Into<ServerAddr>) -> KvsClient {
+        KvsClient::with_protocol(addr, Protocol::Json)
+    }
+
+    /// A client of a server listening on the Unix socket at `path`.
+    pub fn connect_unix(path: impl Into<PathBuf>) -> KvsClient {
+        KvsClient::new(path.into())
+    }
+
+    pub fn with_protocol(addr: impl Into<ServerAddr>, protocol: Protocol) -> KvsClient {
+        KvsClient { addr: addr.into(), protocol, tls: None, auth: None, db: None, idle_connections: Mutex::new(Vec::new()), next_id: AtomicU64::new(0) }
+    }
+
+    /// Connect with TLS, the server certificate must be valid for `server_name`.
+    ///
+    /// `server_name` is a DNS name or an IP address, see `tls::client_config` for the config.
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>, server_name: &str) -> Result<()> {
+        self.tls = Some(ClientTls { config, server_name: tls::server_name(server_name)? });
+        Ok(())
+    }
+
+    /// Log in to a server with an ACL, `user` may be left out if the password is unique.
+    pub fn set_auth(&mut self, user: Option<&str>, password: &str) {
+        self.auth = Some((user.map(str::to_string), password.to_string()));
+    }
+
+    /// Send every request to the database `db` of the server instead of the default one.
+    pub fn set_db(&mut self, db: &str) {
+        self.db = Some(db.to_string());
     }
 
     fn send_command(&self, request: Request) -> Result<Response> {
-        let stream = TcpStream::connect(self.addr)?;
-        let cloned_stream = stream.try_clone()?;
-        let mut reader = Deserializer::from_reader(BufReader::new(stream));
-        let mut writer = BufWriter::new(cloned_stream);
-        to_writer(&mut writer, &request)?;
-        writer.flush()?;
+        let mut responses = self.pipeline(vec![request])?;
+        Ok(responses.remove(0))
+    }
+
+    /// Send all requests on one connection without waiting for the responses in between.
+    ///
+    /// The server may run them concurrently, so they should not depend on each other.
+    /// Responses are returned in the order of `requests`.
+    pub fn pipeline(&self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let envelopes: Vec<RequestEnvelope> = requests.into_iter()
+            .map(|request| RequestEnvelope::new(self.next_id.fetch_add(1, Ordering::Relaxed), request))
+            .collect();
+
+        let idle_connection = self.idle_connections.lock().unwrap().pop();
+        if let Some(mut connection) = idle_connection {
+            match connection.send(&envelopes) {
+                Ok(responses) => {
+                    self.release(connection);
+                    return Ok(responses);
+                }
+                // The server closed the idle connection before answering, so the requests
+                // were not run and are sent again on a new one
+                Err(e) if !connection.answered && is_closed(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-        let resp = Response::deserialize(&mut reader)?;
-        Ok(resp)
+        let mut connection = self.connect()?;
+        let responses = connection.send(&envelopes)?;
+        self.release(connection);
+        Ok(responses)
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let mut connection = Connection::connect(&self.addr, self.protocol, self.tls.as_ref())?;
+        if let Some((user, password)) = &self.auth {
+            let request = Request::Auth { user: user.clone(), password: password.clone() };
+            let envelope = RequestEnvelope::new(self.next_id.fetch_add(1, Ordering::Relaxed), request);
+            resp_to_unit(connection.send(&[envelope])?.remove(0))?;
+        }
+        if let Some(db) = &self.db {
+            let envelope = RequestEnvelope::new(self.next_id.fetch_add(1, Ordering::Relaxed), Request::Select { db: db.clone() });
+            resp_to_unit(connection.send(&[envelope])?.remove(0))?;
+        }
+        Ok(connection)
+    }
+
+    fn release(&self, connection: Connection) {
+        let mut idle_connections = self.idle_connections.lock().unwrap();
+        if idle_connections.len() < MAX_IDLE_CONNECTIONS {
+            idle_connections.push(connection);
+        }
     }
 
     pub fn set(&self, key: &str, value: &str) -> Result<()> {
         let request = Request::Set { key: key.to_string(), value: value.to_string() };
         let resp = self.send_command(request)?;
-        if resp.is_ok {
-            Ok(())
-        } else {
-            Err(KvsError::ServerRespError(resp.data))
-        }
+        resp_to_unit(resp)
     }
 
     pub fn get(&self, key: &str) -> Result<Option<String>> {
         let request = Request::Get { key: key.to_string() };
         let resp = self.send_command(request)?;
-        if resp.is_ok {
-            if resp.data.is_empty() {
-                Ok(None)
-            } else {
-                Ok(Some(resp.data))
-            }
-        } else {
-            Err(KvsError::ServerRespError(resp.data))
-        }
+        resp_to_value(resp)
     }
 
     pub fn remove(&self, key: &str) -> Result<()> {
         let request = Request::Rm { key: key.to_string() };
         let resp = self.send_command(request)?;
-        if resp.is_ok {
-            Ok(())
-        } else {
-            Err(KvsError::ServerRespError(resp.data))
-        }
+        resp_to_removed(resp, key)
     }
 
     pub fn is_key_exist(&self, key: &str) -> Result<bool> {
         let result = self.get(key)?.is_some();
         Ok(result)
     }
+
+    pub fn stats(&self) -> Result<ServerStats> {
+        let resp = self.send_command(Request::Stats)?;
+        resp_to_stats(resp)
+    }
+
+    /// Compact the engine of the server, returns the bytes freed.
+    pub fn compact(&self) -> Result<u64> {
+        let resp = self.send_command(Request::Compact)?;
+        resp_to_compacted(resp)
+    }
+
+    /// The slow requests kept by the server, the latest first.
+    pub fn slow_log(&self) -> Result<Vec<SlowLogEntry>> {
+        let resp = self.send_command(Request::SlowLog)?;
+        resp_to_slow_log(resp)
+    }
+
+    /// Make the server reload its settings, see `KvsServer::set_reload_handler`.
+    pub fn reload(&self) -> Result<ReloadReport> {
+        let resp = self.send_command(Request::Reload)?;
+        resp_to_reloaded(resp)
+    }
+}
+
+pub(crate) fn resp_to_unit(resp: Response) -> Result<()> {
+    match resp {
+        Response::Ok => Ok(()),
+        resp => Err(unexpected(resp)),
+    }
+}
+
+pub(crate) fn resp_to_value(resp: Response) -> Result<Option<String>> {
+    match resp {
+        Response::Value(value) => Ok(Some(value)),
+        Response::NotFound => Ok(None),
+        resp => Err(unexpected(resp)),
+    }
+}
+
+pub(crate) fn resp_to_stats(resp: Response) -> Result<ServerStats> {
+    match resp {
+        Response::Stats(stats) => Ok(stats),
+        resp => Err(unexpected(resp)),
+    }
+}
+
+pub(crate) fn resp_to_compacted(resp: Response) -> Result<u64> {
+    match resp {
+        Response::Compacted { reclaimed_bytes } => Ok(reclaimed_bytes),
+        resp => Err(unexpected(resp)),
+    }
+}
+
+pub(crate) fn resp_to_slow_log(resp: Response) -> Result<Vec<SlowLogEntry>> {
+    match resp {
+        Response::SlowLog(entries) => Ok(entries),
+        resp => Err(unexpected(resp)),
+    }
+}
+
+pub(crate) fn resp_to_reloaded(resp: Response) -> Result<ReloadReport> {
+    match resp {
+        Response::Reloaded(report) => Ok(report),
+        resp => Err(unexpected(resp)),
+    }
+}
+
+// `Rm` of a missing key fails with `KvsError::KeyNotFound`, like the engines do.
+pub(crate) fn resp_to_removed(resp: Response, key: &str) -> Result<()> {
+    match resp {
+        Response::NotFound => Err(KvsError::KeyNotFound(key.to_string())),
+        resp => resp_to_unit(resp),
+    }
+}
+
+fn unexpected(resp: Response) -> KvsError {
+    match resp {
+        Response::Error { code, message } => KvsError::ServerError { code, message },
+        resp => KvsError::ProtocolError(format!("unexpected response {:?}", resp)),
+    }
 }
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// Settings of `kvs-server` read from a TOML file, every one is optional.
+///
+/// Values are checked by the server like the flags of the same name, which override them.
+/// Relative paths are relative to the directory of the file.
+///
+/// ```toml
+/// data_dir = "data"
+/// mode = "sync"
+///
+/// [listen]
+/// addr = "127.0.0.1:4000"
+///
+/// [engine]
+/// name = "kvs"
+/// options = { compact_dead_ratio = 0.5 }
+///
+/// [limits]
+/// max_connections = 1000
+/// idle_timeout = 300
+///
+/// [databases.sessions]
+/// engine = "memory"
+/// ```
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub data_dir: Option<PathBuf>,
+    /// `sync` or `async`.
+    pub mode: Option<String>,
+    /// JSON file of users and their grants.
+    pub acl: Option<PathBuf>,
+    pub listen: ListenSection,
+    pub engine: EngineSection,
+    pub pool: PoolSection,
+    pub durability: DurabilitySection,
+    pub limits: LimitsSection,
+    pub slowlog: SlowLogSection,
+    pub log: LogSection,
+    pub tls: TlsSection,
+    /// Served besides the default database, by name.
+    pub databases: BTreeMap<String, DatabaseSection>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenSection {
+    pub addr: Option<String>,
+    pub unix: Option<PathBuf>,
+    /// Permission bits of the Unix socket file in octal, like `"660"`.
+    pub unix_mode: Option<String>,
+    pub protocol: Option<String>,
+    pub http: Option<String>,
+    pub metrics: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineSection {
+    pub name: Option<String>,
+    /// Passed to the engine in its `EngineConfig`.
+    pub options: BTreeMap<String, toml::Value>,
+}
+
+/// A database in the `databases` directory of `data_dir`.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    /// The engine of the default database if left out.
+    pub engine: Option<String>,
+    /// Added to the options of the default database.
+    pub options: BTreeMap<String, toml::Value>,
+}
+
+impl DatabaseSection {
+    /// See `ConfigFile::engine_options`.
+    pub fn engine_options(&self) -> impl Iterator<Item=(&str, String)> {
+        option_strings(&self.options)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolSection {
+    pub kind: Option<String>,
+    pub threads: Option<usize>,
+    pub max_queued: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DurabilitySection {
+    /// Write every change to disk before answering, the `sync` engine option.
+    pub sync: Option<bool>,
+}
+
+/// Timeouts are in seconds.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub max_connections: Option<usize>,
+    pub idle_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub write_timeout: Option<u64>,
+    pub max_request_size: Option<usize>,
+    pub rate_limit: Option<u32>,
+    pub rate_burst: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowLogSection {
+    /// In milliseconds.
+    pub threshold: Option<u64>,
+    pub len: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    /// The lowest level logged, like `"info"`.
+    pub level: Option<String>,
+    /// `text` or `json`.
+    pub format: Option<String>,
+    /// Log to this file instead of stderr.
+    pub file: Option<PathBuf>,
+    /// Export a span for every request to this file, see `SpanExporter`.
+    pub trace_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<ConfigFile> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        let mut config = ConfigFile::parse(&contents)?;
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+        Ok(config)
+    }
+
+    pub fn parse(contents: &str) -> Result<ConfigFile> {
+        toml::from_str(contents).map_err(|e| KvsError::InvalidConfig(e.to_string()))
+    }
+
+    /// The engine options as strings, like `--engine-opt` takes them.
+    pub fn engine_options(&self) -> impl Iterator<Item=(&str, String)> {
+        option_strings(&self.engine.options)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        let paths = [
+            &mut self.data_dir,
+            &mut self.acl,
+            &mut self.listen.unix,
+            &mut self.log.file,
+            &mut self.log.trace_file,
+            &mut self.tls.cert,
+            &mut self.tls.key,
+            &mut self.tls.client_ca,
+        ];
+        for path in paths.into_iter().flatten() {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+}
+
+/// The answer to `Request::Reload`, settings are named like in a `ConfigFile`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReloadReport {
+    /// Changed settings the server uses now.
+    pub applied: Vec<String>,
+    /// Changed settings the server only reads when it starts.
+    pub restart_required: Vec<String>,
+}
+
+/// Reloads the settings of a server, see `KvsServer::set_reload_handler`.
+pub type ReloadHandler = Arc<dyn Fn() -> Result<ReloadReport> + Send + Sync>;
+
+fn option_strings(options: &BTreeMap<String, toml::Value>) -> impl Iterator<Item=(&str, String)> {
+    options.iter().map(|(key, value)| {
+        let value = match value {
+            toml::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        (key.as_str(), value)
+    })
+}
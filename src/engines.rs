@@ -1,8 +1,14 @@
-use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use crate::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{CompactionPolicy, KvsError, KvStore, MemoryKvsEngine, Result, SledKvsEngine};
 
 pub trait KvsEngine: Clone + Send + 'static {
     // Set the value of a string key to a string.
@@ -11,6 +17,280 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
     // Remove a given string key.
     fn remove(&self, key: String) -> Result<()>;
+    // List the keys starting with `prefix` in ascending order, unsupported by default.
+    fn scan_prefix(&self, _prefix: String) -> Result<Vec<String>> {
+        Err(KvsError::Unsupported("listing keys".to_string()))
+    }
+    // Write everything kept in memory to disk, called before the server stops.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    // What the engine knows about itself, nothing by default.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats::default())
+    }
+    // Compact the storage now, returns the bytes freed.
+    fn compact(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("compaction".to_string()))
+    }
+    // The name of the engine, as reported by `Request::Stats`.
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+}
+
+/// What an engine knows about itself, `None` where it keeps no such number.
+///
+/// Compactions are counted since the engine was opened.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    pub keys: Option<u64>,
+    pub size_bytes: Option<u64>,
+    /// Bytes of the log holding the current values, the rest is freed by the next compaction.
+    pub live_bytes: Option<u64>,
+    pub dead_bytes: Option<u64>,
+    pub since_last_compact_log_num: Option<u64>,
+    pub compactions: u64,
+    pub compaction_time: Duration,
+    pub last_compaction: Option<SystemTime>,
+    pub reclaimed_bytes: u64,
+    /// Size of each file of the engine, by file name.
+    pub files: BTreeMap<String, u64>,
+}
+
+/// Object safe version of `KvsEngine`, so engines can be chosen at runtime
+/// and shared as `SharedEngine` without making the caller generic.
+///
+/// Every `KvsEngine` that is also `Sync` implements it.
+///
+/// The engines store strings, so the `_bytes` methods only take UTF-8 keys and values
+/// and fail with `KvsError::NotUtf8` otherwise, for callers holding raw bytes.
+pub trait DynKvsEngine: Send + Sync {
+    // Set the value of a string key to a string.
+    fn set(&self, key: &str, value: &str) -> Result<()>;
+    // Get the string value of a string key.
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    // Remove a given string key.
+    fn remove(&self, key: &str) -> Result<()>;
+    // List the keys starting with `prefix` in ascending order.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<String>>;
+    // Write everything kept in memory to disk, called before the server stops.
+    fn flush(&self) -> Result<()>;
+    fn stats(&self) -> Result<EngineStats>;
+    fn compact(&self) -> Result<u64>;
+    fn name(&self) -> &'static str;
+
+    // Set the value of a byte key to bytes, both UTF-8.
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set(utf8(key, "key")?, utf8(value, "value")?)
+    }
+
+    // Get the value of a UTF-8 byte key as bytes.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(utf8(key, "key")?)?.map(String::into_bytes))
+    }
+
+    // Remove a given UTF-8 byte key.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.remove(utf8(key, "key")?)
+    }
+}
+
+fn utf8<'a>(bytes: &'a [u8], what: &'static str) -> Result<&'a str> {
+    std::str::from_utf8(bytes).map_err(|_| KvsError::NotUtf8(what))
+}
+
+impl<E: KvsEngine + Sync> DynKvsEngine for E {
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        KvsEngine::set(self, key.to_string(), value.to_string())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        KvsEngine::get(self, key.to_string())
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        KvsEngine::remove(self, key.to_string())
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        KvsEngine::scan_prefix(self, prefix.to_string())
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvsEngine::flush(self)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        KvsEngine::stats(self)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        KvsEngine::compact(self)
+    }
+
+    fn name(&self) -> &'static str {
+        KvsEngine::name(self)
+    }
+}
+
+pub type SharedEngine = Arc<dyn DynKvsEngine>;
+
+/// Engine specific options, passed to the engine factory as plain strings.
+#[derive(Clone, Debug, Default)]
+pub struct EngineConfig {
+    options: BTreeMap<String, String>,
+}
+
+impl EngineConfig {
+    pub fn new() -> EngineConfig {
+        EngineConfig::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.options.insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(String::as_str)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.options.keys().map(String::as_str)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        self.get_parsed(key)
+    }
+
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Result<Option<T>> {
+        match self.get(key) {
+            Some(val) => match val.parse() {
+                Ok(val) => Ok(Some(val)),
+                Err(_) => Err(KvsError::InvalidEngineOption(format!("{}={}", key, val))),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+type EngineFactory = Arc<dyn Fn(&Path, &EngineConfig) -> Result<SharedEngine> + Send + Sync>;
+
+#[derive(Clone)]
+struct RegisteredEngine {
+    factory: EngineFactory,
+    // `None` if the engine didn't say which options it reads
+    options: Option<Vec<String>>,
+}
+
+/// Maps engine names to the functions that open them.
+///
+/// `EngineRegistry::default()` knows the engines shipped with this crate,
+/// other engines can be added with `register`.
+#[derive(Clone)]
+pub struct EngineRegistry {
+    factories: BTreeMap<String, RegisteredEngine>,
+}
+
+impl EngineRegistry {
+    pub fn new() -> EngineRegistry {
+        EngineRegistry { factories: BTreeMap::new() }
+    }
+
+    /// Register an engine that is given every option, and checks them itself.
+    pub fn register<F>(&mut self, name: &str, factory: F)
+        where F: Fn(&Path, &EngineConfig) -> Result<SharedEngine> + Send + Sync + 'static {
+        self.factories.insert(name.to_string(), RegisteredEngine { factory: Arc::new(factory), options: None });
+    }
+
+    /// Register an engine reading the options `options`.
+    ///
+    /// Opening it fails on an option no registered engine reads. Options of the other
+    /// engines are accepted, as databases of several engines share one config.
+    pub fn register_with_options<F>(&mut self, name: &str, options: &[&str], factory: F)
+        where F: Fn(&Path, &EngineConfig) -> Result<SharedEngine> + Send + Sync + 'static {
+        let options = options.iter().map(|option| option.to_string()).collect();
+        self.factories.insert(name.to_string(), RegisteredEngine { factory: Arc::new(factory), options: Some(options) });
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.factories.keys().map(String::as_str).collect()
+    }
+
+    /// The options read by the registered engines.
+    pub fn options(&self) -> Vec<&str> {
+        let options: BTreeSet<&str> = self.factories.values()
+            .flat_map(|engine| engine.options.iter().flatten())
+            .map(String::as_str)
+            .collect();
+        options.into_iter().collect()
+    }
+
+    /// Open the engine `name` in `path`.
+    ///
+    /// Fails if the directory was created by another engine, otherwise records
+    /// `name` as the engine of the directory.
+    pub fn open(&self, name: &str, path: impl Into<PathBuf>, config: &EngineConfig) -> Result<SharedEngine> {
+        let path = path.into();
+        let engine = match self.factories.get(name) {
+            Some(engine) => engine,
+            None => return Err(KvsError::UnknownEngine(name.to_string())),
+        };
+        if engine.options.is_some() {
+            let options = self.options();
+            if let Some(key) = config.keys().find(|key| !options.contains(key)) {
+                return Err(KvsError::UnknownEngineOption(key.to_string(), options.join(", ")));
+            }
+        }
+        if let Some(before) = get_engine_name(&path)? {
+            if before.ne(name) {
+                return Err(KvsError::WrongEngine(before, name.to_string()));
+            }
+        }
+        if !path.exists() {
+            create_dir_all(&path)?;
+        }
+        let engine = (engine.factory)(&path, config)?;
+        write_engine(name, &path)?;
+
+        Ok(engine)
+    }
+}
+
+impl Default for EngineRegistry {
+    fn default() -> Self {
+        let mut registry = EngineRegistry::new();
+        registry.register_with_options("kvs", &["compact_dead_ratio", "compact_dead_bytes", "sync"], |path, config| {
+            let mut policy = CompactionPolicy::default();
+            if let Some(ratio) = config.get_parsed::<f64>("compact_dead_ratio")? {
+                if !(0.0..=1.0).contains(&ratio) {
+                    return Err(KvsError::InvalidEngineOption(format!("compact_dead_ratio={}", ratio)));
+                }
+                policy.min_dead_ratio = ratio;
+            }
+            if let Some(bytes) = config.get_parsed("compact_dead_bytes")? {
+                policy.min_dead_bytes = bytes;
+            }
+            let store = KvStore::open_with_policy(path, policy)?;
+            store.set_sync(config.get_bool("sync")?.unwrap_or(false));
+            Ok(Arc::new(store))
+        });
+        registry.register_with_options("sled", &[], |path, _config| {
+            Ok(Arc::new(SledKvsEngine::open(path)?))
+        });
+        registry.register_with_options("memory", &["snapshot"], |path, config| {
+            if config.get_bool("snapshot")?.unwrap_or(false) {
+                Ok(Arc::new(MemoryKvsEngine::open(path)?))
+            } else {
+                Ok(Arc::new(MemoryKvsEngine::new()))
+            }
+        });
+        registry
+    }
 }
 
 pub fn get_engine_name(path: impl Into<PathBuf>) -> Result<Option<String>> {
@@ -29,9 +309,9 @@ pub fn write_engine(engine: &str, path: impl Into<PathBuf>) -> Result<()> {
     let mut file = OpenOptions::new()
         .create(true)
         .write(true)
-        .append(false)
+        .truncate(true)
         .open(path)?;
     file.write_all(engine.as_bytes())?;
 
     Ok(())
-}
\ No newline at end of file
+}
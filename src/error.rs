@@ -1,5 +1,8 @@
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::RejectReason;
+
 #[derive(Error, Debug)]
 pub enum KvsError {
     #[error(transparent)]
@@ -14,8 +17,17 @@ pub enum KvsError {
     #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
 
-    #[error("failed to exec command, server return error: `{0}`")]
-    ServerRespError(String),
+    #[error(transparent)]
+    BincodeError(#[from] bincode::Error),
+
+    #[error("failed to exec command, server return error: `{message}`")]
+    ServerError { code: ErrorCode, message: String },
+
+    #[error("protocol error: {0}")]
+    ProtocolError(String),
+
+    #[error("frame of {0} bytes is larger than the limit of {1} bytes")]
+    FrameTooLarge(usize, usize),
 
     #[error(transparent)]
     SledError(#[from] sled::Error),
@@ -23,6 +35,108 @@ pub enum KvsError {
     #[error(transparent)]
     RayonBuilderError(#[from] rayon::ThreadPoolBuildError),
 
+    #[error(transparent)]
+    JoinError(#[from] tokio::task::JoinError),
+
+    #[error(transparent)]
+    TlsError(#[from] rustls::Error),
+
+    #[error("invalid TLS config: {0}")]
+    TlsConfigError(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("connection rejected: {0}")]
+    Rejected(RejectReason),
+
+    #[error("rate limit exceeded, retry later")]
+    RateLimited,
+
+    #[error("server busy, retry later")]
+    ServerBusy,
+
+    #[error("invalid ACL: {0}")]
+    InvalidAcl(String),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("unknown engine `{0}`")]
+    UnknownEngine(String),
+
+    #[error("unknown database `{0}`")]
+    UnknownDatabase(String),
+
+    #[error("wrong engine, before: `{0}`, now: `{1}`")]
+    WrongEngine(String, String),
+
+    #[error("invalid engine option `{0}`")]
+    InvalidEngineOption(String),
+
+    #[error("unknown engine option `{0}`, valid options: {1}")]
+    UnknownEngineOption(String, String),
+
+    #[error("unknown thread pool `{0}`")]
+    UnknownThreadPool(String),
+
+    #[error("{0} is not supported by this engine")]
+    Unsupported(String),
+
+    #[error("the {0} is not valid UTF-8")]
+    NotUtf8(&'static str),
+
     #[error("unknown error")]
     Unknown,
 }
+
+/// The kind of a `KvsError`, sent to clients in `Response::Error` so they can tell errors apart.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    Io,
+    KeyNotFound,
+    Corruption,
+    Serialization,
+    Protocol,
+    Storage,
+    Tls,
+    PermissionDenied,
+    Rejected,
+    RateLimited,
+    Busy,
+    Config,
+    UnknownDatabase,
+    Unsupported,
+    Internal,
+    Unknown,
+}
+
+impl KvsError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::IOError(_) => ErrorCode::Io,
+            KvsError::KeyNotFound(_) => ErrorCode::KeyNotFound,
+            KvsError::RecordError() => ErrorCode::Corruption,
+            KvsError::SerdeError(_) | KvsError::BincodeError(_) => ErrorCode::Serialization,
+            KvsError::ServerError { code, .. } => *code,
+            KvsError::ProtocolError(_) | KvsError::FrameTooLarge(_, _) => ErrorCode::Protocol,
+            KvsError::SledError(_) => ErrorCode::Storage,
+            KvsError::RayonBuilderError(_) | KvsError::JoinError(_) => ErrorCode::Internal,
+            KvsError::TlsError(_) | KvsError::TlsConfigError(_) => ErrorCode::Tls,
+            KvsError::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            KvsError::Rejected(_) => ErrorCode::Rejected,
+            KvsError::RateLimited => ErrorCode::RateLimited,
+            KvsError::ServerBusy => ErrorCode::Busy,
+            KvsError::InvalidAcl(_)
+            | KvsError::InvalidConfig(_)
+            | KvsError::UnknownEngine(_)
+            | KvsError::WrongEngine(_, _)
+            | KvsError::InvalidEngineOption(_)
+            | KvsError::UnknownEngineOption(_, _)
+            | KvsError::UnknownThreadPool(_) => ErrorCode::Config,
+            KvsError::UnknownDatabase(_) => ErrorCode::UnknownDatabase,
+            KvsError::Unsupported(_) | KvsError::NotUtf8(_) => ErrorCode::Unsupported,
+            KvsError::Unknown => ErrorCode::Unknown,
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use std::io::{self, Cursor, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog_scope::{debug, error, info, warn};
+use tiny_http::{Header, Method, Request as HttpRequest, Response as HttpResponse, Server};
+
+use crate::{Access, Acl, ConnectionLimits, DynKvsEngine, ErrorCode, KvsError, Metrics, RateLimit, RateLimiter, RejectReason, Result, SharedEngine, SlowLog, User};
+use crate::slow_log::SlowQuery;
+use crate::shutdown::{DEFAULT_SHUTDOWN_TIMEOUT, InFlight, SHUTDOWN_POLL_INTERVAL, ShutdownHandle};
+use crate::thread_pool::{AnyThreadPool, BoundedThreadPool, PoolKind};
+
+/// Serves the engine over HTTP with JSON bodies.
+///
+/// * `GET /v1/keys/{key}` returns `{"key", "value"}` or 404
+/// * `PUT /v1/keys/{key}` with `{"value"}` sets the key, 201 if it is new
+/// * `DELETE /v1/keys/{key}` removes the key, 404 if it is missing
+/// * `GET /v1/keys?prefix=` returns `{"keys"}` in ascending order
+///
+/// Values carry an `ETag`, `If-Match` and `If-None-Match` make writes conditional.
+/// `If-Match` compares tags strongly, so weak `W/` tags never match it.
+/// Conditional writes are only atomic with the other writes of this gateway.
+///
+/// With an ACL, requests log in with HTTP Basic authentication, an empty user name
+/// logs in with the password alone.
+pub struct HttpGateway {
+    addr: SocketAddr,
+    engine: SharedEngine,
+    acl: Option<Arc<Acl>>,
+    max_request_size: usize,
+    pool_kind: PoolKind,
+    threads: usize,
+    max_queued: Option<usize>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    metrics: Arc<Metrics>,
+    slow_log: Arc<SlowLog>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+// What the requests of a running gateway share.
+struct GatewayState {
+    engine: SharedEngine,
+    acl: Option<Arc<Acl>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    metrics: Arc<Metrics>,
+    slow_log: Arc<SlowLog>,
+    max_request_size: usize,
+    // Held between reading and writing a key, so conditional writes don't interleave
+    write_lock: Mutex<()>,
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+struct Reply {
+    status: u16,
+    body: Option<Value>,
+    etag: Option<String>,
+    // Asks the client to log in
+    challenge: bool,
+    // Counted in `Metrics`, `None` for requests that did not fail
+    code: Option<ErrorCode>,
+}
+
+impl Reply {
+    fn new(status: u16, body: Value) -> Reply {
+        Reply { status, body: Some(body), etag: None, challenge: false, code: None }
+    }
+
+    fn empty(status: u16) -> Reply {
+        Reply { status, body: None, etag: None, challenge: false, code: None }
+    }
+
+    fn unauthorized() -> Reply {
+        Reply { challenge: true, ..Reply::error(401, ErrorCode::PermissionDenied, "authentication required") }
+    }
+
+    fn error(status: u16, code: ErrorCode, message: impl ToString) -> Reply {
+        Reply { code: Some(code), ..Reply::new(status, json!({ "error": message.to_string() })) }
+    }
+
+    fn failed(status: u16, e: KvsError) -> Reply {
+        Reply::error(status, e.code(), e)
+    }
+
+    // A missing key is an answer rather than a failure, as `Response::NotFound` is
+    fn not_found(key: &str) -> Reply {
+        Reply::new(404, json!({ "error": KvsError::KeyNotFound(key.to_string()).to_string() }))
+    }
+
+    // So is a write whose precondition does not hold
+    fn precondition_failed() -> Reply {
+        Reply::new(412, json!({ "error": "precondition failed" }))
+    }
+
+    fn value_size(&self) -> Option<u64> {
+        self.body.as_ref()?.get("value")?.as_str().map(|value| value.len() as u64)
+    }
+
+    fn value(status: u16, key: &str, value: &str) -> Reply {
+        Reply {
+            status,
+            body: Some(json!({ "key": key, "value": value })),
+            etag: Some(etag(value)),
+            challenge: false,
+            code: None,
+        }
+    }
+
+    fn into_response(self) -> HttpResponse<Cursor<Vec<u8>>> {
+        let mut response = match self.body {
+            Some(body) => HttpResponse::from_data(body.to_string())
+                .with_header(header("Content-Type", "application/json")),
+            None => HttpResponse::from_data(Vec::new()),
+        };
+        if let Some(etag) = self.etag {
+            response.add_header(header("ETag", &etag));
+        }
+        if self.challenge {
+            response.add_header(header("WWW-Authenticate", "Basic realm=\"kvs\""));
+        }
+        response.with_status_code(self.status)
+    }
+}
+
+impl HttpGateway {
+    pub fn new(addr: SocketAddr, engine: SharedEngine) -> Self {
+        HttpGateway {
+            addr,
+            engine,
+            acl: None,
+            max_request_size: ConnectionLimits::default().max_request_size,
+            pool_kind: PoolKind::SharedQueue,
+            threads: num_cpus::get(),
+            max_queued: None,
+            rate_limiter: None,
+            metrics: Arc::new(Metrics::new()),
+            slow_log: Arc::new(SlowLog::default()),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Answer 413 to request bodies larger than `bytes`.
+    pub fn set_max_request_size(&mut self, bytes: usize) {
+        self.max_request_size = bytes;
+    }
+
+    /// Require Basic authentication and check every request against `acl`.
+    pub fn set_acl(&mut self, acl: Arc<Acl>) {
+        self.acl = Some(acl);
+    }
+
+    /// Answer requests on a pool of `threads` threads, a shared queue pool with a thread
+    /// per CPU by default.
+    pub fn set_thread_pool(&mut self, kind: PoolKind, threads: usize) {
+        assert!(threads > 0);
+        self.pool_kind = kind;
+        self.threads = threads;
+    }
+
+    /// Answer 503 once `jobs` requests are waiting for a thread, see `KvsServer::set_max_queued`.
+    pub fn set_max_queued(&mut self, jobs: usize) {
+        self.max_queued = Some(jobs);
+    }
+
+    /// Limit the requests of every client, see `RateLimit`. Requests over the limit are
+    /// answered with 429.
+    pub fn set_rate_limit(&mut self, limit: RateLimit) {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(Some(limit))));
+    }
+
+    /// Limit the requests of every client with a limiter shared with other servers.
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Count requests and errors in `metrics`, see `KvsServer::set_metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Keep slow requests in `slow_log`, see `KvsServer::set_slow_log`.
+    pub fn set_slow_log(&mut self, slow_log: Arc<SlowLog>) {
+        self.slow_log = slow_log;
+    }
+
+    /// Stops `handle_connection` from another thread, see `KvsServer::shutdown_handle`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// See `KvsServer::set_shutdown_timeout`.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Serve until the gateway is shut down, then flush the engine.
+    pub fn handle_connection(&mut self) -> Result<()> {
+        let server = Server::http(self.addr).map_err(io::Error::other)?;
+        let max_queued = self.max_queued.unwrap_or(usize::MAX);
+        let thread_pool = BoundedThreadPool::new(AnyThreadPool::with_kind(self.pool_kind, self.threads)?, max_queued);
+        self.metrics.watch_queue("http", thread_pool.queue());
+        let state = Arc::new(GatewayState {
+            engine: self.engine.clone(),
+            acl: self.acl.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            metrics: self.metrics.clone(),
+            slow_log: self.slow_log.clone(),
+            max_request_size: self.max_request_size,
+            write_lock: Mutex::new(()),
+        });
+        let in_flight = InFlight::default();
+
+        while !self.shutdown.is_shutdown() {
+            let request = match server.recv_timeout(SHUTDOWN_POLL_INTERVAL)? {
+                Some(request) => request,
+                None => continue,
+            };
+            debug!("Receive HTTP request.");
+            // Only this loop adds jobs, so the job below is not refused
+            if thread_pool.waiting() >= max_queued {
+                self.metrics.record_error(ErrorCode::Busy);
+                respond(request, Reply::failed(503, KvsError::ServerBusy));
+                continue;
+            }
+            let state = state.clone();
+            let working = in_flight.start();
+            thread_pool.try_spawn(move || {
+                let mut request = request;
+                let reply = serve_request(&state, &mut request);
+                respond(request, reply);
+                drop(working);
+            });
+        }
+
+        info!("Shutting down HTTP gateway {}", self.addr);
+        if !in_flight.wait(self.shutdown_timeout) {
+            warn!("HTTP requests still running after {:?}, stop waiting", self.shutdown_timeout);
+        }
+        self.engine.flush()
+    }
+}
+
+// `Authorization: Basic base64(user:password)`
+fn login(acl: &Acl, request: &HttpRequest) -> Option<Arc<User>> {
+    let credentials = header_value(request, "Authorization")?;
+    let (scheme, encoded) = credentials.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    let user = if user.is_empty() { None } else { Some(user) };
+    acl.authenticate(user, password).ok()
+}
+
+// Clients are rate limited by user once logged in, by address otherwise. Requests
+// that are not served only count their error.
+fn serve_request(state: &GatewayState, request: &mut HttpRequest) -> Reply {
+    let peer = request.remote_addr().map(|addr| addr.ip());
+    let user = state.acl.as_ref().and_then(|acl| login(acl, request));
+    if let Some(limiter) = &state.rate_limiter {
+        if let Err(e) = limiter.check(user.as_deref(), peer) {
+            state.metrics.record_error(e.code());
+            return Reply::failed(429, e);
+        }
+    }
+    if state.acl.is_some() && user.is_none() {
+        state.metrics.record_error(ErrorCode::PermissionDenied);
+        return Reply::unauthorized();
+    }
+    let route = match parse_route(request) {
+        Ok(route) => route,
+        Err(reply) => {
+            state.metrics.record_error(reply.code.unwrap_or(ErrorCode::Unknown));
+            return reply;
+        }
+    };
+
+    let started = Instant::now();
+    let reply = serve_route(state, user.as_deref(), &route, request);
+    state.metrics.record(route.name(), started.elapsed(), reply.code);
+    let query = SlowQuery::new(route.name(), route.key().map(str::to_owned), reply.value_size());
+    state.slow_log.record(query, started.elapsed(), peer);
+    reply
+}
+
+fn respond(request: HttpRequest, reply: Reply) {
+    if let Err(e) = request.respond(reply.into_response()) {
+        error!("Failed to send response: {}", e);
+    }
+}
+
+fn check(user: Option<&User>, access: Access, key: &str) -> Result<()> {
+    user.map_or(Ok(()), |user| user.check(access, key))
+}
+
+// What a request asks for, the key or query decoded.
+enum Route {
+    List(String),
+    Get(String),
+    Put(String),
+    Delete(String),
+}
+
+impl Route {
+    // The names of the matching `Request`s
+    fn name(&self) -> &'static str {
+        match self {
+            Route::List(_) => "scan",
+            Route::Get(_) => "get",
+            Route::Put(_) => "set",
+            Route::Delete(_) => "rm",
+        }
+    }
+
+    fn key(&self) -> Option<&str> {
+        match self {
+            Route::List(_) => None,
+            Route::Get(key) | Route::Put(key) | Route::Delete(key) => Some(key),
+        }
+    }
+}
+
+fn parse_route(request: &HttpRequest) -> std::result::Result<Route, Reply> {
+    let url = request.url();
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let key = match path.strip_prefix("/v1/keys") {
+        Some("") | Some("/") => None,
+        Some(key) if key.starts_with('/') => match percent_decode(&key[1..], false) {
+            Some(key) => Some(key),
+            None => return Err(Reply::error(400, ErrorCode::Protocol, "invalid key encoding")),
+        },
+        _ => return Err(Reply::error(404, ErrorCode::Unsupported, "not found")),
+    };
+    match (request.method(), key) {
+        (Method::Get, None) => Ok(Route::List(query.to_owned())),
+        (Method::Get, Some(key)) => Ok(Route::Get(key)),
+        (Method::Put, Some(key)) => Ok(Route::Put(key)),
+        (Method::Delete, Some(key)) => Ok(Route::Delete(key)),
+        _ => Err(Reply::error(405, ErrorCode::Unsupported, "method not allowed")),
+    }
+}
+
+fn serve_route(state: &GatewayState, user: Option<&User>, route: &Route, request: &mut HttpRequest) -> Reply {
+    let engine = state.engine.as_ref();
+    let result = match route {
+        Route::List(query) => list_keys(engine, user, query),
+        Route::Get(key) => check(user, Access::Read, key)
+            .and_then(|_| get_key(engine, request, key)),
+        Route::Put(key) => check(user, Access::Write, key)
+            .and_then(|_| put_key(engine, &state.write_lock, state.max_request_size, request, key)),
+        Route::Delete(key) => check(user, Access::Write, key)
+            .and_then(|_| delete_key(engine, &state.write_lock, request, key)),
+    };
+    result.unwrap_or_else(|e| match e {
+        KvsError::PermissionDenied(_) => Reply::failed(403, e),
+        e => Reply::failed(500, e),
+    })
+}
+
+// Only the keys the user may read are listed.
+fn list_keys(engine: &dyn DynKvsEngine, user: Option<&User>, query: &str) -> Result<Reply> {
+    let mut prefix = String::new();
+    for pair in query.split('&') {
+        if let Some(("prefix", value)) = pair.split_once('=') {
+            prefix = match percent_decode(value, true) {
+                Some(value) => value,
+                None => return Ok(Reply::error(400, ErrorCode::Protocol, "invalid prefix encoding")),
+            };
+        }
+    }
+    let mut keys = engine.scan_prefix(&prefix)?;
+    if let Some(user) = user {
+        keys.retain(|key| user.allows(Access::Read, key));
+    }
+    Ok(Reply::new(200, json!({ "keys": keys })))
+}
+
+fn get_key(engine: &dyn DynKvsEngine, request: &HttpRequest, key: &str) -> Result<Reply> {
+    match engine.get(key)? {
+        Some(value) => {
+            if let Some(tags) = header_value(request, "If-None-Match") {
+                if matches_etag(&tags, &value, false) {
+                    let mut reply = Reply::empty(304);
+                    reply.etag = Some(etag(&value));
+                    return Ok(reply);
+                }
+            }
+            Ok(Reply::value(200, key, &value))
+        }
+        None => Ok(Reply::not_found(key)),
+    }
+}
+
+fn put_key(engine: &dyn DynKvsEngine, write_lock: &Mutex<()>, max_request_size: usize, request: &mut HttpRequest, key: &str) -> Result<Reply> {
+    let mut body = Vec::new();
+    request.as_reader().take(max_request_size as u64 + 1).read_to_end(&mut body)?;
+    if body.len() > max_request_size {
+        return Ok(Reply::error(413, ErrorCode::Rejected, RejectReason::RequestTooLarge));
+    }
+    let body: PutBody = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(e) => return Ok(Reply::failed(400, e.into())),
+    };
+
+    let _guard = write_lock.lock().unwrap();
+    let current = engine.get(key)?;
+    if !preconditions_hold(request, current.as_deref()) {
+        return Ok(Reply::precondition_failed());
+    }
+    engine.set(key, &body.value)?;
+    let status = if current.is_some() { 200 } else { 201 };
+    Ok(Reply::value(status, key, &body.value))
+}
+
+fn delete_key(engine: &dyn DynKvsEngine, write_lock: &Mutex<()>, request: &HttpRequest, key: &str) -> Result<Reply> {
+    let _guard = write_lock.lock().unwrap();
+    let current = engine.get(key)?;
+    if current.is_none() {
+        return Ok(Reply::not_found(key));
+    }
+    if !preconditions_hold(request, current.as_deref()) {
+        return Ok(Reply::precondition_failed());
+    }
+    match engine.remove(key) {
+        Ok(_) => Ok(Reply::empty(204)),
+        Err(KvsError::KeyNotFound(_)) => Ok(Reply::not_found(key)),
+        Err(e) => Err(e),
+    }
+}
+
+fn preconditions_hold(request: &HttpRequest, current: Option<&str>) -> bool {
+    if let Some(tags) = header_value(request, "If-Match") {
+        match current {
+            Some(value) if matches_etag(&tags, value, true) => {}
+            _ => return false,
+        }
+    }
+    if let Some(tags) = header_value(request, "If-None-Match") {
+        if let Some(value) = current {
+            if matches_etag(&tags, value, false) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// `tags` is `*` or a comma separated list of entity tags, a `strong` comparison
+// never matches weak tags (RFC 9110 section 8.8.3.2).
+fn matches_etag(tags: &str, value: &str, strong: bool) -> bool {
+    let etag = etag(value);
+    tags.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || match tag.strip_prefix("W/") {
+            Some(weak) => !strong && weak == etag,
+            None => tag == etag,
+        })
+}
+
+// FNV-1a of the value, stable across restarts and builds.
+fn etag(value: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in value.as_bytes() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{:016x}\"", hash)
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn header_value(request: &HttpRequest, field: &'static str) -> Option<String> {
+    request.headers()
+        .iter()
+        .find(|header| header.field.equiv(field))
+        .map(|header| header.value.to_string())
+}
+
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Standard alphabet, padding is optional.
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut bit_len = 0;
+    for b in s.trim_end_matches('=').bytes() {
+        let sextet = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | sextet as u32;
+        bit_len += 6;
+        if bit_len >= 8 {
+            bit_len -= 8;
+            decoded.push((bits >> bit_len) as u8);
+            bits &= (1 << bit_len) - 1;
+        }
+    }
+    Some(decoded)
+}
@@ -1,16 +1,42 @@
 use std::collections::HashMap;
-use std::fs::{create_dir_all, File, OpenOptions, remove_file, rename};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File, OpenOptions, read_dir, remove_file, rename};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::io::BufReader;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::{KvsEngine, KvsError, Result};
+use crate::{EngineStats, KvsEngine, KvsError, Result};
+
+/// When a `KvStore` compacts its log on its own.
+///
+/// Overwritten and removed values stay in the log as dead bytes until a compaction.
+/// The log is compacted after a write once its dead bytes reach `min_dead_bytes` and
+/// make up at least `min_dead_ratio` of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompactionPolicy {
+    pub min_dead_ratio: f64,
+    pub min_dead_bytes: u64,
+}
+
+impl Default for CompactionPolicy {
+    // Half of the log and at least 1 MiB
+    fn default() -> Self {
+        CompactionPolicy { min_dead_ratio: 0.5, min_dead_bytes: 1024 * 1024 }
+    }
+}
 
-const COMPACT_NUM_THRESHOLD: usize = 512;
+impl CompactionPolicy {
+    fn should_compact(&self, dead_bytes: u64, log_bytes: u64) -> bool {
+        dead_bytes > 0
+            && dead_bytes >= self.min_dead_bytes
+            && dead_bytes as f64 >= self.min_dead_ratio * log_bytes as f64
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LogPosition {
@@ -35,6 +61,25 @@ pub struct MetaData {
 struct MutableKvsData {
     metadata: MetaData,
     store_map: HashMap<String, LogPosition>,
+    // Bytes of the log the map points to
+    #[serde(skip)]
+    live_bytes: u64,
+    #[serde(skip)]
+    policy: CompactionPolicy,
+    // Whether writes reach the disk before they return
+    #[serde(skip)]
+    sync: bool,
+    #[serde(skip)]
+    compactions: CompactionStats,
+}
+
+// Compactions since the store was opened
+#[derive(Debug, Default)]
+struct CompactionStats {
+    runs: u64,
+    duration: Duration,
+    last: Option<SystemTime>,
+    reclaimed_bytes: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -49,7 +94,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -78,7 +123,7 @@ fn rebuild_map(log_entry_path: impl Into<PathBuf>) -> Result<HashMap<String, Log
     let mut stream = Deserializer::from_reader(reader).into_iter::<LogEntry>();
     let mut result: HashMap<String, LogPosition> = HashMap::new();
     while let Some(log_entry) = stream.next() {
-        let new_pos = stream.byte_offset() as usize;
+        let new_pos = stream.byte_offset();
         match log_entry? {
             LogEntry::Set { key, .. } => {
                 let log_position = LogPosition { start: pos, len: new_pos - pos };
@@ -106,6 +151,10 @@ impl KvStore {
         let data = Arc::new(Mutex::new(MutableKvsData {
             metadata,
             store_map,
+            live_bytes: 0,
+            policy: CompactionPolicy::default(),
+            sync: false,
+            compactions: CompactionStats::default(),
         }));
         KvStore {
             data
@@ -113,9 +162,14 @@ impl KvStore {
     }
 
     pub fn new_with_data(metadata: MetaData, store_map: HashMap<String, LogPosition>) -> KvStore {
+        let live_bytes = store_map.values().map(|pos| pos.len as u64).sum();
         let data = Arc::new(Mutex::new(MutableKvsData {
             metadata,
             store_map,
+            live_bytes,
+            policy: CompactionPolicy::default(),
+            sync: false,
+            compactions: CompactionStats::default(),
         }));
         KvStore {
             data
@@ -126,7 +180,23 @@ impl KvStore {
         self.data.lock().unwrap().save_memory_map()
     }
 
+    /// Open the store in `path` with the default `CompactionPolicy`.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_policy(path, CompactionPolicy::default())
+    }
+
+    pub fn open_with_policy(path: impl Into<PathBuf>, policy: CompactionPolicy) -> Result<KvStore> {
+        let store = KvStore::open_store(path)?;
+        store.data.lock().unwrap().policy = policy;
+        Ok(store)
+    }
+
+    /// Write every change to disk before `set` and `remove` return, off by default.
+    pub fn set_sync(&self, sync: bool) {
+        self.data.lock().unwrap().sync = sync;
+    }
+
+    fn open_store(path: impl Into<PathBuf>) -> Result<KvStore> {
         let path = path.into();
         let kvs_metadata_path = path.join("kvs_metadata");
         let log_entry_path = path.join("kvs_log_entry");
@@ -190,6 +260,52 @@ impl KvsEngine for KvStore {
         self.data.lock().unwrap().remove(key)?;
         Ok(())
     }
+
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        let data = self.data.lock().unwrap();
+        let mut keys: Vec<String> = data.store_map.keys()
+            .filter(|key| key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.save_memory_map()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let data = self.data.lock().unwrap();
+        let mut files = BTreeMap::new();
+        for entry in read_dir(&data.metadata.store_path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.insert(entry.file_name().to_string_lossy().into_owned(), metadata.len());
+            }
+        }
+        Ok(EngineStats {
+            keys: Some(data.store_map.len() as u64),
+            size_bytes: Some(data.metadata.cur_file_end as u64),
+            live_bytes: Some(data.live_bytes),
+            dead_bytes: Some(data.dead_bytes()),
+            since_last_compact_log_num: Some(data.metadata.since_last_compact_log_num as u64),
+            compactions: data.compactions.runs,
+            compaction_time: data.compactions.duration,
+            last_compaction: data.compactions.last,
+            reclaimed_bytes: data.compactions.reclaimed_bytes,
+            files,
+        })
+    }
+
+    fn compact(&self) -> Result<u64> {
+        self.data.lock().unwrap().compact()
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
 }
 
 impl MutableKvsData {
@@ -199,20 +315,19 @@ impl MutableKvsData {
             value,
         };
         let log_pos = self.save_log_entry(&log_entry)?;
-        self.store_map.insert(key, log_pos);
-        self.save_metadata()?;
-
-        if self.metadata.since_last_compact_log_num > COMPACT_NUM_THRESHOLD {
-            self.compact()?;
+        self.live_bytes += log_pos.len as u64;
+        if let Some(old_pos) = self.store_map.insert(key, log_pos) {
+            self.live_bytes -= old_pos.len as u64;
         }
+        self.save_metadata()?;
 
-        Ok(())
+        self.compact_by_policy()
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
         if self.store_map.contains_key(&key) {
             let log_pos = self.store_map.get(&key).unwrap();
-            let mut file = File::open(&self.metadata.store_path.join("kvs_log_entry"))?;
+            let mut file = File::open(self.metadata.store_path.join("kvs_log_entry"))?;
             file.seek(SeekFrom::Start(log_pos.start as u64))?;
             let mut buf = Vec::with_capacity(log_pos.len);
             file.take(log_pos.len as u64).read_to_end(&mut buf)?;
@@ -230,26 +345,26 @@ impl MutableKvsData {
         if !self.store_map.contains_key(&key) {
             return Err(KvsError::KeyNotFound(key));
         }
-        self.store_map.remove(&key);
+        if let Some(old_pos) = self.store_map.remove(&key) {
+            self.live_bytes -= old_pos.len as u64;
+        }
         let log_entry = LogEntry::Rm { key };
         self.save_log_entry(&log_entry)?;
         self.save_metadata()?;
 
-        if self.metadata.since_last_compact_log_num > COMPACT_NUM_THRESHOLD {
-            self.compact()?;
-        }
-
-        Ok(())
+        self.compact_by_policy()
     }
 
     fn save_log_entry(&mut self, log_entry: &LogEntry) -> Result<LogPosition> {
         let mut store_file = OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
-            .open(&self.metadata.store_path.join("kvs_log_entry"))?;
+            .open(self.metadata.store_path.join("kvs_log_entry"))?;
         let serialized_log = serde_json::to_vec(&log_entry)?;
         store_file.write_all(&serialized_log)?;
+        if self.sync {
+            store_file.sync_data()?;
+        }
 
         let log_pos = LogPosition {
             start: self.metadata.cur_file_end,
@@ -265,11 +380,28 @@ impl MutableKvsData {
         let serialized_kvs = serde_json::to_string(&self.metadata)?;
         let mut file = File::create(self.metadata.store_path.join("kvs_metadata"))?;
         file.write_all(serialized_kvs.as_bytes())?;
+        if self.sync {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
 
+    fn dead_bytes(&self) -> u64 {
+        (self.metadata.cur_file_end as u64).saturating_sub(self.live_bytes)
+    }
+
+    fn compact_by_policy(&mut self) -> Result<()> {
+        if self.policy.should_compact(self.dead_bytes(), self.metadata.cur_file_end as u64) {
+            self.compact()?;
+        }
         Ok(())
     }
 
-    fn compact(&mut self) -> Result<()> {
+    // Rewrites the log with the live values only, returns the bytes freed.
+    fn compact(&mut self) -> Result<u64> {
+        let started = Instant::now();
+        let size_before = self.metadata.cur_file_end;
         self.metadata.cur_file_end = 0;
         self.metadata.since_last_compact_log_num = 0;
         let mut new_store_map: HashMap<String, LogPosition> = HashMap::new();
@@ -297,28 +429,34 @@ impl MutableKvsData {
 
         self.write_new_log_entry_file(&all_serialized_log)?;
         self.store_map = new_store_map;
+        self.live_bytes = self.metadata.cur_file_end as u64;
         self.save_metadata()?;
 
-        Ok(())
+        let reclaimed_bytes = size_before.saturating_sub(self.metadata.cur_file_end) as u64;
+        self.compactions.runs += 1;
+        self.compactions.duration += started.elapsed();
+        self.compactions.last = Some(SystemTime::now());
+        self.compactions.reclaimed_bytes += reclaimed_bytes;
+
+        Ok(reclaimed_bytes)
     }
 
     fn write_new_log_entry_file(&self, content: &[u8]) -> Result<()> {
         let mut store_file = OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
-            .open(&self.metadata.store_path.join("kvs_log_entry.new"))?;
+            .open(self.metadata.store_path.join("kvs_log_entry.new"))?;
         store_file.write_all(content)?;
 
         rename(
-            &self.metadata.store_path.join("kvs_log_entry"),
-            &self.metadata.store_path.join("kvs_log_entry.bak"),
+            self.metadata.store_path.join("kvs_log_entry"),
+            self.metadata.store_path.join("kvs_log_entry.bak"),
         )?;
         rename(
-            &self.metadata.store_path.join("kvs_log_entry.new"),
-            &self.metadata.store_path.join("kvs_log_entry"),
+            self.metadata.store_path.join("kvs_log_entry.new"),
+            self.metadata.store_path.join("kvs_log_entry"),
         )?;
-        remove_file(&self.metadata.store_path.join("kvs_log_entry.bak"))?;
+        remove_file(self.metadata.store_path.join("kvs_log_entry.bak"))?;
 
         Ok(())
     }
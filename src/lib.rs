@@ -1,29 +1,82 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
+pub use acl::{Access, Acl, User};
+pub use async_client::AsyncKvsClient;
+pub use async_engine::AsyncKvsEngine;
+pub use async_server::AsyncKvsServer;
 pub use client::KvsClient;
+pub use config::{ConfigFile, ReloadHandler, ReloadReport};
 pub use engines::{get_engine_name, write_engine};
-pub use engines::KvsEngine;
-pub use kvs_engine::KvStore;
-pub use server::KvsServer;
+pub use engines::{DynKvsEngine, EngineConfig, EngineRegistry, EngineStats, KvsEngine, SharedEngine};
+pub use http_gateway::HttpGateway;
+pub use kvs_engine::{CompactionPolicy, KvStore};
+pub use limits::{ConnectionLimits, RejectReason, Rejections};
+pub use memory_engine::MemoryKvsEngine;
+pub use metrics::{Metrics, MetricsEndpoint};
+pub use protocol::{BINARY_HANDSHAKE, Protocol, PROTOCOL_VERSION, RequestEnvelope, ResponseEnvelope, ServerAddr, ServerProtocol};
+pub use rate_limit::{RateLimit, RateLimiter};
+pub use server::{DEFAULT_DATABASE, DEFAULT_UNIX_MODE, KvsServer};
+pub use shutdown::{DEFAULT_SHUTDOWN_TIMEOUT, ShutdownHandle};
 pub use sled_engine::SledKvsEngine;
-pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use slow_log::{SlowLog, SlowLogEntry};
+pub use trace::SpanExporter;
+pub use thread_pool::{AnyThreadPool, BoundedThreadPool, NaiveThreadPool, PoolKind, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 
-use crate::error::KvsError;
+pub use error::{ErrorCode, KvsError};
 
+mod acl;
 mod error;
 mod engines;
 mod server;
+mod async_server;
+mod async_engine;
 mod client;
+pub mod config;
+mod http_gateway;
+mod async_client;
 mod kvs_engine;
+mod limits;
+mod memory_engine;
+mod metrics;
+mod protocol;
+mod rate_limit;
+mod resp;
+mod shutdown;
 mod sled_engine;
+mod slow_log;
 pub mod thread_pool;
+pub mod tls;
+mod trace;
 
 pub type Result<T> = std::result::Result<T, KvsError>;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Response {
-    is_ok: bool,
-    data: String,
+/// The answer to a `Request`.
+///
+/// `Get` answers `Value` or `NotFound`, so an empty value is not mistaken for a missing key.
+/// `Rm` of a missing key answers `NotFound` as well.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum Response {
+    Ok,
+    Value(String),
+    NotFound,
+    Stats(ServerStats),
+    Compacted { reclaimed_bytes: u64 },
+    SlowLog(Vec<SlowLogEntry>),
+    Reloaded(ReloadReport),
+    Error { code: ErrorCode, message: String },
+}
+
+/// The answer to `Request::Stats`.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ServerStats {
+    pub version: String,
+    pub engine: String,
+    pub uptime: Duration,
+    /// Threads serving requests, in async mode running engine calls.
+    pub threads: usize,
+    pub engine_stats: EngineStats,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -31,11 +84,70 @@ pub enum Request {
     Set { key: String, value: String },
     Get { key: String },
     Rm { key: String },
+    /// Log in as the first request of a connection, `user` may be left out if `password` is a token.
+    Auth {
+        #[serde(default)]
+        user: Option<String>,
+        password: String,
+    },
+    /// Statistics of the server and its engine, needs `Access::Admin` with an ACL.
+    Stats,
+    /// Compact the engine now, needs `Access::Admin` with an ACL.
+    Compact,
+    /// The slow requests kept by the server, needs `Access::Admin` with an ACL.
+    SlowLog,
+    /// Send the next requests of the connection to the database `db`, see `KvsServer::add_database`.
+    Select { db: String },
+    /// Reload the settings that can change while the server runs, needs `Access::Admin` with an ACL.
+    Reload,
+}
+
+impl Request {
+    /// The access to a key that the request needs, `None` if it needs none.
+    pub fn access(&self) -> Option<(Access, &str)> {
+        match self {
+            Request::Set { key, .. } | Request::Rm { key } => Some((Access::Write, key)),
+            Request::Get { key } => Some((Access::Read, key)),
+            Request::Auth { .. } | Request::Select { .. } => None,
+            Request::Stats | Request::Compact | Request::SlowLog | Request::Reload => Some((Access::Admin, "")),
+        }
+    }
+
+    /// The type of the request, as counted in `Metrics`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Rm { .. } => "rm",
+            Request::Auth { .. } => "auth",
+            Request::Stats => "stats",
+            Request::Compact => "compact",
+            Request::SlowLog => "slowlog",
+            Request::Select { .. } => "select",
+            Request::Reload => "reload",
+        }
+    }
 }
 
 impl Response {
-    pub fn new(is_ok: bool, data: String) -> Response {
-        Response { is_ok, data }
+    pub fn is_ok(&self) -> bool {
+        !matches!(self, Response::Error { .. })
+    }
+
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Response::Error { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl From<KvsError> for Response {
+    fn from(e: KvsError) -> Response {
+        match e {
+            KvsError::KeyNotFound(_) => Response::NotFound,
+            e => Response::Error { code: e.code(), message: e.to_string() },
+        }
     }
 }
 
@@ -0,0 +1,215 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use slog_scope::info;
+
+use crate::protocol::{MAX_FRAME_LEN, Stream};
+
+// How long a rejected client gets to read its reply
+pub(crate) const LINGER_TIMEOUT: Duration = Duration::from_millis(500);
+// Threads of a blocking server lingering on rejected connections, and how many may wait
+// for them, the others are closed at once
+pub(crate) const LINGER_THREADS: usize = 2;
+pub(crate) const MAX_LINGERING: usize = 64;
+
+/// Limits enforced by a server on its connections, `None` is unlimited.
+///
+/// A connection breaking a limit gets an error response when the protocol can carry one,
+/// and is closed.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    /// How long a connection may wait before starting a request.
+    pub idle_timeout: Option<Duration>,
+    /// How long a client may take to send a request once it started.
+    pub read_timeout: Option<Duration>,
+    /// How long a client may take to receive a response.
+    pub write_timeout: Option<Duration>,
+    pub max_request_size: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: None,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            max_request_size: MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// Why a server closed a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RejectReason {
+    TooManyConnections,
+    IdleTimeout,
+    ReadTimeout,
+    WriteTimeout,
+    RequestTooLarge,
+    ServerBusy,
+}
+
+impl RejectReason {
+    pub const ALL: [RejectReason; 6] = [
+        RejectReason::TooManyConnections,
+        RejectReason::IdleTimeout,
+        RejectReason::ReadTimeout,
+        RejectReason::WriteTimeout,
+        RejectReason::RequestTooLarge,
+        RejectReason::ServerBusy,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RejectReason::TooManyConnections => "too_many_connections",
+            RejectReason::IdleTimeout => "idle_timeout",
+            RejectReason::ReadTimeout => "read_timeout",
+            RejectReason::WriteTimeout => "write_timeout",
+            RejectReason::RequestTooLarge => "request_too_large",
+            RejectReason::ServerBusy => "server_busy",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RejectReason::TooManyConnections => "too many connections",
+            RejectReason::IdleTimeout => "idle for too long",
+            RejectReason::ReadTimeout => "request not received in time",
+            RejectReason::WriteTimeout => "response not received in time",
+            RejectReason::RequestTooLarge => "request too large",
+            RejectReason::ServerBusy => "server busy",
+        };
+        f.write_str(message)
+    }
+}
+
+/// How many connections a server closed for each `RejectReason`.
+#[derive(Debug, Default)]
+pub struct Rejections {
+    counts: [AtomicU64; RejectReason::ALL.len()],
+}
+
+impl Rejections {
+    pub fn count(&self, reason: RejectReason) -> u64 {
+        self.counts[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add(&self, reason: RejectReason) {
+        info!("Close connection. reason: {}", reason);
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Closes a rejected connection once the client has closed it too, or after `LINGER_TIMEOUT`.
+///
+/// Closing a socket with unread input resets it, and the client may lose the reply,
+/// so the input is read and dropped until then.
+pub(crate) fn linger<S: Stream>(mut stream: S) {
+    let _ = stream.shutdown_write();
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut buf = [0; 4096];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+            return;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+// A blocking read or write ran out of time.
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Where a connection is between its requests, shared with the `RequestReader` reading it.
+#[derive(Default)]
+pub(crate) struct RequestState {
+    started: Cell<Option<Instant>>,
+    len: Cell<usize>,
+    rejected: Cell<Option<RejectReason>>,
+}
+
+impl RequestState {
+    // The next byte read starts a new request.
+    pub(crate) fn request_done(&self) {
+        self.started.set(None);
+        self.len.set(0);
+    }
+
+    pub(crate) fn rejected(&self) -> Option<RejectReason> {
+        self.rejected.get()
+    }
+}
+
+/// Applies the idle and read timeouts and the request size limit to the reads of a
+/// blocking connection. Reads fail once a limit is broken, see `RequestState::rejected`.
+///
+/// Bytes buffered past the end of a request count as idle, so the limits are approximate.
+pub(crate) struct RequestReader<S: Stream> {
+    stream: S,
+    limits: ConnectionLimits,
+    state: Rc<RequestState>,
+    // The timeout currently set on the socket
+    timeout: Option<Duration>,
+}
+
+impl<S: Stream> RequestReader<S> {
+    pub(crate) fn new(stream: S, limits: ConnectionLimits) -> RequestReader<S> {
+        RequestReader { stream, limits, state: Rc::new(RequestState::default()), timeout: None }
+    }
+
+    pub(crate) fn state(&self) -> Rc<RequestState> {
+        self.state.clone()
+    }
+
+    fn reject(&self, reason: RejectReason) -> io::Error {
+        self.state.rejected.set(Some(reason));
+        io::Error::new(io::ErrorKind::TimedOut, reason.to_string())
+    }
+}
+
+impl<S: Stream> Read for RequestReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = self.state.started.get();
+        let timeout = match (started, self.limits.read_timeout) {
+            (Some(started), Some(limit)) => match limit.checked_sub(started.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Err(self.reject(RejectReason::ReadTimeout)),
+            },
+            (Some(_), None) => None,
+            (None, _) => self.limits.idle_timeout,
+        };
+        if timeout != self.timeout {
+            self.stream.set_read_timeout(timeout)?;
+            self.timeout = timeout;
+        }
+
+        let len = match self.stream.read(buf) {
+            Err(e) if is_timeout(&e) => {
+                let reason = if started.is_some() { RejectReason::ReadTimeout } else { RejectReason::IdleTimeout };
+                return Err(self.reject(reason));
+            }
+            result => result?,
+        };
+        if len > 0 && started.is_none() {
+            self.state.started.set(Some(Instant::now()));
+        }
+        self.state.len.set(self.state.len.get() + len);
+        if self.state.len.get() > self.limits.max_request_size {
+            return Err(self.reject(RejectReason::RequestTooLarge));
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use slog_scope::error;
+
+use crate::{EngineStats, KvsEngine, KvsError, Result};
+
+const SNAPSHOT_FILE: &str = "memory_snapshot";
+
+struct MemoryData {
+    map: RwLock<BTreeMap<String, String>>,
+    snapshot_path: Option<PathBuf>,
+}
+
+/// An engine that keeps every key in memory.
+///
+/// Created with `new` it never touches the disk. Created with `open` it loads the
+/// snapshot found in the directory and writes a new one when the last handle is dropped.
+#[derive(Clone)]
+pub struct MemoryKvsEngine {
+    data: Arc<MemoryData>,
+}
+
+impl MemoryKvsEngine {
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::with_map(BTreeMap::new(), None)
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<MemoryKvsEngine> {
+        let path = path.into();
+        if !path.exists() {
+            create_dir_all(&path)?;
+        }
+        let snapshot_path = path.join(SNAPSHOT_FILE);
+        let mut map = BTreeMap::new();
+        if snapshot_path.exists() {
+            let file = File::open(&snapshot_path)?;
+            if file.metadata()?.len() != 0 {
+                map = serde_json::from_reader(BufReader::new(file))?;
+            }
+        }
+        Ok(MemoryKvsEngine::with_map(map, Some(snapshot_path)))
+    }
+
+    fn with_map(map: BTreeMap<String, String>, snapshot_path: Option<PathBuf>) -> MemoryKvsEngine {
+        let data = Arc::new(MemoryData {
+            map: RwLock::new(map),
+            snapshot_path,
+        });
+        MemoryKvsEngine { data }
+    }
+
+    /// Write all keys to the snapshot file. Does nothing for an engine created with `new`.
+    pub fn snapshot(&self) -> Result<()> {
+        self.data.snapshot()
+    }
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> Self {
+        MemoryKvsEngine::new()
+    }
+}
+
+impl MemoryData {
+    fn snapshot(&self) -> Result<()> {
+        let snapshot_path = match &self.snapshot_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp_path = snapshot_path.with_extension("new");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &*self.map.read().unwrap())?;
+        writer.flush()?;
+        rename(&tmp_path, snapshot_path)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryData {
+    fn drop(&mut self) {
+        if let Err(e) = self.snapshot() {
+            error!("Can't write memory snapshot: {}", e);
+        }
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.data.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.data.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.data.map.write().unwrap().remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound(key)),
+        }
+    }
+
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        let map = self.data.map.read().unwrap();
+        let keys = map.range(prefix.clone()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        Ok(keys)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.snapshot()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats { keys: Some(self.data.map.read().unwrap().len() as u64), ..EngineStats::default() })
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use slog_scope::error;
+use tiny_http::{Header, Method, Response as HttpResponse, Server};
+
+use crate::{DynKvsEngine, ErrorCode, RejectReason, Rejections, Result, SharedEngine};
+use crate::shutdown::{SHUTDOWN_POLL_INTERVAL, ShutdownHandle};
+
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Counters of one or more servers, rendered in the Prometheus text format by `render`.
+///
+/// Share one with `KvsServer::set_metrics` to count all servers of a process together.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<&'static str, Latencies>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+    connections: AtomicI64,
+    rejections: Arc<Rejections>,
+    // Jobs waiting for a thread, by pool, dropped with their pool
+    queues: Mutex<Vec<(&'static str, Weak<AtomicUsize>)>>,
+}
+
+#[derive(Default)]
+struct Latencies {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Connections closed for breaking the limits of the servers.
+    pub fn rejections(&self) -> Arc<Rejections> {
+        self.rejections.clone()
+    }
+
+    /// Count a request named `request` served in `elapsed`, and its error if it failed.
+    pub(crate) fn record(&self, request: &'static str, elapsed: Duration, error: Option<ErrorCode>) {
+        let elapsed = elapsed.as_secs_f64();
+        {
+            let mut requests = self.requests.lock().unwrap();
+            let latencies = requests.entry(request).or_default();
+            if let Some(pos) = LATENCY_BUCKETS.iter().position(|bound| elapsed <= *bound) {
+                latencies.buckets[pos] += 1;
+            }
+            latencies.count += 1;
+            latencies.sum += elapsed;
+        }
+        if let Some(code) = error {
+            self.record_error(code);
+        }
+    }
+
+    /// Count an error answered without serving the request.
+    pub(crate) fn record_error(&self, code: ErrorCode) {
+        *self.errors.lock().unwrap().entry(format!("{:?}", code)).or_default() += 1;
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn watch_queue(&self, pool: &'static str, waiting: Weak<AtomicUsize>) {
+        self.queues.lock().unwrap().push((pool, waiting));
+    }
+
+    /// The metrics in the Prometheus text format, with the statistics of `engine`.
+    pub fn render(&self, engine: Option<&dyn DynKvsEngine>) -> Result<String> {
+        let mut out = String::new();
+
+        let requests = self.requests.lock().unwrap();
+        header(&mut out, "kvs_requests_total", "counter", "Requests served, by type.");
+        for (request, latencies) in requests.iter() {
+            let _ = writeln!(out, "kvs_requests_total{{type=\"{}\"}} {}", request, latencies.count);
+        }
+        header(&mut out, "kvs_request_duration_seconds", "histogram", "Time to serve a request, by type.");
+        for (request, latencies) in requests.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latencies.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "kvs_request_duration_seconds_bucket{{type=\"{}\",le=\"{}\"}} {}", request, bound, cumulative);
+            }
+            let _ = writeln!(out, "kvs_request_duration_seconds_bucket{{type=\"{}\",le=\"+Inf\"}} {}", request, latencies.count);
+            let _ = writeln!(out, "kvs_request_duration_seconds_sum{{type=\"{}\"}} {}", request, latencies.sum);
+            let _ = writeln!(out, "kvs_request_duration_seconds_count{{type=\"{}\"}} {}", request, latencies.count);
+        }
+        drop(requests);
+
+        header(&mut out, "kvs_errors_total", "counter", "Error responses, by error code.");
+        for (code, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "kvs_errors_total{{code=\"{}\"}} {}", code, count);
+        }
+
+        header(&mut out, "kvs_connections_active", "gauge", "Connections being served.");
+        let _ = writeln!(out, "kvs_connections_active {}", self.connections.load(Ordering::Relaxed));
+
+        header(&mut out, "kvs_rejected_connections_total", "counter", "Connections closed for breaking a limit, by reason.");
+        for reason in RejectReason::ALL {
+            let _ = writeln!(out, "kvs_rejected_connections_total{{reason=\"{}\"}} {}", reason.name(), self.rejections.count(reason));
+        }
+
+        header(&mut out, "kvs_queued_jobs", "gauge", "Jobs waiting for a thread, by pool.");
+        let mut queued: BTreeMap<&str, usize> = BTreeMap::new();
+        self.queues.lock().unwrap().retain(|(pool, waiting)| match waiting.upgrade() {
+            Some(waiting) => {
+                *queued.entry(pool).or_default() += waiting.load(Ordering::Relaxed);
+                true
+            }
+            None => false,
+        });
+        for (pool, waiting) in queued {
+            let _ = writeln!(out, "kvs_queued_jobs{{pool=\"{}\"}} {}", pool, waiting);
+        }
+
+        if let Some(engine) = engine {
+            let stats = engine.stats()?;
+            if let Some(keys) = stats.keys {
+                header(&mut out, "kvs_engine_keys", "gauge", "Keys in the engine.");
+                let _ = writeln!(out, "kvs_engine_keys {}", keys);
+            }
+            if let Some(size) = stats.size_bytes {
+                header(&mut out, "kvs_engine_size_bytes", "gauge", "Bytes the engine keeps on disk.");
+                let _ = writeln!(out, "kvs_engine_size_bytes {}", size);
+            }
+            header(&mut out, "kvs_compactions_total", "counter", "Compactions run by the engine.");
+            let _ = writeln!(out, "kvs_compactions_total {}", stats.compactions);
+            header(&mut out, "kvs_compaction_duration_seconds_total", "counter", "Time spent compacting.");
+            let _ = writeln!(out, "kvs_compaction_duration_seconds_total {}", stats.compaction_time.as_secs_f64());
+            header(&mut out, "kvs_compaction_reclaimed_bytes_total", "counter", "Bytes freed by compactions.");
+            let _ = writeln!(out, "kvs_compaction_reclaimed_bytes_total {}", stats.reclaimed_bytes);
+        }
+        Ok(out)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Serves `GET /metrics` over HTTP for Prometheus to scrape.
+///
+/// It has no authentication, so it should listen on a local address.
+pub struct MetricsEndpoint {
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    engine: Option<SharedEngine>,
+    shutdown: ShutdownHandle,
+}
+
+impl MetricsEndpoint {
+    pub fn new(addr: SocketAddr, metrics: Arc<Metrics>) -> Self {
+        MetricsEndpoint { addr, metrics, engine: None, shutdown: ShutdownHandle::new() }
+    }
+
+    /// Stops `handle_connection` from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Also report the statistics of `engine`.
+    pub fn set_engine(&mut self, engine: SharedEngine) {
+        self.engine = Some(engine);
+    }
+
+    pub fn handle_connection(&mut self) -> Result<()> {
+        let server = Server::http(self.addr).map_err(io::Error::other)?;
+        while !self.shutdown.is_shutdown() {
+            let request = match server.recv_timeout(SHUTDOWN_POLL_INTERVAL)? {
+                Some(request) => request,
+                None => continue,
+            };
+            let response = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") => match self.metrics.render(self.engine.as_deref()) {
+                    Ok(body) => text_response(200, body),
+                    Err(e) => text_response(500, e.to_string()),
+                },
+                _ => text_response(404, "not found".to_string()),
+            };
+            if let Err(e) = request.respond(response) {
+                error!("Failed to send response: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn text_response(status: u16, body: String) -> HttpResponse<Cursor<Vec<u8>>> {
+    // The header is valid ASCII
+    let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+    HttpResponse::from_data(body).with_header(content_type).with_status_code(status)
+}
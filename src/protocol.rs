@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Request, Response, Result};
+
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// A request tagged with an id, the server answers it with a `ResponseEnvelope` of the same id.
+///
+/// Requests in envelopes may be answered out of order, so a client can send many of them
+/// without waiting. A bare `Request` is still accepted and answered in order with a bare `Response`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestEnvelope {
+    pub version: u32,
+    pub id: u64,
+    pub request: Request,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseEnvelope {
+    pub id: u64,
+    pub response: Response,
+}
+
+impl RequestEnvelope {
+    pub fn new(id: u64, request: Request) -> RequestEnvelope {
+        RequestEnvelope { version: PROTOCOL_VERSION, id, request }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum IncomingRequest {
+    Envelope(RequestEnvelope),
+    Bare(Request),
+}
+
+impl IncomingRequest {
+    pub(crate) fn request(&self) -> &Request {
+        match self {
+            IncomingRequest::Envelope(envelope) => &envelope.request,
+            IncomingRequest::Bare(request) => request,
+        }
+    }
+
+    // Wrap `response` the same way as the request.
+    pub(crate) fn reply(&self, response: Response) -> OutgoingResponse {
+        match self {
+            IncomingRequest::Envelope(envelope) => OutgoingResponse::Envelope(ResponseEnvelope { id: envelope.id, response }),
+            IncomingRequest::Bare(_) => OutgoingResponse::Bare(response),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum OutgoingResponse {
+    Envelope(ResponseEnvelope),
+    Bare(Response),
+}
+
+impl OutgoingResponse {
+    pub(crate) fn response(&self) -> &Response {
+        match self {
+            OutgoingResponse::Envelope(envelope) => &envelope.response,
+            OutgoingResponse::Bare(response) => response,
+        }
+    }
+}
+
+// A server answers envelopes with a bare response only when it closes the connection.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum IncomingResponse {
+    Envelope(ResponseEnvelope),
+    Bare(Response),
+}
+
+impl IncomingResponse {
+    pub(crate) fn into_envelope(self) -> Result<ResponseEnvelope> {
+        match self {
+            IncomingResponse::Envelope(envelope) => Ok(envelope),
+            IncomingResponse::Bare(response) => Err(refusal(response)),
+        }
+    }
+}
+
+/// The error of a server that answered the binary handshake with `reply` instead.
+pub(crate) fn refused_handshake(reply: &[u8]) -> KvsError {
+    match serde_json::from_slice::<Response>(reply) {
+        Ok(response) => refusal(response),
+        Err(_) => KvsError::ProtocolError("server refused the binary protocol".to_string()),
+    }
+}
+
+fn refusal(response: Response) -> KvsError {
+    match response {
+        Response::Error { code, message } => KvsError::ServerError { code, message },
+        response => KvsError::ProtocolError(format!("unexpected response {:?}", response)),
+    }
+}
+
+// Puts responses back into the order of their requests.
+pub(crate) struct PendingResponses {
+    index: HashMap<u64, usize>,
+    responses: Vec<Option<Response>>,
+    remaining: usize,
+}
+
+impl PendingResponses {
+    pub(crate) fn new(envelopes: &[RequestEnvelope]) -> PendingResponses {
+        let index = envelopes.iter()
+            .enumerate()
+            .map(|(pos, envelope)| (envelope.id, pos))
+            .collect();
+        PendingResponses {
+            index,
+            responses: envelopes.iter().map(|_| None).collect(),
+            remaining: envelopes.len(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, envelope: ResponseEnvelope) -> Result<()> {
+        match self.index.remove(&envelope.id) {
+            Some(pos) => {
+                self.responses[pos] = Some(envelope.response);
+                self.remaining -= 1;
+                Ok(())
+            }
+            None => Err(KvsError::ProtocolError(format!("unexpected response id {}", envelope.id))),
+        }
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    pub(crate) fn into_responses(self) -> Vec<Response> {
+        self.responses.into_iter().flatten().collect()
+    }
+}
+
+/// Sent by a client as the first byte of a connection to switch it to `Protocol::Binary`,
+/// the server sends it back to accept.
+pub const BINARY_HANDSHAKE: u8 = 0xB1;
+
+// Frames larger than this are treated as a broken stream.
+pub(crate) const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Encoding of the messages on a connection.
+///
+/// `Json` is a stream of JSON values and is easy to type by hand. `Binary` sends every
+/// message as a 4 byte big endian length followed by the bincode encoded envelope.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    Json,
+    Binary,
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Protocol> {
+        match s {
+            "json" => Ok(Protocol::Json),
+            "binary" => Ok(Protocol::Binary),
+            _ => Err(KvsError::ProtocolError(format!("unknown protocol `{}`", s))),
+        }
+    }
+}
+
+/// What a server speaks on its connections.
+///
+/// `Kvs` accepts both `Protocol::Json` and `Protocol::Binary` clients, `Resp` speaks
+/// the Redis protocol so Redis clients can be used.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServerProtocol {
+    Kvs,
+    Resp,
+}
+
+impl FromStr for ServerProtocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<ServerProtocol> {
+        match s {
+            "kvs" => Ok(ServerProtocol::Kvs),
+            "resp" => Ok(ServerProtocol::Resp),
+            _ => Err(KvsError::ProtocolError(format!("unknown protocol `{}`", s))),
+        }
+    }
+}
+
+/// Where a server listens and a client connects to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ServerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(addr: SocketAddr) -> ServerAddr {
+        ServerAddr::Tcp(addr)
+    }
+}
+
+impl From<PathBuf> for ServerAddr {
+    fn from(path: PathBuf) -> ServerAddr {
+        ServerAddr::Unix(path)
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "{}", addr),
+            ServerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// A blocking connection of either kind, split into a reader and a writer with `try_clone`.
+pub(crate) trait Stream: Read + Write + Send + Sync + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    // Makes blocked and later reads of every clone return the end of the stream.
+    fn shutdown_read(&self) -> io::Result<()>;
+    // Tells the client nothing more is coming.
+    fn shutdown_write(&self) -> io::Result<()>;
+    // `None` for clients on a Unix socket.
+    fn peer_ip(&self) -> Option<IpAddr>;
+    // Timeouts are shared by all clones.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+pub(crate) fn encode_message<T: Serialize>(protocol: Protocol, message: &T) -> Result<Vec<u8>> {
+    match protocol {
+        Protocol::Json => Ok(serde_json::to_vec(message)?),
+        Protocol::Binary => {
+            let payload = bincode::serialize(message)?;
+            let mut frame = Vec::with_capacity(4 + payload.len());
+            frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&payload);
+            Ok(frame)
+        }
+    }
+}
+
+/// Find the first complete frame in `buf`, returns the frame length and its payload.
+pub(crate) fn split_frame(buf: &[u8], max_len: usize) -> Result<Option<(usize, &[u8])>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let payload_len = frame_len([buf[0], buf[1], buf[2], buf[3]], max_len)?;
+    if buf.len() < 4 + payload_len {
+        return Ok(None);
+    }
+    Ok(Some((4 + payload_len, &buf[4..4 + payload_len])))
+}
+
+/// Read one frame, returns `None` if the stream is closed before it starts.
+///
+/// Fails with `FrameTooLarge` before reading the payload if it is longer than `max_len`.
+pub(crate) fn read_frame<R: Read>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut payload = vec![0; frame_len(len_buf, max_len)?];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+// No frame is longer than `MAX_FRAME_LEN`, whatever `max_len` is.
+fn frame_len(len_buf: [u8; 4], max_len: usize) -> Result<usize> {
+    let payload_len = u32::from_be_bytes(len_buf) as usize;
+    let max_len = max_len.min(MAX_FRAME_LEN);
+    if payload_len > max_len {
+        return Err(KvsError::FrameTooLarge(payload_len, max_len));
+    }
+    Ok(payload_len)
+}
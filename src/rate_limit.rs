@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::{KvsError, Result, User};
+
+// The least recently used bucket is dropped to keep at most this many
+const MAX_BUCKETS: usize = 10_000;
+
+/// Requests a client may send, as a token bucket refilled with `rate` tokens per second
+/// and holding at most `burst` of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// `rate` requests per second with bursts of as many.
+    pub fn per_second(rate: u32) -> RateLimit {
+        RateLimit { rate: rate as f64, burst: rate.max(1) }
+    }
+}
+
+/// Applies a `RateLimit` to every client of the servers sharing it on its own.
+///
+/// Logged in clients are told apart by their user, others by their IP address.
+/// All clients on a Unix socket share one bucket.
+pub struct RateLimiter {
+    limit: Mutex<Option<RateLimit>>,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<String, Bucket>,
+    // Clients in the order of their requests, a client is only at the position of its
+    // last one, the entries of its earlier requests are stale
+    order: VecDeque<(u64, String)>,
+    requests: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // The request that used it last
+    used: u64,
+}
+
+impl RateLimiter {
+    /// Clients are not limited while `limit` is `None`.
+    pub fn new(limit: Option<RateLimit>) -> RateLimiter {
+        RateLimiter { limit: Mutex::new(limit), buckets: Mutex::new(Buckets::default()) }
+    }
+
+    pub fn limit(&self) -> Option<RateLimit> {
+        *self.limit.lock().unwrap()
+    }
+
+    /// Change the limit of running servers, clients start again with a full bucket.
+    pub fn set_limit(&self, limit: Option<RateLimit>) {
+        let mut current = self.limit.lock().unwrap();
+        if *current != limit {
+            *current = limit;
+            *self.buckets.lock().unwrap() = Buckets::default();
+        }
+    }
+
+    /// Takes a token for a request, fails with `KvsError::RateLimited` if there is none.
+    pub(crate) fn check(&self, user: Option<&User>, peer: Option<IpAddr>) -> Result<()> {
+        let limit = match self.limit() {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let client = match (user, peer) {
+            (Some(user), _) => format!("user:{}", user.name()),
+            (None, Some(ip)) => ip.to_string(),
+            (None, None) => "local".to_string(),
+        };
+        let now = Instant::now();
+        let burst = limit.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get(client, now, burst);
+        bucket.tokens = bucket.refilled(now, limit.rate, burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(KvsError::RateLimited);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+impl Buckets {
+    // The bucket of `client`, full if it is new. Takes amortized constant time.
+    fn get(&mut self, client: String, now: Instant, burst: f64) -> &mut Bucket {
+        if !self.by_client.contains_key(&client) && self.by_client.len() >= MAX_BUCKETS {
+            self.evict();
+        }
+        self.requests += 1;
+        let used = self.requests;
+        self.by_client.entry(client.clone()).or_insert(Bucket { tokens: burst, updated: now, used }).used = used;
+        self.order.push_back((used, client.clone()));
+        if self.order.len() > 2 * MAX_BUCKETS {
+            let by_client = &self.by_client;
+            self.order.retain(|(used, client)| by_client.get(client).is_some_and(|bucket| bucket.used == *used));
+        }
+        self.by_client.get_mut(&client).unwrap()
+    }
+
+    // Drops the least recently used bucket.
+    fn evict(&mut self) {
+        while let Some((used, client)) = self.order.pop_front() {
+            if self.by_client.get(&client).is_some_and(|bucket| bucket.used == used) {
+                self.by_client.remove(&client);
+                return;
+            }
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
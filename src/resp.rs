@@ -0,0 +1,526 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Access, Acl, DEFAULT_DATABASE, DynKvsEngine, ErrorCode, KvsError, Result, User};
+use crate::protocol::MAX_FRAME_LEN;
+
+const MAX_ARGS: usize = 1024 * 1024;
+const SCAN_DEFAULT_COUNT: usize = 10;
+
+/// A RESP2 value, only used for replies.
+#[derive(Debug)]
+pub(crate) enum RespValue {
+    Simple(String),
+    // With the code counted in `Metrics`
+    Error(ErrorCode, String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<RespValue>),
+}
+
+impl RespValue {
+    fn ok() -> RespValue {
+        RespValue::Simple("OK".to_string())
+    }
+
+    // A command the server can't make sense of
+    fn error(message: impl Into<String>) -> RespValue {
+        RespValue::Error(ErrorCode::Protocol, format!("ERR {}", message.into()))
+    }
+
+    pub(crate) fn failed(e: &KvsError) -> RespValue {
+        RespValue::Error(e.code(), format!("ERR {}", e))
+    }
+
+    fn denied(message: impl Into<String>) -> RespValue {
+        RespValue::Error(ErrorCode::PermissionDenied, message.into())
+    }
+
+    /// The code of the error a reply carries.
+    pub(crate) fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            RespValue::Error(code, _) => Some(*code),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RespValue::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            RespValue::Error(_, s) => out.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+            RespValue::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            RespValue::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::Bulk(Some(s)) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::Array(values) => {
+                out.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// Find the first complete command in `buf`, returns its length and arguments.
+///
+/// Commands are arrays of bulk strings, as sent by Redis clients, or inline
+/// commands separated by spaces, as typed into telnet.
+pub(crate) fn parse_command(buf: &[u8]) -> Result<Option<(usize, Vec<Vec<u8>>)>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        return match read_line(buf, 0) {
+            Some((line, end)) => {
+                let args = line.split(|b| b.is_ascii_whitespace())
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| arg.to_vec())
+                    .collect();
+                Ok(Some((end, args)))
+            }
+            None => Ok(None),
+        };
+    }
+
+    let (line, mut pos) = match read_line(buf, 1) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let arg_num = parse_len(line, MAX_ARGS)?;
+    // The count is the client's word, so room is only made for the arguments that came
+    let mut args = Vec::new();
+    for _ in 0..arg_num {
+        if pos >= buf.len() {
+            return Ok(None);
+        }
+        if buf[pos] != b'$' {
+            return Err(KvsError::ProtocolError(format!("expected '$', got '{}'", buf[pos] as char)));
+        }
+        let (line, start) = match read_line(buf, pos + 1) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let end = start + parse_len(line, MAX_FRAME_LEN)?;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(KvsError::ProtocolError("bulk string is not terminated by CRLF".to_string()));
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((pos, args)))
+}
+
+// Returns the line starting at `start` without its line ending, and the position after it.
+fn read_line(buf: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let len = buf[start..].iter().position(|b| *b == b'\n')?;
+    let line = &buf[start..start + len];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Some((line, start + len + 1))
+}
+
+fn parse_len(line: &[u8], max: usize) -> Result<usize> {
+    match std::str::from_utf8(line).ok().and_then(|s| s.parse::<usize>().ok()) {
+        Some(len) if len <= max => Ok(len),
+        _ => Err(KvsError::ProtocolError(format!("invalid length `{}`", String::from_utf8_lossy(line)))),
+    }
+}
+
+/// Runs Redis commands on an engine.
+///
+/// Expiry times set with `SET ... EX` only live in the server memory, so they are lost
+/// on restart. Commands run one at a time, which keeps `NX`, `XX` and `INCR` atomic.
+pub(crate) struct RespHandler {
+    // By database
+    expiries: Mutex<HashMap<String, HashMap<String, Instant>>>,
+    started: Instant,
+    acl: Option<Arc<Acl>>,
+    databases: BTreeSet<String>,
+}
+
+/// The state of one connection.
+#[derive(Default)]
+pub(crate) struct RespSession {
+    user: Option<Arc<User>>,
+    db: Option<String>,
+}
+
+impl RespSession {
+    pub(crate) fn user(&self) -> Option<&User> {
+        self.user.as_deref()
+    }
+
+    /// The database selected with `SELECT`, commands must run on its engine.
+    pub(crate) fn db(&self) -> &str {
+        self.db.as_deref().unwrap_or(DEFAULT_DATABASE)
+    }
+}
+
+impl RespHandler {
+    /// With `acl`, connections must send `AUTH` before any other command.
+    /// `databases` are the names `SELECT` accepts.
+    pub(crate) fn new(acl: Option<Arc<Acl>>, databases: BTreeSet<String>) -> RespHandler {
+        RespHandler { expiries: Mutex::new(HashMap::new()), started: Instant::now(), acl, databases }
+    }
+
+    pub(crate) fn exec(&self, engine: &dyn DynKvsEngine, session: &mut RespSession, args: Vec<Vec<u8>>) -> RespValue {
+        let mut args = match args.into_iter().map(String::from_utf8).collect::<std::result::Result<Vec<_>, _>>() {
+            Ok(args) => args,
+            Err(_) => return RespValue::error("arguments must be valid UTF-8"),
+        };
+        if args.is_empty() {
+            return RespValue::error("empty command");
+        }
+        let name = args.remove(0).to_uppercase();
+        if name == "AUTH" {
+            return self.auth(session, args);
+        }
+        // A user removed, given another password or denied its database by a reload must log in again
+        if let (Some(acl), Some(user)) = (&self.acl, &session.user) {
+            session.user = acl.refresh(user).filter(|user| user.may_select(session.db()));
+        }
+        if self.acl.is_some() && session.user.is_none() {
+            return RespValue::denied("NOAUTH Authentication required.");
+        }
+        let user = session.user.as_deref();
+        if let Some(user) = user {
+            if let Err(KvsError::PermissionDenied(message)) = check_command(user, &name, &args) {
+                return RespValue::denied(format!("NOPERM {}", message));
+            }
+        }
+        if name == "SELECT" {
+            return self.select(session, args);
+        }
+        let mut expiries = self.expiries.lock().unwrap();
+        let expiries = expiries.entry(session.db().to_string()).or_default();
+        let mut ctx = Context { engine, expiries, user: session.user.as_deref() };
+        let result = match name.as_str() {
+            "PING" => match args.len() {
+                0 => Ok(RespValue::Simple("PONG".to_string())),
+                1 => Ok(RespValue::Bulk(args.pop())),
+                _ => Ok(wrong_args(&name)),
+            },
+            "GET" if args.len() == 1 => ctx.get(&args[0]).map(RespValue::Bulk),
+            "SET" if args.len() >= 2 => ctx.set(args),
+            "DEL" if !args.is_empty() => ctx.del(&args),
+            "EXISTS" if !args.is_empty() => ctx.exists(&args),
+            "MGET" if !args.is_empty() => args.iter()
+                .map(|key| ctx.get(key).map(RespValue::Bulk))
+                .collect::<Result<Vec<_>>>()
+                .map(RespValue::Array),
+            "MSET" if !args.is_empty() && args.len() % 2 == 0 => ctx.mset(args),
+            "SCAN" if !args.is_empty() => ctx.scan(&args),
+            "INCR" if args.len() == 1 => ctx.incr(&args[0]),
+            "INFO" if args.len() <= 1 => self.info(&mut ctx),
+            "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "MSET" | "SCAN" | "INCR" | "INFO" => Ok(wrong_args(&name)),
+            _ => Ok(RespValue::Error(ErrorCode::Unsupported, format!("ERR unknown command '{}'", name))),
+        };
+        result.unwrap_or_else(|e| RespValue::failed(&e))
+    }
+
+    // AUTH [username] password
+    fn auth(&self, session: &mut RespSession, mut args: Vec<String>) -> RespValue {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return RespValue::error("AUTH called without any password configured"),
+        };
+        let password = match args.pop() {
+            Some(password) if args.len() <= 1 => password,
+            _ => return wrong_args("AUTH"),
+        };
+        match acl.authenticate(args.first().map(String::as_str), &password) {
+            Ok(user) => {
+                session.user = Some(user);
+                RespValue::ok()
+            }
+            Err(_) => RespValue::denied("WRONGPASS invalid username-password pair"),
+        }
+    }
+
+    // SELECT db, by name
+    fn select(&self, session: &mut RespSession, mut args: Vec<String>) -> RespValue {
+        match args.pop() {
+            Some(db) if args.is_empty() && self.databases.contains(&db) => {
+                session.db = Some(db);
+                RespValue::ok()
+            }
+            Some(db) if args.is_empty() => RespValue::failed(&KvsError::UnknownDatabase(db)),
+            _ => wrong_args("SELECT"),
+        }
+    }
+
+    fn info(&self, ctx: &mut Context) -> Result<RespValue> {
+        let keys = ctx.live_keys("")?.len();
+        let info = format!(
+            "# Server\r\nkvs_version:{}\r\nuptime_in_seconds:{}\r\n\r\n# Keyspace\r\ndb0:keys={},expires={}\r\n",
+            env!("CARGO_PKG_VERSION"),
+            self.started.elapsed().as_secs(),
+            keys,
+            ctx.expiries.len(),
+        );
+        Ok(RespValue::Bulk(Some(info)))
+    }
+}
+
+/// The lowercase name of a command for `Metrics`, "unknown" if it is not supported.
+pub(crate) fn command_name(args: &[Vec<u8>]) -> &'static str {
+    const COMMANDS: [&str; 12] = ["auth", "ping", "get", "set", "del", "exists", "mget", "mset", "scan", "incr", "info", "select"];
+    let name = args.first().map(|name| String::from_utf8_lossy(name).to_lowercase()).unwrap_or_default();
+    COMMANDS.into_iter().find(|command| *command == name).unwrap_or("unknown")
+}
+
+// SCAN only returns the keys the user may read, so it needs no check here.
+fn check_command(user: &User, name: &str, args: &[String]) -> Result<()> {
+    let (access, keys): (Access, Vec<&String>) = match name {
+        "GET" | "MGET" | "EXISTS" => (Access::Read, args.iter().collect()),
+        "SET" | "INCR" => (Access::Write, args.iter().take(1).collect()),
+        "DEL" => (Access::Write, args.iter().collect()),
+        "MSET" => (Access::Write, args.iter().step_by(2).collect()),
+        "INFO" => return user.check(Access::Admin, ""),
+        "SELECT" => return args.iter().try_for_each(|db| user.check_database(db)),
+        _ => return Ok(()),
+    };
+    keys.into_iter().try_for_each(|key| user.check(access, key))
+}
+
+fn wrong_args(name: &str) -> RespValue {
+    RespValue::error(format!("wrong number of arguments for '{}' command", name.to_lowercase()))
+}
+
+struct Context<'a> {
+    engine: &'a dyn DynKvsEngine,
+    expiries: &'a mut HashMap<String, Instant>,
+    user: Option<&'a User>,
+}
+
+impl Context<'_> {
+    // Removes the key if it has expired.
+    fn expire(&mut self, key: &str) -> Result<()> {
+        if let Some(deadline) = self.expiries.get(key) {
+            if *deadline <= Instant::now() {
+                self.expiries.remove(key);
+                self.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether the key existed.
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        self.expiries.remove(key);
+        match self.engine.remove(key) {
+            Ok(_) => Ok(true),
+            Err(KvsError::KeyNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.expire(key)?;
+        self.engine.get(key)
+    }
+
+    fn live_keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = self.engine.scan_prefix(prefix)?;
+        let now = Instant::now();
+        let expired: Vec<String> = keys.iter()
+            .filter(|key| self.expiries.get(*key).is_some_and(|deadline| *deadline <= now))
+            .cloned()
+            .collect();
+        for key in expired.iter() {
+            self.remove(key)?;
+        }
+        keys.retain(|key| !expired.contains(key));
+        Ok(keys)
+    }
+
+    // SET key value [EX seconds | PX milliseconds] [NX | XX]
+    fn set(&mut self, args: Vec<String>) -> Result<RespValue> {
+        let mut args = args.into_iter();
+        let key = args.next().unwrap();
+        let value = args.next().unwrap();
+        let mut ttl = None;
+        let mut only_if_missing = false;
+        let mut only_if_exists = false;
+        while let Some(option) = args.next() {
+            match option.to_uppercase().as_str() {
+                "NX" => only_if_missing = true,
+                "XX" => only_if_exists = true,
+                unit @ ("EX" | "PX") if ttl.is_none() => {
+                    let amount = match args.next().and_then(|amount| amount.parse::<u64>().ok()) {
+                        Some(amount) if amount > 0 => amount,
+                        _ => return Ok(RespValue::error("invalid expire time in 'set' command")),
+                    };
+                    ttl = Some(if unit == "EX" {
+                        Duration::from_secs(amount)
+                    } else {
+                        Duration::from_millis(amount)
+                    });
+                }
+                _ => return Ok(RespValue::error("syntax error")),
+            }
+        }
+        if only_if_missing && only_if_exists {
+            return Ok(RespValue::error("syntax error"));
+        }
+
+        let exists = self.get(&key)?.is_some();
+        if (only_if_missing && exists) || (only_if_exists && !exists) {
+            return Ok(RespValue::Bulk(None));
+        }
+        self.engine.set(&key, &value)?;
+        match ttl {
+            Some(ttl) => self.expiries.insert(key, Instant::now() + ttl),
+            None => self.expiries.remove(&key),
+        };
+        Ok(RespValue::ok())
+    }
+
+    fn del(&mut self, keys: &[String]) -> Result<RespValue> {
+        let mut removed = 0;
+        for key in keys {
+            self.expire(key)?;
+            if self.remove(key)? {
+                removed += 1;
+            }
+        }
+        Ok(RespValue::Integer(removed))
+    }
+
+    fn exists(&mut self, keys: &[String]) -> Result<RespValue> {
+        let mut found = 0;
+        for key in keys {
+            if self.get(key)?.is_some() {
+                found += 1;
+            }
+        }
+        Ok(RespValue::Integer(found))
+    }
+
+    fn mset(&mut self, args: Vec<String>) -> Result<RespValue> {
+        for pair in args.chunks(2) {
+            self.engine.set(&pair[0], &pair[1])?;
+            self.expiries.remove(&pair[0]);
+        }
+        Ok(RespValue::ok())
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]
+    //
+    // The cursor is the position in the sorted key list, so keys added during
+    // the iteration may be missed or returned twice, as Redis allows.
+    fn scan(&mut self, args: &[String]) -> Result<RespValue> {
+        let cursor = match args[0].parse::<usize>() {
+            Ok(cursor) => cursor,
+            Err(_) => return Ok(RespValue::error("invalid cursor")),
+        };
+        let mut pattern = "*";
+        let mut count = SCAN_DEFAULT_COUNT;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match (option.to_uppercase().as_str(), options.next()) {
+                ("MATCH", Some(val)) => pattern = val,
+                ("COUNT", Some(val)) => match val.parse::<usize>() {
+                    Ok(val) if val > 0 => count = val,
+                    _ => return Ok(RespValue::error("value is not an integer or out of range")),
+                },
+                _ => return Ok(RespValue::error("syntax error")),
+            }
+        }
+
+        // Only the keys before the first wildcard are fetched from the engine
+        let prefix_len = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+        let mut keys = self.live_keys(&pattern[..prefix_len])?;
+        if let Some(user) = self.user {
+            keys.retain(|key| user.allows(Access::Read, key));
+        }
+        let end = keys.len().min(cursor.saturating_add(count));
+        let next_cursor = if end == keys.len() { 0 } else { end };
+        let page = keys.get(cursor..end).unwrap_or_default()
+            .iter()
+            .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+            .map(|key| RespValue::Bulk(Some(key.clone())))
+            .collect();
+        Ok(RespValue::Array(vec![RespValue::Bulk(Some(next_cursor.to_string())), RespValue::Array(page)]))
+    }
+
+    fn incr(&mut self, key: &str) -> Result<RespValue> {
+        let value = match self.get(key)? {
+            Some(value) => match value.parse::<i64>() {
+                Ok(value) => value,
+                Err(_) => return Ok(RespValue::error("value is not an integer or out of range")),
+            },
+            None => 0,
+        };
+        match value.checked_add(1) {
+            Some(value) => {
+                self.engine.set(key, &value.to_string())?;
+                Ok(RespValue::Integer(value))
+            }
+            None => Ok(RespValue::error("increment or decrement would overflow")),
+        }
+    }
+}
+
+// Redis glob style matching with `*`, `?`, `[...]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // After a mismatch the last `*` swallows one more byte, earlier ones never need to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(next) = match_byte(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+// Where the pattern goes on if its element at `p` matches `c`.
+fn match_byte(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    let rest = &pattern[(p + 1).min(pattern.len())..];
+    let matched = match pattern.get(p)? {
+        b'?' => true,
+        b'[' => {
+            let close = match rest.iter().position(|b| *b == b']') {
+                Some(close) => close,
+                None => return (c == b'[').then_some(p + 1),
+            };
+            let (negate, class) = match rest[..close].split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, &rest[..close]),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            return (matched != negate).then_some(p + close + 2);
+        }
+        b'\\' if !rest.is_empty() => return (rest[0] == c).then_some(p + 2),
+        b => *b == c,
+    };
+    matched.then_some(p + 1)
+}
@@ -1,106 +1,800 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::process::exit;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::net::{IpAddr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use serde_json::{Deserializer, to_writer};
-use slog_scope::{debug, error};
+use serde::Serialize;
+use serde_json::Deserializer;
+use slog_scope::{debug, error, info, warn};
 
-use crate::{KvsEngine, Request, Response, Result};
-use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{Acl, BINARY_HANDSHAKE, ConnectionLimits, DynKvsEngine, ErrorCode, KvsError, Metrics, Protocol, PROTOCOL_VERSION, RateLimit, RateLimiter, RejectReason, ReloadHandler, Rejections, Request, RequestEnvelope, Response, ResponseEnvelope, Result, ServerAddr, ServerProtocol, ServerStats, SharedEngine, SlowLog, SpanExporter, User};
+use crate::limits::{is_timeout, linger, LINGER_THREADS, MAX_LINGERING, RequestReader};
+use crate::protocol::{encode_message, IncomingRequest, OutgoingResponse, read_frame, Stream};
+use crate::resp::{command_name, parse_command, RespHandler, RespSession, RespValue};
+use crate::shutdown::{DEFAULT_SHUTDOWN_TIMEOUT, InFlight, ShutdownHandle};
+use crate::slow_log::SlowQuery;
+use crate::thread_pool::{AnyThreadPool, BoundedThreadPool, PoolKind, SharedQueueThreadPool, ThreadPool};
+use crate::trace::ConnectionTrace;
+use crate::tls::{ServerConfig, TlsStream};
 
-pub struct KvsServer<E: KvsEngine> {
-    addr: SocketAddr,
-    engine: E,
+// Only the owner and the group of the server can connect to its Unix socket by default
+pub const DEFAULT_UNIX_MODE: u32 = 0o660;
+
+// The database of the engine a server is created with, connections start on it
+pub const DEFAULT_DATABASE: &str = "default";
+
+pub struct KvsServer {
+    addr: ServerAddr,
+    engine: SharedEngine,
+    databases: BTreeMap<String, SharedEngine>,
+    protocol: ServerProtocol,
+    unix_mode: u32,
+    tls: Option<Arc<ServerConfig>>,
+    acl: Option<Arc<Acl>>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    pool_kind: PoolKind,
+    threads: usize,
+    limits: ConnectionLimits,
+    metrics: Arc<Metrics>,
+    slow_log: Arc<SlowLog>,
+    spans: Option<Arc<SpanExporter>>,
+    reload: Option<ReloadHandler>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    max_queued: Option<usize>,
+}
+
+// Shared by all connections of a server
+struct ServerState {
+    engine: SharedEngine,
+    // By name, with the default database
+    databases: BTreeMap<String, SharedEngine>,
+    protocol: ServerProtocol,
+    // Requests in envelopes run on their own pool, connection threads block on reading
+    request_pool: BoundedThreadPool<AnyThreadPool>,
+    info: Arc<ServerInfo>,
+    resp_handler: RespHandler,
+    acl: Option<Arc<Acl>>,
+    in_flight: InFlight,
+    limits: ConnectionLimits,
+    metrics: Arc<Metrics>,
+    rejections: Arc<Rejections>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+/// What a server reports about itself in `Request::Stats` and `Request::SlowLog`, besides its engine,
+/// and how it answers `Request::Reload`.
+pub(crate) struct ServerInfo {
+    started: Instant,
+    threads: usize,
+    slow_log: Arc<SlowLog>,
+    spans: Option<Arc<SpanExporter>>,
+    reload: Option<ReloadHandler>,
 }
 
-impl<E: KvsEngine> KvsServer<E> {
-    pub fn new(addr: SocketAddr, engine: E) -> Self {
-        KvsServer { addr, engine }
+impl ServerInfo {
+    pub(crate) fn new(threads: usize, slow_log: Arc<SlowLog>, spans: Option<Arc<SpanExporter>>, reload: Option<ReloadHandler>) -> ServerInfo {
+        ServerInfo { started: Instant::now(), threads, slow_log, spans, reload }
     }
 
-    pub fn handle_connection(&mut self) {
-        let listener = match TcpListener::bind(self.addr) {
-            Ok(listener) => listener,
-            Err(e) => {
-                error!("Can't bind address. addr: {}, error: {}", self.addr, e);
-                exit(-1);
-            }
-        };
-        let thread_pool = SharedQueueThreadPool::new(num_cpus::get()).unwrap();
+    pub(crate) fn slow_log(&self) -> &SlowLog {
+        &self.slow_log
+    }
 
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
+    pub(crate) fn spans(&self) -> Option<&SpanExporter> {
+        self.spans.as_deref()
+    }
+
+    fn stats(&self, engine: &dyn DynKvsEngine) -> Result<ServerStats> {
+        Ok(ServerStats {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            engine: engine.name().to_string(),
+            uptime: self.started.elapsed(),
+            threads: self.threads,
+            engine_stats: engine.stats()?,
+        })
+    }
+}
+
+impl ServerState {
+    fn check_rate(&self, user: Option<&User>, peer: Option<IpAddr>) -> Result<()> {
+        match &self.rate_limiter {
+            Some(limiter) => limiter.check(user, peer),
+            None => Ok(()),
+        }
+    }
+}
+
+impl KvsServer {
+    pub fn new(addr: impl Into<ServerAddr>, engine: SharedEngine) -> Self {
+        KvsServer::with_protocol(addr, engine, ServerProtocol::Kvs)
+    }
+
+    pub fn with_protocol(addr: impl Into<ServerAddr>, engine: SharedEngine, protocol: ServerProtocol) -> Self {
+        KvsServer {
+            addr: addr.into(),
+            engine,
+            databases: BTreeMap::new(),
+            protocol,
+            unix_mode: DEFAULT_UNIX_MODE,
+            tls: None,
+            acl: None,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            pool_kind: PoolKind::SharedQueue,
+            threads: num_cpus::get(),
+            limits: ConnectionLimits::default(),
+            metrics: Arc::new(Metrics::new()),
+            slow_log: Arc::new(SlowLog::default()),
+            spans: None,
+            reload: None,
+            rate_limiter: None,
+            max_queued: None,
+        }
+    }
+
+    /// Serve `engine` as the database `name` as well, connections switch to it with
+    /// `Request::Select`. The engine of `new` is the database `DEFAULT_DATABASE`.
+    ///
+    /// With an ACL, users may only select the databases it lists for them, see `Acl`.
+    pub fn add_database(&mut self, name: &str, engine: SharedEngine) {
+        assert_ne!(name, DEFAULT_DATABASE);
+        self.databases.insert(name.to_string(), engine);
+    }
+
+    /// Set the permission bits of the Unix socket file, which decide who may connect.
+    pub fn set_unix_mode(&mut self, mode: u32) {
+        self.unix_mode = mode;
+    }
+
+    /// Only accept TLS connections, see `tls::server_config`.
+    pub fn set_tls(&mut self, config: Arc<ServerConfig>) {
+        self.tls = Some(config);
+    }
+
+    /// Require clients to log in with `Request::Auth` and check every request against `acl`.
+    pub fn set_acl(&mut self, acl: Arc<Acl>) {
+        self.acl = Some(acl);
+    }
+
+    /// Serve connections on a pool of `kind` with `threads` threads, and run requests
+    /// in envelopes on a second one. A shared queue pool with a thread per CPU by default.
+    ///
+    /// A connection keeps its thread while it is open, so `threads` also limits the
+    /// number of clients served at the same time.
+    pub fn set_thread_pool(&mut self, kind: PoolKind, threads: usize) {
+        assert!(threads > 0);
+        self.pool_kind = kind;
+        self.threads = threads;
+    }
+
+    /// Close connections breaking `limits`, a client waiting for a request pins a thread otherwise.
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.limits = limits;
+    }
+
+    /// How many connections were closed for breaking the limits.
+    pub fn rejections(&self) -> Arc<Rejections> {
+        self.metrics.rejections()
+    }
+
+    /// Count requests, errors and connections in `metrics` instead of counters of its own.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Keep slow requests in `slow_log` instead of a log of its own, which keeps the last
+    /// `SlowLog::DEFAULT_CAPACITY` requests over `SlowLog::DEFAULT_THRESHOLD`.
+    pub fn set_slow_log(&mut self, slow_log: Arc<SlowLog>) {
+        self.slow_log = slow_log;
+    }
+
+    pub fn slow_log(&self) -> Arc<SlowLog> {
+        self.slow_log.clone()
+    }
+
+    /// Export a span for every request answered, see `SpanExporter`.
+    pub fn set_span_exporter(&mut self, spans: Arc<SpanExporter>) {
+        self.spans = Some(spans);
+    }
+
+    /// Limit the requests of every client, see `RateLimit`. Requests over the limit are
+    /// answered with `ErrorCode::RateLimited`.
+    pub fn set_rate_limit(&mut self, limit: RateLimit) {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(Some(limit))));
+    }
+
+    /// Limit the requests of every client with a limiter shared with other servers, its
+    /// limit can be changed while they run.
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Answer `Request::Reload` with `reload`, which applies the settings that can change
+    /// while the server runs. Servers without one answer it with an error.
+    pub fn set_reload_handler(&mut self, reload: ReloadHandler) {
+        self.reload = Some(reload);
+    }
+
+    /// Answer with `ErrorCode::Busy` once `jobs` connections or requests are waiting for
+    /// a thread of their pool, instead of queueing them. Unbounded by default.
+    pub fn set_max_queued(&mut self, jobs: usize) {
+        self.max_queued = Some(jobs);
+    }
+
+    /// A handle to stop the server, `handle_connection` returns once it is stopped.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// How long a stopping server waits for the requests it has already read, 10 seconds by default.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Serve until the server is shut down, see `shutdown_handle`.
+    pub fn handle_connection(&mut self) -> Result<()> {
+        match &self.addr {
+            ServerAddr::Tcp(addr) => TcpListener::bind(addr).map_err(KvsError::from).and_then(|listener| {
+                self.shutdown.set_listening(listener.local_addr()?.into());
+                self.serve(listener.incoming())
+            }),
+            ServerAddr::Unix(path) => bind_unix(path, self.unix_mode).map_err(KvsError::from).and_then(|listener| {
+                self.shutdown.set_listening(path.clone().into());
+                let result = self.serve(listener.incoming());
+                let _ = fs::remove_file(path);
+                result
+            }),
+        }
+    }
+
+    fn serve<S: Stream>(&self, incoming: impl Iterator<Item=io::Result<S>>) -> Result<()> {
+        let max_queued = self.max_queued.unwrap_or(usize::MAX);
+        let thread_pool = BoundedThreadPool::new(AnyThreadPool::with_kind(self.pool_kind, self.threads)?, max_queued);
+        let request_pool = BoundedThreadPool::new(AnyThreadPool::with_kind(self.pool_kind, self.threads)?, max_queued);
+        self.metrics.watch_queue("connections", thread_pool.queue());
+        self.metrics.watch_queue("requests", request_pool.queue());
+        let databases = with_default(&self.databases, &self.engine);
+        let state = Arc::new(ServerState {
+            engine: self.engine.clone(),
+            protocol: self.protocol,
+            request_pool,
+            info: Arc::new(ServerInfo::new(self.threads, self.slow_log.clone(), self.spans.clone(), self.reload.clone())),
+            resp_handler: RespHandler::new(self.acl.clone(), databases.keys().cloned().collect()),
+            databases,
+            acl: self.acl.clone(),
+            in_flight: InFlight::default(),
+            limits: self.limits.clone(),
+            metrics: self.metrics.clone(),
+            rejections: self.metrics.rejections(),
+            rate_limiter: self.rate_limiter.clone(),
+        });
+        // Open connections, so their reads can be stopped on shutdown
+        let connections: Arc<Mutex<HashMap<u64, S>>> = Arc::new(Mutex::new(HashMap::new()));
+        // Checked before every accept too, nothing wakes it if `shutdown` ran before `set_listening`
+        let mut incoming = incoming;
+        let incoming = iter::from_fn(|| if self.shutdown.is_shutdown() { None } else { incoming.next() });
+        // The connection pool may be busy, and the accept loop must not wait on rejected clients
+        let lingering = BoundedThreadPool::new(SharedQueueThreadPool::new(LINGER_THREADS)?, MAX_LINGERING);
+
+        for (id, stream) in (0..).zip(incoming) {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let tls = self.tls.clone();
+            let stream = stream.and_then(|stream| Ok((stream.try_clone()?, stream)));
             match stream {
-                Ok(stream) => {
+                Ok((_, stream)) if self.limits.max_connections.is_some_and(|max| connections.lock().unwrap().len() >= max) => {
+                    state.rejections.add(RejectReason::TooManyConnections);
+                    self.reject(&lingering, stream, RejectReason::TooManyConnections)?;
+                }
+                Ok((watched, stream)) => {
                     debug!("Receive connection.");
-                    thread_pool.spawn(move || {
-                        handle_stream(&engine, &stream);
+                    connections.lock().unwrap().insert(id, watched);
+                    let job_state = state.clone();
+                    let job_connections = connections.clone();
+                    let working = state.in_flight.start();
+                    let spawned = thread_pool.try_spawn(move || {
+                        job_state.metrics.connection_opened();
+                        let conn = ConnectionTrace::new(stream.peer_ip(), &slog_scope::logger());
+                        // Everything logged about the connection carries its id
+                        slog_scope::scope(conn.logger(), || {
+                            let result = match tls {
+                                // The handshake must be done within the idle timeout, as the first request must start
+                                Some(config) => TlsStream::accept(config, &stream, job_state.limits.idle_timeout)
+                                    .inspect_err(|e| if let KvsError::Rejected(reason) = e { job_state.rejections.add(*reason) })
+                                    .and_then(|stream| serve_stream(&job_state, stream, &conn)),
+                                None => serve_stream(&job_state, stream, &conn),
+                            };
+                            if let Err(e) = result {
+                                error!("Connection error: {}", e);
+                            }
+                        });
+                        job_connections.lock().unwrap().remove(&id);
+                        job_state.metrics.connection_closed();
+                        drop(working);
                     });
+                    // A full queue of connections is answered like too many connections
+                    if !spawned {
+                        state.rejections.add(RejectReason::ServerBusy);
+                        if let Some(stream) = connections.lock().unwrap().remove(&id) {
+                            self.reject(&lingering, stream, RejectReason::ServerBusy)?;
+                        }
+                    }
                 }
                 Err(e) => error!("Connection error: {}", e),
             }
         }
 
-        // close the socket server
-        drop(listener);
+        info!("Shutting down {}", self.addr);
+        for stream in connections.lock().unwrap().values() {
+            let _ = stream.shutdown_read();
+        }
+        if !state.in_flight.wait(self.shutdown_timeout) {
+            warn!("Requests still running after {:?}, stop waiting", self.shutdown_timeout);
+        }
+        let mut result = Ok(());
+        for (name, engine) in state.databases.iter() {
+            if let Err(e) = engine.flush() {
+                error!("Can't flush engine of database {}: {}", name, e);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    // Replies to a connection rejected by the accept loop, and closes it.
+    fn reject<S: Stream>(&self, lingering: &BoundedThreadPool<SharedQueueThreadPool>, mut stream: S, reason: RejectReason) -> Result<()> {
+        // A TLS client can't read anything before the handshake
+        if self.tls.is_none() {
+            let _ = stream.write_all(&rejection_reply(self.protocol, reason)?);
+            lingering.try_spawn(move || linger(stream));
+        }
+        Ok(())
     }
 }
 
-fn handle_stream<E: KvsEngine>(engine: &E, stream: &TcpStream) {
-    let reader = BufReader::new(stream);
-    let request_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-    for command in request_reader {
-        match command {
-            Ok(command) => {
-                match send_resp(engine, stream, &command) {
-                    Ok(_) => debug!("Send response."),
-                    Err(e) => error!("Failed to send response: {}", e)
+fn serve_stream<S: Stream>(state: &Arc<ServerState>, stream: S, conn: &ConnectionTrace) -> Result<()> {
+    match state.protocol {
+        ServerProtocol::Kvs => handle_stream(state, stream, conn),
+        ServerProtocol::Resp => handle_resp_stream(state, stream, conn),
+    }
+}
+
+// A socket file left by a stopped server is replaced, anything else at `path` is an error.
+pub(crate) fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() && std::os::unix::net::UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// The reply to a client whose connection is closed for `reason`.
+///
+/// `ServerProtocol::Kvs` clients get a bare JSON `Response`, binary clients read it in
+/// place of the handshake.
+pub(crate) fn rejection_reply(protocol: ServerProtocol, reason: RejectReason) -> Result<Vec<u8>> {
+    let error = KvsError::Rejected(reason);
+    match protocol {
+        ServerProtocol::Kvs => encode_message(Protocol::Json, &Response::from(error)),
+        ServerProtocol::Resp => {
+            let mut reply = Vec::new();
+            RespValue::failed(&error).encode(&mut reply);
+            Ok(reply)
+        }
+    }
+}
+
+// Counts a response that was not written in time.
+fn check_write(rejections: &Rejections, result: io::Result<()>) -> Result<()> {
+    if let Err(e) = &result {
+        if is_timeout(e) {
+            rejections.add(RejectReason::WriteTimeout);
+        }
+    }
+    Ok(result?)
+}
+
+struct ResponseWriter {
+    writer: Mutex<Box<dyn Write + Send>>,
+    protocol: Protocol,
+    rejections: Arc<Rejections>,
+    // Stops reading the connection once a response is not written in time
+    close: Box<dyn Fn() + Send + Sync>,
+    timed_out: AtomicBool,
+}
+
+impl ResponseWriter {
+    fn send<T: Serialize>(&self, response: &T) -> Result<()> {
+        self.write(&encode_message(self.protocol, response)?)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Requests already read are still answered, but the client stopped reading
+        if self.timed_out.load(Ordering::Relaxed) {
+            return Err(KvsError::Rejected(RejectReason::WriteTimeout));
+        }
+        let result = writer.write_all(buf).and_then(|_| writer.flush());
+        if result.as_ref().is_err_and(is_timeout) {
+            self.timed_out.store(true, Ordering::Relaxed);
+            (self.close)();
+        }
+        check_write(&self.rejections, result)
+    }
+}
+
+pub(crate) fn with_default(databases: &BTreeMap<String, SharedEngine>, engine: &SharedEngine) -> BTreeMap<String, SharedEngine> {
+    let mut databases = databases.clone();
+    databases.insert(DEFAULT_DATABASE.to_string(), engine.clone());
+    databases
+}
+
+// The engine a `Request::Select` switches to, a user is not told about databases it may not use.
+pub(crate) fn select_database(databases: &BTreeMap<String, SharedEngine>, user: Option<&User>, db: &str) -> Result<SharedEngine> {
+    if let Some(user) = user {
+        user.check_database(db)?;
+    }
+    databases.get(db).cloned().ok_or_else(|| KvsError::UnknownDatabase(db.to_string()))
+}
+
+fn handle_stream<S: Stream>(state: &Arc<ServerState>, stream: S, conn: &ConnectionTrace) -> Result<()> {
+    stream.set_write_timeout(state.limits.write_timeout)?;
+    let mut writer = stream.try_clone()?;
+    let watched = Arc::new(stream.try_clone()?);
+    let reader = RequestReader::new(stream, state.limits.clone());
+    let read_state = reader.state();
+    let mut reader = BufReader::new(reader);
+    let protocol = match reader.fill_buf() {
+        Ok([]) => return Ok(()),
+        Ok([BINARY_HANDSHAKE, ..]) => {
+            reader.consume(1);
+            read_state.request_done();
+            Protocol::Binary
+        }
+        Ok(_) => Protocol::Json,
+        Err(e) => return match read_state.rejected() {
+            Some(reason) => {
+                state.rejections.add(reason);
+                let _ = writer.write_all(&rejection_reply(state.protocol, reason)?);
+                linger(writer);
+                Ok(())
+            }
+            None => Err(e.into()),
+        },
+    };
+    let writer = Arc::new(ResponseWriter {
+        writer: Mutex::new(Box::new(BufWriter::new(writer))),
+        protocol,
+        rejections: state.rejections.clone(),
+        close: Box::new({
+            let watched = watched.clone();
+            move || {
+                let _ = watched.shutdown_read();
+            }
+        }),
+        timed_out: AtomicBool::new(false),
+    });
+    let mut user = None;
+    let mut engine = (DEFAULT_DATABASE.to_string(), state.engine.clone());
+
+    match protocol {
+        Protocol::Json => {
+            let request_reader = Deserializer::from_reader(reader).into_iter::<IncomingRequest>();
+            for command in request_reader {
+                match command {
+                    Ok(command) => {
+                        read_state.request_done();
+                        if !handle_request(state, command, &mut user, &mut engine, conn, &writer)? {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) => {
+                        // There is no way to find the start of the next request
+                        match read_state.rejected() {
+                            Some(reason) => {
+                                state.rejections.add(reason);
+                                let _ = writer.write(&rejection_reply(state.protocol, reason)?);
+                                if let Ok(stream) = watched.try_clone() {
+                                    linger(stream);
+                                }
+                            }
+                            None => error!("Can't parse request: {}", e),
+                        }
+                        return Ok(());
+                    }
                 };
             }
+        }
+        Protocol::Binary => {
+            writer.write(&[BINARY_HANDSHAKE])?;
+            loop {
+                // Frames only carry envelopes, so the client is not told why it is closed
+                let frame = match read_frame(&mut reader, state.limits.max_request_size) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(KvsError::FrameTooLarge(_, _)) => {
+                        state.rejections.add(RejectReason::RequestTooLarge);
+                        return Ok(());
+                    }
+                    Err(e) => return match read_state.rejected() {
+                        Some(reason) => {
+                            state.rejections.add(reason);
+                            Ok(())
+                        }
+                        None => Err(e),
+                    },
+                };
+                read_state.request_done();
+                // The next frame starts right after this one, so a broken frame is skipped
+                match bincode::deserialize::<RequestEnvelope>(&frame) {
+                    Ok(envelope) => {
+                        if !handle_request(state, IncomingRequest::Envelope(envelope), &mut user, &mut engine, conn, &writer)? {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) => error!("Can't parse request: {}", e),
+                };
+            }
+        }
+    }
+    Ok(())
+}
+
+// Returns false if the connection should be closed.
+//
+// `engine` is the database the connection selected, with its name.
+fn handle_request(state: &Arc<ServerState>, request: IncomingRequest, user: &mut Option<Arc<User>>, engine: &mut (String, SharedEngine), conn: &ConnectionTrace, writer: &Arc<ResponseWriter>) -> Result<bool> {
+    let peer = conn.peer();
+    if let (Some(acl), Some(logged_in)) = (&state.acl, user.as_deref()) {
+        match refresh_user(acl, logged_in, &engine.0) {
+            Ok(current) => *user = Some(current),
             Err(e) => {
-                error!("Can't parse request: {}", e);
+                writer.send(&request.reply(e.into()))?;
+                return Ok(false);
             }
-        };
+        }
+    }
+    if let Err(e) = state.check_rate(user.as_deref(), peer) {
+        state.metrics.record_error(e.code());
+        writer.send(&request.reply(e.into()))?;
+        return Ok(true);
+    }
+    match (&state.acl, &user) {
+        (Some(acl), None) => {
+            let trace = conn.request(request.request().name());
+            let started = Instant::now();
+            let (logged_in, response) = login(acl, &request);
+            state.metrics.record(request.request().name(), started.elapsed(), response.response().error_code());
+            state.info.slow_log.record(SlowQuery::request(request.request()), started.elapsed(), peer);
+            trace.finish(response.response().error_code(), state.info.spans());
+            writer.send(&response)?;
+            *user = logged_in;
+            Ok(user.is_some())
+        }
+        _ => {
+            // Later requests of the connection must not run before the switch
+            if let Request::Select { db } = request.request() {
+                let trace = conn.request(request.request().name());
+                let started = Instant::now();
+                let response = match select_database(&state.databases, user.as_deref(), db) {
+                    Ok(selected) => {
+                        *engine = (db.clone(), selected);
+                        Response::Ok
+                    }
+                    Err(e) => e.into(),
+                };
+                state.metrics.record(request.request().name(), started.elapsed(), response.error_code());
+                trace.finish(response.error_code(), state.info.spans());
+                writer.send(&request.reply(response))?;
+            } else {
+                dispatch_request(state, request, engine.1.clone(), user.clone(), conn, writer);
+            }
+            Ok(true)
+        }
     }
 }
 
-fn send_resp<E: KvsEngine>(engine: &E, stream: &TcpStream, request: &Request) -> Result<()> {
-    let mut writer = BufWriter::new(stream);
+/// The user a connection logged in as, with the grants of the current ACL.
+///
+/// Fails if a reload removed the user, changed its password or took away the database
+/// `db` it uses, the connection is then closed after the reply.
+pub(crate) fn refresh_user(acl: &Acl, user: &User, db: &str) -> Result<Arc<User>> {
+    let current = acl.refresh(user).ok_or_else(|| KvsError::PermissionDenied(format!("user `{}` must log in again", user.name())))?;
+    current.check_database(db)?;
+    Ok(current)
+}
 
-    match request {
-        Request::Set { key, value } => {
-            match engine.set(key.to_string(), value.to_string()) {
-                Ok(_) => {
-                    to_writer(&mut writer, &Response::new(true, "".to_string()))?;
-                }
-                Err(e) => {
-                    to_writer(&mut writer, &Response::new(false, e.to_string()))?;
+/// The first request must be `Request::Auth` when the server has an ACL, the connection
+/// is closed after the reply if it fails.
+pub(crate) fn login(acl: &Acl, request: &IncomingRequest) -> (Option<Arc<User>>, OutgoingResponse) {
+    let result = match request.request() {
+        Request::Auth { user, password } => acl.authenticate(user.as_deref(), password),
+        _ => Err(KvsError::PermissionDenied("authentication required".to_string())),
+    };
+    match result {
+        Ok(user) => (Some(user), request.reply(Response::Ok)),
+        Err(e) => (None, request.reply(e.into())),
+    }
+}
+
+// Redis clients wait for each reply unless they pipeline, so commands run in order
+fn handle_resp_stream<S: Stream>(state: &ServerState, stream: S, conn: &ConnectionTrace) -> Result<()> {
+    stream.set_write_timeout(state.limits.write_timeout)?;
+    let peer = conn.peer();
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut reader = RequestReader::new(stream, state.limits.clone());
+    let read_state = reader.state();
+    let mut session = RespSession::default();
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let read_len = match reader.read(&mut chunk) {
+            Ok(read_len) => read_len,
+            Err(e) => return match read_state.rejected() {
+                Some(reason) => {
+                    state.rejections.add(reason);
+                    let _ = writer.write_all(&rejection_reply(state.protocol, reason)?).and_then(|_| writer.flush());
+                    if let Ok(stream) = writer.into_inner() {
+                        linger(stream);
+                    }
+                    Ok(())
                 }
-            };
+                None => Err(e.into()),
+            },
+        };
+        if read_len == 0 {
+            return Ok(());
         }
-        Request::Get { key } => {
-            match engine.get(key.to_string()) {
-                Ok(val) => {
-                    let value = val.unwrap_or("".to_string());
-                    to_writer(&mut writer, &Response::new(true, value))?;
+        buf.extend_from_slice(&chunk[..read_len]);
+
+        let mut replies = Vec::new();
+        let mut parsed_len = 0;
+        loop {
+            match parse_command(&buf[parsed_len..]) {
+                Ok(Some((command_len, args))) => {
+                    parsed_len += command_len;
+                    if !args.is_empty() {
+                        let reply = match state.check_rate(session.user(), peer) {
+                            Ok(_) => {
+                                let started = Instant::now();
+                                let name = command_name(&args);
+                                let trace = conn.request(name);
+                                let query = SlowQuery::command(&args);
+                                let engine = &state.databases[session.db()];
+                                let reply = state.resp_handler.exec(engine.as_ref(), &mut session, args);
+                                state.metrics.record(name, started.elapsed(), reply.error_code());
+                                state.info.slow_log.record(query.reply(&reply), started.elapsed(), peer);
+                                trace.finish(reply.error_code(), state.info.spans());
+                                reply
+                            }
+                            Err(e) => {
+                                state.metrics.record_error(e.code());
+                                RespValue::failed(&e)
+                            }
+                        };
+                        reply.encode(&mut replies);
+                    }
                 }
+                Ok(None) => break,
                 Err(e) => {
-                    to_writer(&mut writer, &Response::new(false, e.to_string()))?;
+                    // There is no way to find the start of the next command
+                    RespValue::failed(&e).encode(&mut replies);
+                    return check_write(&state.rejections, writer.write_all(&replies).and_then(|_| writer.flush()));
                 }
-            };
+            }
         }
-        Request::Rm { key } => {
-            match engine.remove(key.to_string()) {
-                Ok(_) => {
-                    to_writer(&mut writer, &Response::new(true, "".to_string()))?;
-                }
-                Err(e) => {
-                    to_writer(&mut writer, &Response::new(false, e.to_string()))?;
+        buf.drain(..parsed_len);
+        if buf.is_empty() {
+            read_state.request_done();
+        }
+        check_write(&state.rejections, writer.write_all(&replies).and_then(|_| writer.flush()))?;
+        debug!("Send response.");
+    }
+}
+
+fn dispatch_request(state: &Arc<ServerState>, request: IncomingRequest, engine: SharedEngine, user: Option<Arc<User>>, conn: &ConnectionTrace, writer: &Arc<ResponseWriter>) {
+    let peer = conn.peer();
+    match request {
+        IncomingRequest::Bare(command) => {
+            let trace = conn.request(command.name());
+            slog_scope::scope(trace.logger(), || {
+                let started = Instant::now();
+                let response = exec_request(engine.as_ref(), &state.info, user.as_deref(), &command);
+                state.metrics.record(command.name(), started.elapsed(), response.error_code());
+                state.info.slow_log.record(SlowQuery::request(&command).response(&response), started.elapsed(), peer);
+                trace.finish(response.error_code(), state.info.spans());
+                match writer.send(&response) {
+                    Ok(_) => debug!("Send response."),
+                    Err(e) => error!("Failed to send response: {}", e)
+                };
+            });
+        }
+        IncomingRequest::Envelope(envelope) => {
+            let id = envelope.id;
+            let info = state.info.clone();
+            let metrics = state.metrics.clone();
+            let job_writer = writer.clone();
+            let working = state.in_flight.start();
+            let trace = conn.request(envelope.request.name());
+            let spawned = state.request_pool.try_spawn(move || {
+                slog_scope::scope(trace.logger(), || {
+                    let started = Instant::now();
+                    let response = exec_envelope(engine.as_ref(), &info, user.as_deref(), &envelope);
+                    metrics.record(envelope.request.name(), started.elapsed(), response.response.error_code());
+                    info.slow_log.record(SlowQuery::request(&envelope.request).response(&response.response), started.elapsed(), peer);
+                    trace.finish(response.response.error_code(), info.spans());
+                    match job_writer.send(&response) {
+                        Ok(_) => debug!("Send response."),
+                        Err(e) => error!("Failed to send response: {}", e)
+                    };
+                });
+                drop(working);
+            });
+            if !spawned {
+                state.metrics.record_error(ErrorCode::Busy);
+                let response = ResponseEnvelope { id, response: KvsError::ServerBusy.into() };
+                if let Err(e) = writer.send(&response) {
+                    error!("Failed to send response: {}", e);
                 }
-            };
+            }
         }
     }
-    writer.flush()?;
+}
 
-    Ok(())
+pub(crate) fn exec_envelope(engine: &dyn DynKvsEngine, info: &ServerInfo, user: Option<&User>, envelope: &RequestEnvelope) -> ResponseEnvelope {
+    let response = if envelope.version > PROTOCOL_VERSION {
+        KvsError::ProtocolError(format!("unsupported protocol version {}", envelope.version)).into()
+    } else {
+        exec_request(engine, info, user, &envelope.request)
+    };
+    ResponseEnvelope { id: envelope.id, response }
+}
+
+/// `user` is the logged in user if the server has an ACL, the request is checked
+/// against its grants before the engine is touched.
+pub(crate) fn exec_request(engine: &dyn DynKvsEngine, info: &ServerInfo, user: Option<&User>, request: &Request) -> Response {
+    if let (Some(user), Some((access, key))) = (user, request.access()) {
+        if let Err(e) = user.check(access, key) {
+            return e.into();
+        }
+    }
+    let result = match request {
+        Request::Set { key, value } => engine.set(key, value).map(|_| Response::Ok),
+        Request::Get { key } => engine.get(key).map(|val| match val {
+            Some(val) => Response::Value(val),
+            None => Response::NotFound,
+        }),
+        Request::Rm { key } => engine.remove(key).map(|_| Response::Ok),
+        Request::Stats => info.stats(engine).map(Response::Stats),
+        Request::Compact => engine.compact().map(|reclaimed_bytes| Response::Compacted { reclaimed_bytes }),
+        Request::SlowLog => Ok(Response::SlowLog(info.slow_log.entries())),
+        Request::Reload => match &info.reload {
+            Some(reload) => reload().map(Response::Reloaded),
+            None => Err(KvsError::InvalidConfig("the server has no settings to reload".to_string())),
+        },
+        // Connections answer it themselves, see `KvsServer::add_database`
+        Request::Select { .. } => Err(KvsError::ProtocolError("a database can only be selected on a connection".to_string())),
+        // Logging in only happens as the first request, and always succeeds without an ACL
+        Request::Auth { .. } => match user {
+            Some(_) => Err(KvsError::ProtocolError("already authenticated".to_string())),
+            None => Ok(Response::Ok),
+        },
+    };
+    result.unwrap_or_else(Response::from)
 }
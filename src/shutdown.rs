@@ -0,0 +1,108 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::ServerAddr;
+
+// How long a stopping server waits for the requests it has already read
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+// How often the HTTP listeners, whose wait for a request can't be woken, check for a shutdown
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stops a server from another thread or task.
+///
+/// The server stops accepting connections and reading requests, answers the requests
+/// it has already read, flushes the engine and returns from `handle_connection`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    requested: watch::Sender<bool>,
+    // A blocking accept only returns for a connection, so one is made to the listener
+    listening: Mutex<Option<ServerAddr>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            inner: Arc::new(ShutdownState { requested: watch::Sender::new(false), listening: Mutex::new(None) }),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.inner.requested.send_replace(true);
+        let listening = self.inner.listening.lock().unwrap().take();
+        let _ = match listening {
+            Some(ServerAddr::Tcp(addr)) => TcpStream::connect(reachable(addr)).map(drop),
+            Some(ServerAddr::Unix(path)) => UnixStream::connect(path).map(drop),
+            None => Ok(()),
+        };
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.inner.requested.borrow()
+    }
+
+    // Called by blocking servers before accepting, they must check `is_shutdown` after it.
+    pub(crate) fn set_listening(&self, addr: ServerAddr) {
+        *self.inner.listening.lock().unwrap() = Some(addr);
+    }
+
+    pub(crate) async fn wait(&self) {
+        let mut requested = self.inner.requested.subscribe();
+        // The sender lives as long as `self`
+        let _ = requested.wait_for(|requested| *requested).await;
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        ShutdownHandle::new()
+    }
+}
+
+// A listener on all interfaces is reached through the loopback interface.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+        _ => addr,
+    }
+}
+
+/// Counts the connections and requests being served, so a stopping server can wait for them.
+#[derive(Clone, Default)]
+pub(crate) struct InFlight {
+    inner: Arc<(Mutex<usize>, Condvar)>,
+}
+
+pub(crate) struct InFlightGuard {
+    in_flight: InFlight,
+}
+
+impl InFlight {
+    pub(crate) fn start(&self) -> InFlightGuard {
+        *self.inner.0.lock().unwrap() += 1;
+        InFlightGuard { in_flight: self.clone() }
+    }
+
+    // Returns false if some work is still running after `timeout`.
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let (count, drained) = &*self.inner;
+        let (count, _) = drained.wait_timeout_while(count.lock().unwrap(), timeout, |count| *count > 0).unwrap();
+        *count == 0
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let (count, drained) = &*self.in_flight.inner;
+        *count.lock().unwrap() -= 1;
+        drained.notify_all();
+    }
+}
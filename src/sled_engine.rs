@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use sled::IVec;
 
-use crate::{KvsEngine, Result};
+use crate::{EngineStats, KvsEngine, Result};
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
@@ -11,7 +11,7 @@ pub struct SledKvsEngine {
 
 impl SledKvsEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let db = sled::open(&path.into())?;
+        let db = sled::open(path.into())?;
         Ok(SledKvsEngine { db })
     }
 }
@@ -36,4 +36,29 @@ impl KvsEngine for SledKvsEngine {
         self.db.flush()?;
         Ok(())
     }
+
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for key in self.db.scan_prefix(prefix.as_bytes()).keys() {
+            keys.push(String::from_utf8_lossy(key?.as_ref()).to_string());
+        }
+        Ok(keys)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: Some(self.db.len() as u64),
+            size_bytes: Some(self.db.size_on_disk()?),
+            ..EngineStats::default()
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
 }
\ No newline at end of file
@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Request, Response};
+use crate::resp::{command_name, RespValue};
+
+/// A request that took longer than the threshold of a `SlowLog`.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct SlowLogEntry {
+    pub op: String,
+    pub key: Option<String>,
+    /// Size of the value sent with a set, or returned by a get.
+    pub value_size: Option<u64>,
+    pub duration: Duration,
+    /// `None` for clients on a Unix socket.
+    pub client: Option<IpAddr>,
+    pub time: SystemTime,
+}
+
+/// The last requests of one or more servers that took longer than a threshold.
+///
+/// Share one with `KvsServer::set_slow_log` to keep the requests of all servers of a
+/// process together. Requests are timed from when they are read until their response
+/// is ready.
+pub struct SlowLog {
+    // In nanoseconds
+    threshold: AtomicU64,
+    capacity: AtomicUsize,
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+}
+
+impl SlowLog {
+    pub const DEFAULT_THRESHOLD: Duration = Duration::from_millis(10);
+    pub const DEFAULT_CAPACITY: usize = 128;
+
+    /// Keep the last `capacity` requests that took `threshold` or longer.
+    pub fn new(threshold: Duration, capacity: usize) -> SlowLog {
+        SlowLog {
+            threshold: AtomicU64::new(threshold.as_nanos() as u64),
+            capacity: AtomicUsize::new(capacity),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn threshold(&self) -> Duration {
+        Duration::from_nanos(self.threshold.load(Ordering::Relaxed))
+    }
+
+    /// Only keep requests that take `threshold` or longer from now on.
+    pub fn set_threshold(&self, threshold: Duration) {
+        self.threshold.store(threshold.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Keep the last `capacity` requests, the oldest ones above it are dropped now.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut entries = self.entries.lock().unwrap();
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let excess = entries.len().saturating_sub(capacity);
+        entries.drain(..excess);
+    }
+
+    /// The requests kept, the latest first.
+    pub fn entries(&self) -> Vec<SlowLogEntry> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    pub(crate) fn record(&self, query: SlowQuery, duration: Duration, client: Option<IpAddr>) {
+        if duration < self.threshold() || self.capacity() == 0 {
+            return;
+        }
+        let entry = SlowLogEntry {
+            op: query.op.to_string(),
+            key: query.key,
+            value_size: query.value_size,
+            duration,
+            client,
+            time: SystemTime::now(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        // The capacity may have changed since it was checked
+        while entries.len() >= self.capacity() && !entries.is_empty() {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog::new(SlowLog::DEFAULT_THRESHOLD, SlowLog::DEFAULT_CAPACITY)
+    }
+}
+
+/// What the slow log keeps of a request, taken before the request is run.
+pub(crate) struct SlowQuery {
+    op: &'static str,
+    key: Option<String>,
+    value_size: Option<u64>,
+}
+
+impl SlowQuery {
+    pub(crate) fn new(op: &'static str, key: Option<String>, value_size: Option<u64>) -> SlowQuery {
+        SlowQuery { op, key, value_size }
+    }
+
+    pub(crate) fn request(request: &Request) -> SlowQuery {
+        let (key, value_size) = match request {
+            Request::Set { key, value } => (Some(key.clone()), Some(value.len() as u64)),
+            Request::Get { key } | Request::Rm { key } => (Some(key.clone()), None),
+            _ => (None, None),
+        };
+        SlowQuery { op: request.name(), key, value_size }
+    }
+
+    // The key is the first argument of every command that has one, `AUTH` has none
+    // so its password is not kept.
+    pub(crate) fn command(args: &[Vec<u8>]) -> SlowQuery {
+        let op = command_name(args);
+        let key = match op {
+            "auth" => None,
+            _ => args.get(1).map(|key| String::from_utf8_lossy(key).into_owned()),
+        };
+        let value_size = match op {
+            "set" => args.get(2).map(|value| value.len() as u64),
+            _ => None,
+        };
+        SlowQuery { op, key, value_size }
+    }
+
+    pub(crate) fn response(mut self, response: &Response) -> SlowQuery {
+        if let Response::Value(value) = response {
+            self.value_size = Some(value.len() as u64);
+        }
+        self
+    }
+
+    pub(crate) fn reply(mut self, reply: &RespValue) -> SlowQuery {
+        if let ("get", RespValue::Bulk(Some(value))) = (self.op, reply) {
+            self.value_size = Some(value.len() as u64);
+        }
+        self
+    }
+}
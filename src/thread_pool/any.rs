@@ -0,0 +1,56 @@
+use std::str::FromStr;
+
+use super::{NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use crate::KvsError;
+
+/// The `ThreadPool` implementations, to choose one at runtime.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PoolKind {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl FromStr for PoolKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<PoolKind> {
+        match s {
+            "naive" => Ok(PoolKind::Naive),
+            "shared" => Ok(PoolKind::SharedQueue),
+            "rayon" => Ok(PoolKind::Rayon),
+            _ => Err(KvsError::UnknownThreadPool(s.to_string())),
+        }
+    }
+}
+
+/// A thread pool of a `PoolKind`, `ThreadPool::new` makes a `SharedQueueThreadPool`.
+pub enum AnyThreadPool {
+    Naive(NaiveThreadPool),
+    SharedQueue(SharedQueueThreadPool),
+    Rayon(RayonThreadPool),
+}
+
+impl AnyThreadPool {
+    pub fn with_kind(kind: PoolKind, threads: usize) -> Result<AnyThreadPool> {
+        match kind {
+            PoolKind::Naive => Ok(AnyThreadPool::Naive(NaiveThreadPool::new(threads)?)),
+            PoolKind::SharedQueue => Ok(AnyThreadPool::SharedQueue(SharedQueueThreadPool::new(threads)?)),
+            PoolKind::Rayon => Ok(AnyThreadPool::Rayon(RayonThreadPool::new(threads)?)),
+        }
+    }
+}
+
+impl ThreadPool for AnyThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        AnyThreadPool::with_kind(PoolKind::SharedQueue, threads)
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        match self {
+            AnyThreadPool::Naive(pool) => pool.spawn(job),
+            AnyThreadPool::SharedQueue(pool) => pool.spawn(job),
+            AnyThreadPool::Rayon(pool) => pool.spawn(job),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::ThreadPool;
+
+/// Runs jobs on a `ThreadPool`, but refuses new ones while `capacity` jobs are waiting
+/// for a thread, so a full queue can be answered instead of growing without bound.
+pub struct BoundedThreadPool<P: ThreadPool> {
+    pool: P,
+    capacity: usize,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl<P: ThreadPool> BoundedThreadPool<P> {
+    pub fn new(pool: P, capacity: usize) -> BoundedThreadPool<P> {
+        BoundedThreadPool { pool, capacity, waiting: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Returns false without running `job` if the queue is full.
+    pub fn try_spawn<F>(&self, job: F) -> bool where F: FnOnce() + Send + 'static {
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.capacity {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        let waiting = self.waiting.clone();
+        self.pool.spawn(move || {
+            waiting.fetch_sub(1, Ordering::SeqCst);
+            job();
+        });
+        true
+    }
+
+    /// Jobs waiting for a thread.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    // The count behind `waiting`, for `Metrics`.
+    pub(crate) fn queue(&self) -> Weak<AtomicUsize> {
+        Arc::downgrade(&self.waiting)
+    }
+}
@@ -1,9 +1,13 @@
 use crate::Result;
 
+pub use self::any::{AnyThreadPool, PoolKind};
+pub use self::bounded::BoundedThreadPool;
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
 
+mod any;
+mod bounded;
 mod naive;
 mod shared_queue;
 mod rayon;
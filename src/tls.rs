@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustls::{Connection, RootCertStore, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+
+use crate::{KvsError, RejectReason, Result};
+use crate::limits::is_timeout;
+use crate::protocol::Stream;
+
+pub use rustls::{ClientConfig, ServerConfig};
+
+/// Build the config of a TLS server from PEM files.
+///
+/// With `client_ca_path`, clients must present a certificate signed by one of its CAs.
+pub fn server_config(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> Result<Arc<ServerConfig>> {
+    let builder = match client_ca_path {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(path)?))
+                .build()
+                .map_err(|e| KvsError::TlsConfigError(e.to_string()))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    Ok(Arc::new(config))
+}
+
+/// Build the config of a TLS client, servers must have a certificate signed by a CA in `ca_path`.
+///
+/// `identity` is the certificate and key paths sent to servers that ask for one.
+pub fn client_config(ca_path: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder().with_root_certificates(load_roots(ca_path)?);
+    let config = match identity {
+        Some((cert_path, key_path)) => builder.with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+pub(crate) fn server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_string())
+        .map_err(|_| KvsError::TlsConfigError(format!("invalid server name `{}`", name)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(KvsError::TlsConfigError(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(KvsError::TlsConfigError(format!("no private key in {}", path.display()))),
+    }
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// A TLS session over a `Stream`.
+///
+/// Clones share the session but not its socket reads, so one clone can block on reading
+/// while another writes, like the halves of a plain `TcpStream`.
+pub(crate) struct TlsStream<S: Stream> {
+    conn: Arc<Mutex<Connection>>,
+    sock: S,
+}
+
+impl<S: Stream> TlsStream<S> {
+    pub(crate) fn new(conn: impl Into<Connection>, sock: S) -> TlsStream<S> {
+        TlsStream { conn: Arc::new(Mutex::new(conn.into())), sock }
+    }
+
+    /// Run the server side of the handshake on `sock`, which must be done within `timeout`.
+    ///
+    /// Running out of time fails with `KvsError::Rejected(RejectReason::IdleTimeout)`,
+    /// the connection stays open until the caller drops `sock`.
+    pub(crate) fn accept(config: Arc<ServerConfig>, sock: &S, timeout: Option<Duration>) -> Result<TlsStream<S>> {
+        let mut sock = sock.try_clone()?;
+        let mut conn: Connection = ServerConnection::new(config)?.into();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let timed_out = |e: io::Error| if is_timeout(&e) { KvsError::Rejected(RejectReason::IdleTimeout) } else { e.into() };
+        loop {
+            // Every read and write gets the time left
+            let left = match deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+                Some(left) if left.is_zero() => return Err(KvsError::Rejected(RejectReason::IdleTimeout)),
+                left => left,
+            };
+            sock.set_read_timeout(left)?;
+            sock.set_write_timeout(left)?;
+            TlsStream::write_tls(&mut conn, &mut sock).map_err(timed_out)?;
+            if !conn.is_handshaking() {
+                break;
+            }
+            if conn.read_tls(&mut sock).map_err(timed_out)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            if let Err(e) = conn.process_new_packets() {
+                // Send the alert before giving up
+                let _ = TlsStream::write_tls(&mut conn, &mut sock);
+                return Err(e.into());
+            }
+        }
+        sock.set_read_timeout(None)?;
+        sock.set_write_timeout(None)?;
+        Ok(TlsStream::new(conn, sock))
+    }
+
+    fn write_tls(conn: &mut Connection, sock: &mut S) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(sock)?;
+        }
+        sock.flush()
+    }
+}
+
+impl<S: Stream> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = [0; 16 * 1024];
+        loop {
+            {
+                let mut conn = self.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                // The handshake may have something to send before the peer answers
+                TlsStream::write_tls(&mut conn, &mut self.sock)?;
+            }
+
+            // Wait for the peer without holding the session
+            let len = self.sock.read(&mut received)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut conn = self.conn.lock().unwrap();
+            let mut received = &received[..len];
+            while !received.is_empty() {
+                conn.read_tls(&mut received)?;
+                if let Err(e) = conn.process_new_packets() {
+                    // Send the alert before giving up
+                    let _ = TlsStream::write_tls(&mut conn, &mut self.sock);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+            TlsStream::write_tls(&mut conn, &mut self.sock)?;
+        }
+    }
+}
+
+impl<S: Stream> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let len = conn.writer().write(buf)?;
+        TlsStream::write_tls(&mut conn, &mut self.sock)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        TlsStream::write_tls(&mut conn, &mut self.sock)
+    }
+}
+
+impl<S: Stream> Stream for TlsStream<S> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream { conn: self.conn.clone(), sock: self.sock.try_clone()? })
+    }
+
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.sock.shutdown_read()
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.sock.shutdown_write()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.sock.peer_ip()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use slog::{Logger, o};
+
+use crate::{ErrorCode, Result};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Writes a span for every request a server answers to a file, to be read by an
+/// OpenTelemetry collector.
+///
+/// Each line is an OTLP/JSON export request with one span. The requests of a connection
+/// share a trace, and carry the same `conn` and `req` ids as the log records about them.
+pub struct SpanExporter {
+    file: Mutex<File>,
+}
+
+impl SpanExporter {
+    /// Append spans to the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<SpanExporter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(SpanExporter { file: Mutex::new(file) })
+    }
+
+    fn export(&self, span: &RequestTrace, end_time: SystemTime, code: Option<ErrorCode>) {
+        let mut attributes = vec![
+            int_attribute("kvs.connection_id", span.connection_id),
+            int_attribute("kvs.request_id", span.id),
+        ];
+        if let Some(peer) = span.peer {
+            attributes.push(json!({ "key": "client.address", "value": { "stringValue": peer.to_string() } }));
+        }
+        let status = match code {
+            Some(code) => {
+                attributes.push(json!({ "key": "kvs.error_code", "value": { "stringValue": format!("{:?}", code) } }));
+                json!({ "code": 2, "message": format!("{:?}", code) })
+            }
+            None => json!({ "code": 1 }),
+        };
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{ "key": "service.name", "value": { "stringValue": "kvs-server" } }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "kvs", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": [{
+                        "traceId": format!("{:032x}", span.trace_id),
+                        "spanId": format!("{:016x}", span.span_id),
+                        "name": span.op,
+                        // SPAN_KIND_SERVER
+                        "kind": 2,
+                        "startTimeUnixNano": unix_nanos(span.start_time),
+                        "endTimeUnixNano": unix_nanos(end_time),
+                        "attributes": attributes,
+                        "status": status,
+                    }],
+                }],
+            }],
+        });
+        let mut line = request.to_string();
+        line.push('\n');
+        // One write per line, so spans of concurrent requests don't mix
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            slog_scope::error!("Can't export span: {}", e);
+        }
+    }
+}
+
+fn int_attribute(key: &str, value: u64) -> Value {
+    // OTLP/JSON encodes 64 bit integers as strings
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+// The hasher of every `RandomState` is seeded differently, which is random enough for ids.
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// A connection as it appears in logs and spans.
+pub(crate) struct ConnectionTrace {
+    id: u64,
+    trace_id: u128,
+    peer: Option<IpAddr>,
+    next_request_id: AtomicU64,
+    logger: Logger,
+}
+
+impl ConnectionTrace {
+    /// Records logged with `logger` carry the id of the connection as `conn`.
+    pub(crate) fn new(peer: Option<IpAddr>, logger: &Logger) -> ConnectionTrace {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        ConnectionTrace {
+            id,
+            trace_id: (random_u64() as u128) << 64 | random_u64() as u128,
+            peer,
+            next_request_id: AtomicU64::new(1),
+            logger: logger.new(o!("conn" => id)),
+        }
+    }
+
+    pub(crate) fn peer(&self) -> Option<IpAddr> {
+        self.peer
+    }
+
+    pub(crate) fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    /// Start tracing the next request of the connection.
+    pub(crate) fn request(&self, op: &'static str) -> RequestTrace {
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        RequestTrace {
+            connection_id: self.id,
+            id,
+            trace_id: self.trace_id,
+            span_id: random_u64(),
+            op,
+            peer: self.peer,
+            started: Instant::now(),
+            start_time: SystemTime::now(),
+            logger: self.logger.new(o!("req" => id)),
+        }
+    }
+}
+
+/// A request of a `ConnectionTrace`, from when it is read until its response is ready.
+pub(crate) struct RequestTrace {
+    connection_id: u64,
+    id: u64,
+    trace_id: u128,
+    span_id: u64,
+    op: &'static str,
+    peer: Option<IpAddr>,
+    started: Instant,
+    start_time: SystemTime,
+    logger: Logger,
+}
+
+impl RequestTrace {
+    /// Records logged with it carry the ids of the connection and the request.
+    pub(crate) fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    /// Log the outcome of the request, and export its span if there is an exporter.
+    pub(crate) fn finish(&self, code: Option<ErrorCode>, spans: Option<&SpanExporter>) {
+        let duration = self.started.elapsed();
+        slog::debug!(self.logger, "Request done"; "op" => self.op, "duration_us" => duration.as_micros() as u64, "error" => ?code);
+        if let Some(spans) = spans {
+            spans.export(self, self.start_time + duration, code);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{Acl, AsyncKvsEngine, AsyncKvsServer, HttpGateway, KvsClient, KvsServer, MemoryKvsEngine, PoolKind, Result, ServerProtocol};
+
+const ACL: &str = r#"{"users": {
+    "app": {"password": "app-secret", "grants": [{"prefix": "app/", "access": ["read", "write"]}]},
+    "reader": {"password": "reader-secret", "grants": [{"prefix": "", "access": ["read"]}]},
+    "admin": {"password": "admin-token", "grants": [{"prefix": "", "access": ["admin"]}]}
+}}"#;
+
+fn acl() -> Arc<Acl> {
+    Arc::new(Acl::parse(ACL).unwrap())
+}
+
+fn client(addr: SocketAddr, user: Option<&str>, password: &str) -> KvsClient {
+    let mut client = KvsClient::new(addr);
+    client.set_auth(user, password);
+    client
+}
+
+// Clients are dropped before the next one connects, the sync server may only have one thread
+fn check_acl(addr: SocketAddr) -> Result<()> {
+    assert!(KvsClient::new(addr).set("app/key1", "value1").is_err());
+    assert!(client(addr, Some("app"), "wrong").set("app/key1", "value1").is_err());
+    assert!(client(addr, Some("reader"), "app-secret").set("app/key1", "value1").is_err());
+
+    let app = client(addr, Some("app"), "app-secret");
+    app.set("app/key1", "value1")?;
+    assert_eq!(app.get("app/key1")?, Some("value1".to_owned()));
+    let err = app.set("other/key1", "value1").unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{}", err);
+    assert!(app.get("other/key1").is_err());
+    drop(app);
+
+    let reader = client(addr, Some("reader"), "reader-secret");
+    assert_eq!(reader.get("app/key1")?, Some("value1".to_owned()));
+    assert!(reader.remove("app/key1").is_err());
+    assert!(reader.stats().is_err());
+    assert!(reader.compact().is_err());
+    assert!(reader.slow_log().is_err());
+    assert!(reader.reload().is_err());
+    drop(reader);
+
+    // A unique password works without a user name
+    let admin = client(addr, None, "admin-token");
+    admin.set("other/key1", "value1")?;
+    admin.remove("app/key1")?;
+    assert_eq!(admin.stats()?.engine, "memory");
+    // The memory engine has nothing to compact
+    assert!(admin.compact().is_err());
+    Ok(())
+}
+
+#[test]
+fn acl_file() {
+    assert!(Acl::parse(ACL).is_ok());
+    let shared = r#"{"users": {"a": {"password": "same", "grants": []}, "b": {"password": "same", "grants": []}}}"#;
+    assert!(Acl::parse(shared).is_err());
+    assert!(Acl::parse(r#"{"users": {"a": {"password": "p", "grants": [{"prefix": "", "access": ["delete"]}]}}}"#).is_err());
+}
+
+#[test]
+fn server_acl() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4025".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_acl(acl());
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    check_acl(addr)
+}
+
+#[test]
+fn async_server_acl() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4026".parse().unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+        let mut server = AsyncKvsServer::new(addr, engine);
+        server.set_acl(acl());
+        runtime.block_on(server.handle_connection()).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    check_acl(addr)
+}
+
+// `app` may only read, `reader` is removed
+const REPLACED_ACL: &str = r#"{"users": {
+    "app": {"password": "app-secret", "grants": [{"prefix": "app/", "access": ["read"]}]},
+    "admin": {"password": "admin-token", "grants": [{"prefix": "", "access": ["admin"]}]}
+}}"#;
+
+// Connections already logged in follow the replaced ACL
+fn check_acl_replace(addr: SocketAddr, acl: &Acl) -> Result<()> {
+    let app = client(addr, Some("app"), "app-secret");
+    app.set("app/key1", "value1")?;
+    let reader = client(addr, Some("reader"), "reader-secret");
+    assert_eq!(reader.get("app/key1")?, Some("value1".to_owned()));
+
+    assert!(acl.replace(Acl::parse(REPLACED_ACL)?));
+    assert!(!acl.replace(Acl::parse(REPLACED_ACL)?));
+    let err = app.set("app/key1", "value2").unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{}", err);
+    assert_eq!(app.get("app/key1")?, Some("value1".to_owned()));
+    let err = reader.get("app/key1").unwrap_err();
+    assert!(err.to_string().contains("must log in again"), "{}", err);
+    assert!(reader.get("app/key1").is_err());
+    Ok(())
+}
+
+#[test]
+fn acl_replace() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4067".parse().unwrap();
+    let server_acl = acl();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_thread_pool(PoolKind::SharedQueue, 4);
+    server.set_acl(server_acl.clone());
+    thread::spawn(move || server.handle_connection());
+
+    let async_addr: SocketAddr = "127.0.0.1:4068".parse().unwrap();
+    let async_acl = acl();
+    let acl_of_server = async_acl.clone();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+        let mut server = AsyncKvsServer::new(async_addr, engine);
+        server.set_acl(acl_of_server);
+        runtime.block_on(server.handle_connection()).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    check_acl_replace(addr, &server_acl)?;
+    check_acl_replace(async_addr, &async_acl)
+}
+
+fn assert_reply(stream: &mut TcpStream, command: &str, expected: &str) {
+    stream.write_all(format!("{}\r\n", command).as_bytes()).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected, "reply to {:?}", command);
+}
+
+#[test]
+fn resp_acl() {
+    let addr: SocketAddr = "127.0.0.1:4027".parse().unwrap();
+    let server_acl = acl();
+    let mut server = KvsServer::with_protocol(addr, Arc::new(MemoryKvsEngine::new()), ServerProtocol::Resp);
+    server.set_thread_pool(PoolKind::SharedQueue, 2);
+    server.set_acl(server_acl.clone());
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_reply(&mut stream, "GET app/key1", "-NOAUTH Authentication required.\r\n");
+    assert_reply(&mut stream, "AUTH app wrong", "-WRONGPASS invalid username-password pair\r\n");
+    assert_reply(&mut stream, "AUTH app app-secret", "+OK\r\n");
+    assert_reply(&mut stream, "MSET app/key1 value1 app/key2 value2", "+OK\r\n");
+    assert_reply(&mut stream, "MSET app/key3 value3 other/key1 value1", "-NOPERM user `app` has no Write access to `other/key1`\r\n");
+    assert_reply(&mut stream, "EXISTS app/key3", ":0\r\n");
+    assert_reply(&mut stream, "INFO", "-NOPERM user `app` has no Admin access to ``\r\n");
+
+    assert_reply(&mut stream, "AUTH admin-token", "+OK\r\n");
+    assert_reply(&mut stream, "SET other/key1 value1", "+OK\r\n");
+
+    assert_reply(&mut stream, "AUTH app app-secret", "+OK\r\n");
+    assert_reply(&mut stream, "SCAN 0", "*2\r\n$1\r\n0\r\n*2\r\n$8\r\napp/key1\r\n$8\r\napp/key2\r\n");
+
+    // Replacing the ACL applies to the connections logged in
+    let mut reader = TcpStream::connect(addr).unwrap();
+    assert_reply(&mut reader, "AUTH reader reader-secret", "+OK\r\n");
+    assert!(server_acl.replace(Acl::parse(REPLACED_ACL).unwrap()));
+    assert_reply(&mut stream, "SET app/key3 value3", "-NOPERM user `app` has no Write access to `app/key3`\r\n");
+    assert_reply(&mut stream, "GET app/key1", "$6\r\nvalue1\r\n");
+    assert_reply(&mut reader, "GET app/key1", "-NOAUTH Authentication required.\r\n");
+}
+
+fn http_status(addr: SocketAddr, method: &str, path: &str, credentials: Option<&str>) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let body = r#"{"value":"value1"}"#;
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, body.len());
+    if let Some(credentials) = credentials {
+        request.push_str(&format!("Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    let status = reply.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, reply)
+}
+
+#[test]
+fn http_acl() {
+    let addr: SocketAddr = "127.0.0.1:4028".parse().unwrap();
+    let mut gateway = HttpGateway::new(addr, Arc::new(MemoryKvsEngine::new()));
+    gateway.set_acl(acl());
+    thread::spawn(move || gateway.handle_connection().unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    // base64 of `app:app-secret`, `reader:reader-secret` and `:admin-token`
+    let app = "YXBwOmFwcC1zZWNyZXQ=";
+    let reader = "cmVhZGVyOnJlYWRlci1zZWNyZXQ=";
+    let admin = "OmFkbWluLXRva2Vu";
+
+    let (status, reply) = http_status(addr, "GET", "/v1/keys/app/key1", None);
+    assert_eq!(status, 401);
+    assert!(reply.contains("WWW-Authenticate: Basic realm=\"kvs\""), "{}", reply);
+    assert_eq!(http_status(addr, "GET", "/v1/keys/app/key1", Some("YXBwOndyb25n")).0, 401);
+
+    assert_eq!(http_status(addr, "PUT", "/v1/keys/app/key1", Some(app)).0, 201);
+    assert_eq!(http_status(addr, "PUT", "/v1/keys/other/key1", Some(app)).0, 403);
+    assert_eq!(http_status(addr, "PUT", "/v1/keys/other/key1", Some(admin)).0, 201);
+    assert_eq!(http_status(addr, "GET", "/v1/keys/app/key1", Some(reader)).0, 200);
+    assert_eq!(http_status(addr, "DELETE", "/v1/keys/app/key1", Some(reader)).0, 403);
+
+    let (status, reply) = http_status(addr, "GET", "/v1/keys", Some(app));
+    assert_eq!(status, 200);
+    assert!(reply.ends_with(r#"{"keys":["app/key1"]}"#), "{}", reply);
+}
+
+// `app` may use the database `users` besides the default one
+const DATABASES_ACL: &str = r#"{"users": {
+    "app": {"password": "app-secret", "grants": [{"prefix": "", "access": ["read", "write"]}], "databases": ["users"]},
+    "admin": {"password": "admin-token", "grants": [{"prefix": "", "access": ["admin"]}]}
+}}"#;
+
+fn db_client(addr: SocketAddr, password: &str, db: &str) -> KvsClient {
+    let mut client = client(addr, None, password);
+    client.set_db(db);
+    client
+}
+
+// Clients are dropped before the next one connects, the sync server may only have one thread
+fn check_acl_databases(addr: SocketAddr, acl: &Acl) -> Result<()> {
+    let app = db_client(addr, "app-secret", "users");
+    app.set("key1", "value1")?;
+    drop(app);
+    let err = db_client(addr, "app-secret", "secrets").set("key1", "value1").unwrap_err();
+    assert!(err.to_string().contains("no access to database `secrets`"), "{}", err);
+    // Unknown databases are not told apart from denied ones
+    let err = db_client(addr, "app-secret", "missing").set("key1", "value1").unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{}", err);
+    // Admin access to keys doesn't grant databases
+    assert!(db_client(addr, "admin-token", "users").get("key1").is_err());
+    assert!(db_client(addr, "admin-token", "default").get("key1").is_ok());
+
+    // A reload taking the database away closes the connections using it
+    let app = db_client(addr, "app-secret", "users");
+    assert_eq!(app.get("key1")?, Some("value1".to_owned()));
+    assert!(acl.replace(Acl::parse(&DATABASES_ACL.replace(r#", "databases": ["users"]"#, ""))?));
+    let err = app.get("key1").unwrap_err();
+    assert!(err.to_string().contains("no access to database `users`"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn acl_databases() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4080".parse().unwrap();
+    let server_acl = Arc::new(Acl::parse(DATABASES_ACL)?);
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.add_database("users", Arc::new(MemoryKvsEngine::new()));
+    server.add_database("secrets", Arc::new(MemoryKvsEngine::new()));
+    server.set_acl(server_acl.clone());
+    thread::spawn(move || server.handle_connection());
+
+    let async_addr: SocketAddr = "127.0.0.1:4081".parse().unwrap();
+    let async_acl = Arc::new(Acl::parse(DATABASES_ACL)?);
+    let acl_of_server = async_acl.clone();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+        let mut server = AsyncKvsServer::new(async_addr, engine);
+        server.add_database("users", Arc::new(MemoryKvsEngine::new()));
+        server.add_database("secrets", Arc::new(MemoryKvsEngine::new()));
+        server.set_acl(acl_of_server);
+        runtime.block_on(server.handle_connection()).unwrap();
+    });
+
+    let resp_addr: SocketAddr = "127.0.0.1:4082".parse().unwrap();
+    let resp_acl = Arc::new(Acl::parse(DATABASES_ACL)?);
+    let mut server = KvsServer::with_protocol(resp_addr, Arc::new(MemoryKvsEngine::new()), ServerProtocol::Resp);
+    server.add_database("users", Arc::new(MemoryKvsEngine::new()));
+    server.add_database("secrets", Arc::new(MemoryKvsEngine::new()));
+    server.set_acl(resp_acl.clone());
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    check_acl_databases(addr, &server_acl)?;
+    check_acl_databases(async_addr, &async_acl)?;
+
+    let mut stream = TcpStream::connect(resp_addr)?;
+    assert_reply(&mut stream, "AUTH app-secret", "+OK\r\n");
+    assert_reply(&mut stream, "SELECT secrets", "-NOPERM user `app` has no access to database `secrets`\r\n");
+    assert_reply(&mut stream, "SELECT users", "+OK\r\n");
+    assert_reply(&mut stream, "SET key1 value1", "+OK\r\n");
+    assert!(resp_acl.replace(Acl::parse(&DATABASES_ACL.replace(r#", "databases": ["users"]"#, ""))?));
+    assert_reply(&mut stream, "GET key1", "-NOAUTH Authentication required.\r\n");
+    Ok(())
+}
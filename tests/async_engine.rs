@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use kvs::{AsyncKvsEngine, MemoryKvsEngine, Result};
+
+#[tokio::test]
+async fn get_stored_value() -> Result<()> {
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 2);
+
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert!(engine.remove("key1".to_owned()).await.is_err());
+
+    Ok(())
+}
+
+// More tasks than blocking permits should all finish
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_set() -> Result<()> {
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 2);
+
+    let mut handles = Vec::new();
+    for i in 0..100 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            engine.set(format!("key{}", i), format!("value{}", i)).await
+        }));
+    }
+    for handle in handles {
+        handle.await??;
+    }
+
+    for i in 0..100 {
+        assert_eq!(engine.get(format!("key{}", i)).await?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
@@ -1,10 +1,12 @@
 use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use tempfile::TempDir;
 
@@ -21,28 +23,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "get", "key"])
+        .args(["--addr", "invalid-addr", "get", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -53,35 +55,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "set", "key", "value"])
+        .args(["--addr", "invalid-addr", "set", "key", "value"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -92,28 +94,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "rm", "key"])
+        .args(["--addr", "invalid-addr", "rm", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -124,7 +126,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -135,7 +137,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -146,7 +148,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -158,13 +160,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -179,15 +182,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -198,39 +202,41 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(engine: &str, mode: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--mode", mode, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value1"])
+        .args(["--addr", addr, "set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -238,7 +244,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -246,7 +252,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value2"])
+        .args(["--addr", addr, "set", "key1", "value2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -254,7 +260,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -262,7 +268,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -270,7 +276,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key2"])
+        .args(["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -278,7 +284,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key2", "value3"])
+        .args(["--addr", addr, "set", "key2", "value3"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -286,7 +292,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key1"])
+        .args(["--addr", addr, "rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -299,26 +305,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--mode", mode, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -329,10 +336,523 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "sync", "127.0.0.1:4004");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "sync", "127.0.0.1:4005");
+}
+
+#[test]
+fn cli_access_async_server_kvs_engine() {
+    cli_access_server("kvs", "async", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_access_async_server_sled_engine() {
+    cli_access_server("sled", "async", "127.0.0.1:4008");
+}
+
+// The memory engine keeps data only for the lifetime of the server
+#[test]
+fn cli_access_server_memory_engine() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+// A Unix socket can be served alongside TCP
+#[test]
+fn cli_access_server_unix_socket() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let socket = socket_path.to_str().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--mode", "async", "--addr", addr, "--unix", socket, "--unix-mode", "600"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--unix", socket, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn cli_thread_pool() {
+    let addr = "127.0.0.1:4032";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--pool", "rayon", "--threads", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+
+    for args in [["--pool", "fifo"], ["--pool", "naive"], ["--threads", "0"]] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn cli_stats() {
+    let addr = "127.0.0.1:4042";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--threads", "3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for value in ["value1", "value2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine: kvs\n"))
+        .stdout(contains("threads: 3\n"))
+        .stdout(contains("keys: 1\n"))
+        .stdout(contains("since_last_compact_log_num: 2\n"))
+        .stdout(contains("compactions: 0\n"))
+        .stdout(contains("file kvs_log_entry: "));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn cli_compact() {
+    let addr = "127.0.0.1:4043";
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--compact-dead-ratio", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--compact-dead-ratio", "0.9", "--compact-dead-bytes", "4096", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for value in ["value1", "value2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("reclaimed_bytes: "))
+        .stdout(contains("reclaimed_bytes: 0\n").not());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("compactions: 1\n"))
+        .stdout(contains("dead_bytes: 0\n"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn cli_slowlog() {
+    let addr = "127.0.0.1:4047";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--slowlog-threshold", "0", "--slowlog-len", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "slowlog"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("set key=key1 value_size=6 duration_ms="))
+        .stdout(contains(" client=127.0.0.1 seconds_ago="))
+        .stdout(contains("\n").count(1));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn cli_config_file() {
+    let addr = "127.0.0.1:4048";
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.toml"), r#"
+data_dir = "data"
+
+[listen]
+addr = "127.0.0.1:4049"
+
+[engine]
+name = "kvs"
+options = { compact_dead_ratio = 0.9 }
+
+[durability]
+sync = true
+
+[log]
+level = "info"
+file = "server.log"
+"#).unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    // The flag wins over the file
+    let mut child = server
+        .args(["--config", "kvs.toml", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine: kvs\n"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    assert!(temp_dir.path().join("data").join("kvs_log_entry").exists());
+    assert!(!temp_dir.path().join("kvs_log_entry").exists());
+    let log = fs::read_to_string(temp_dir.path().join("server.log")).unwrap();
+    assert!(log.contains(&format!("Listening on {}", addr)), "{}", log);
+}
+
+#[test]
+fn cli_invalid_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    for (contents, message) in [
+        ("port = 4000", "Can't load config kvs.toml: invalid config: "),
+        ("mode = \"fast\"", "The mode fast is invalid"),
+        ("[log]\nlevel = \"loud\"", "The log level loud is invalid"),
+    ] {
+        fs::write(temp_dir.path().join("kvs.toml"), contents).unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--config", "kvs.toml"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stdout(contains(message));
+    }
+}
+
+#[test]
+fn cli_databases() {
+    let addr = "127.0.0.1:4053";
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.toml"), r#"
+[databases.sessions]
+engine = "memory"
+
+[databases.users]
+"#).unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--config", "kvs.toml", "--addr", addr, "--data-dir", "data", "--db", "cache=sled"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for db in ["users", "cache"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "--db", db, "set", "key1", db])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--db", "users", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("users\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--db", "sessions", "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine: memory\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--db", "missing", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    let databases = temp_dir.path().join("data").join("databases");
+    assert!(databases.join("users").join("kvs_log_entry").exists());
+    assert!(databases.join("cache").exists());
+
+    for db in ["default", "a/b"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--db", db])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stdout(contains(format!("The database name {} is invalid", db)));
+    }
+}
+
+#[test]
+fn cli_json_log() {
+    let addr = "127.0.0.1:4055";
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.toml"), r#"
+[log]
+level = "debug"
+format = "json"
+file = "server.log"
+trace_file = "spans.jsonl"
+"#).unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--config", "kvs.toml", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    let log = fs::read_to_string(temp_dir.path().join("server.log")).unwrap();
+    let records: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let done = records.iter().find(|record| record["msg"] == "Request done").expect("no record of the request");
+    assert_eq!(done["op"], "set");
+    assert_eq!(done["level"], "DEBG");
+    assert_eq!(done["req"], 1);
+    let conn = &done["conn"];
+    assert!(conn.is_u64());
+    assert!(records.iter().any(|record| record["msg"] == "Send response." && &record["conn"] == conn));
+    let spans = fs::read_to_string(temp_dir.path().join("spans.jsonl")).unwrap();
+    assert!(spans.contains(r#""name":"set""#), "{}", spans);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--log-format", "xml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("The log format xml is invalid"));
+}
+
+#[test]
+fn cli_reload() {
+    let addr = "127.0.0.1:4058";
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    let acl = temp_dir.path().join("acl.json");
+    fs::write(&acl, r#"{"users": {"admin": {"password": "old", "grants": [{"prefix": "", "access": ["admin"]}]}}}"#).unwrap();
+    fs::write(&config, "acl = \"acl.json\"\n\n[log]\nfile = \"server.log\"\n\n[slowlog]\nthreshold = 10\n").unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--config", "kvs.toml", "--addr", addr, "--engine", "memory"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // The unchanged ACL is not reported
+    fs::write(&config, "acl = \"acl.json\"\n\n[log]\nfile = \"server.log\"\n\n[slowlog]\nthreshold = 0\n\n[pool]\nthreads = 2\n").unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--password", "old", "reload"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("applied: slowlog.threshold\n").and(contains("restart_required: pool.threads\n")));
+    fs::write(&acl, r#"{"users": {"admin": {"password": "new", "grants": [{"prefix": "", "access": ["admin"]}]}}}"#).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--password", "old", "reload"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("applied: acl\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--password", "new", "slowlog"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("reload"));
+
+    // An invalid config changes nothing
+    fs::write(&config, "acl = \"acl.json\"\n\n[log]\nfile = \"server.log\"\nlevel = \"loud\"\n").unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+    fs::write(&config, "acl = \"acl.json\"\n\n[log]\nfile = \"server.log\"\nlevel = \"info\"\n").unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    let log = fs::read_to_string(temp_dir.path().join("server.log")).unwrap();
+    assert!(log.contains("Can't reload settings: invalid config: The log level loud is invalid"), "{}", log);
+    assert!(log.contains("applied: slowlog.threshold, log.level"), "{}", log);
+    assert!(log.contains("Restart to apply pool.threads"), "{}", log);
 }
@@ -0,0 +1,406 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{Deserializer, Value};
+use tempfile::TempDir;
+
+use kvs::{AsyncKvsClient, AsyncKvsEngine, AsyncKvsServer, BINARY_HANDSHAKE, DEFAULT_UNIX_MODE, ErrorCode, KvsClient, KvsError, KvsServer, MemoryKvsEngine, Protocol, PROTOCOL_VERSION, Request, RequestEnvelope, Response, ResponseEnvelope, Result};
+
+fn response_envelope(id: &Value, value: &str) -> String {
+    format!("{{\"id\":{},\"response\":{{\"Value\":\"{}\"}}}}", id, value)
+}
+
+// Answer `count` requests on the stream, every answer is `value`.
+fn answer_requests(stream: &TcpStream, count: usize, value: &str) {
+    let mut writer = stream;
+    let requests = Deserializer::from_reader(stream).into_iter::<Value>();
+    for request in requests.take(count) {
+        let request = request.unwrap();
+        writer.write_all(response_envelope(&request["id"], value).as_bytes()).unwrap();
+    }
+}
+
+// Requests from one client share a connection
+#[test]
+fn reuse_connection() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4010")?;
+    let addr = listener.local_addr()?;
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        // No more connections can be accepted
+        drop(listener);
+        answer_requests(&stream, 3, "value1");
+    });
+
+    let client = KvsClient::new(addr);
+    for _ in 0..3 {
+        assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    }
+    handle.join().unwrap();
+
+    Ok(())
+}
+
+// A connection closed by the server is replaced without an error
+#[test]
+fn reconnect_after_close() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4011")?;
+    let addr = listener.local_addr()?;
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        answer_requests(&stream, 1, "value1");
+        drop(stream);
+        let (stream, _) = listener.accept().unwrap();
+        answer_requests(&stream, 1, "value2");
+    });
+
+    let client = KvsClient::new(addr);
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.get("key1")?, Some("value2".to_owned()));
+    handle.join().unwrap();
+
+    Ok(())
+}
+
+// Requests the server may have run are not sent again
+#[test]
+fn no_resend_after_answer() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4059")?;
+    let addr = listener.local_addr()?;
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        answer_requests(&stream, 1, "value1");
+        let mut reader = stream.try_clone().unwrap();
+        let mut writer = &stream;
+        let mut buf = [0; 64];
+        let _ = reader.read(&mut buf).unwrap();
+        writer.write_all(b"{\"id\": oops}").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err(), "the request was sent again");
+    });
+
+    let client = KvsClient::new(addr);
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    match client.remove("key1") {
+        Err(KvsError::SerdeError(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    handle.join().unwrap();
+
+    Ok(())
+}
+
+// Responses arriving out of order are matched to their requests by id
+#[test]
+fn pipeline_out_of_order() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4014")?;
+    let addr = listener.local_addr()?;
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = &stream;
+        let requests: Vec<Value> = Deserializer::from_reader(&stream)
+            .into_iter::<Value>()
+            .take(3)
+            .map(|request| request.unwrap())
+            .collect();
+        for request in requests.iter().rev() {
+            let key = request["request"]["Get"]["key"].as_str().unwrap();
+            writer.write_all(response_envelope(&request["id"], key).as_bytes()).unwrap();
+        }
+    });
+
+    let client = KvsClient::new(addr);
+    let requests = (0..3).map(|i| Request::Get { key: format!("key{}", i) }).collect();
+    let responses = client.pipeline(requests)?;
+    for (i, resp) in responses.iter().enumerate() {
+        assert_eq!(resp, &Response::Value(format!("key{}", i)));
+    }
+    handle.join().unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn pipeline_requests() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4015".parse().unwrap();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+        server.handle_connection().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    let requests = (0..500)
+        .map(|i| Request::Set { key: format!("key{}", i), value: format!("value{}", i) })
+        .collect();
+    for resp in client.pipeline(requests)? {
+        assert!(resp.is_ok());
+    }
+    let requests = (0..500).map(|i| Request::Get { key: format!("key{}", i) }).collect();
+    for (i, resp) in client.pipeline(requests)?.iter().enumerate() {
+        assert_eq!(resp, &Response::Value(format!("value{}", i)));
+    }
+
+    // A request without envelope gets a response without envelope
+    drop(client);
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"{\"Get\":{\"key\":\"key1\"}}\n")?;
+    let resp = Value::deserialize(&mut Deserializer::from_reader(stream))?;
+    assert_eq!(resp, serde_json::json!({"Value": "value1"}));
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_requests() -> Result<()> {
+    // Every client thread keeps a connection open, which would pin all workers of `KvsServer`
+    let addr: SocketAddr = "127.0.0.1:4012".parse().unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+        let mut server = AsyncKvsServer::new(addr, engine);
+        runtime.block_on(server.handle_connection()).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = Arc::new(KvsClient::new(addr));
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let client = client.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..100 {
+                let key = format!("key{}_{}", thread_id, i);
+                client.set(&key, &format!("value{}", i)).unwrap();
+                assert_eq!(client.get(&key).unwrap(), Some(format!("value{}", i)));
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_concurrent_requests() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4013".parse().unwrap();
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    tokio::spawn(async move {
+        let mut server = AsyncKvsServer::new(addr, engine);
+        server.handle_connection().await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = Arc::new(AsyncKvsClient::new(addr));
+    let mut handles = Vec::new();
+    for task_id in 0..8 {
+        let client = client.clone();
+        handles.push(tokio::spawn(async move {
+            for i in 0..100 {
+                let key = format!("key{}_{}", task_id, i);
+                client.set(&key, &format!("value{}", i)).await.unwrap();
+                assert_eq!(client.get(&key).await.unwrap(), Some(format!("value{}", i)));
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await?;
+    }
+    client.remove("key0_0").await?;
+    assert!(!client.is_key_exist("key0_0").await?);
+
+    Ok(())
+}
+
+#[test]
+fn binary_protocol() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4016".parse().unwrap();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+        server.handle_connection().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::with_protocol(addr, Protocol::Binary);
+    let large_value = "v".repeat(1024 * 1024);
+    client.set("key1", &large_value)?;
+    assert_eq!(client.get("key1")?, Some(large_value));
+    let requests = (0..100).map(|i| Request::Get { key: format!("key{}", i) }).collect();
+    assert_eq!(client.pipeline(requests)?.len(), 100);
+    drop(client);
+
+    // A broken frame is skipped and the next one is still answered
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[BINARY_HANDSHAKE])?;
+    let mut handshake = [0; 1];
+    stream.read_exact(&mut handshake)?;
+    assert_eq!(handshake[0], BINARY_HANDSHAKE);
+    stream.write_all(&[0, 0, 0, 3, 0xff, 0xff, 0xff])?;
+    let payload = bincode::serialize(&RequestEnvelope::new(7, Request::Get { key: "key2".to_owned() })).unwrap();
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(&payload)?;
+    let mut len_buf = [0; 4];
+    stream.read_exact(&mut len_buf)?;
+    let mut payload = vec![0; u32::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut payload)?;
+    let envelope: ResponseEnvelope = bincode::deserialize(&payload).unwrap();
+    assert_eq!(envelope.id, 7);
+    assert!(envelope.response.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_binary_protocol() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4017".parse().unwrap();
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    tokio::spawn(async move {
+        let mut server = AsyncKvsServer::new(addr, engine);
+        server.handle_connection().await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = AsyncKvsClient::with_protocol(addr, Protocol::Binary);
+    let large_value = "v".repeat(1024 * 1024);
+    client.set("key1", &large_value).await?;
+    assert_eq!(client.get("key1").await?, Some(large_value));
+    let requests = (0..100)
+        .map(|i| Request::Set { key: format!("key{}", i), value: format!("value{}", i) })
+        .collect();
+    for resp in client.pipeline(requests).await? {
+        assert!(resp.is_ok());
+    }
+    assert_eq!(client.get("key99").await?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Requests split over many reads, with braces and quotes in their strings
+#[test]
+fn async_split_requests() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4077".parse().unwrap();
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(AsyncKvsServer::new(addr, engine).handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "}{\"]\\".repeat(1000);
+    let set = Request::Set { key: "key1".to_owned(), value: value.clone() };
+    let get = Request::Get { key: "key1".to_owned() };
+    let mut requests = serde_json::to_vec(&set)?;
+    requests.extend(serde_json::to_vec(&get)?);
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    for chunk in requests.chunks(7) {
+        stream.write_all(chunk)?;
+    }
+    let mut responses = Deserializer::from_reader(&stream).into_iter::<Response>();
+    assert_eq!(responses.next().unwrap()?, Response::Ok);
+    assert_eq!(responses.next().unwrap()?, Response::Value(value));
+
+    Ok(())
+}
+
+#[test]
+fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let server_path = path.clone();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(server_path, Arc::new(MemoryKvsEngine::new()));
+        server.handle_connection().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, DEFAULT_UNIX_MODE);
+
+    let client = KvsClient::connect_unix(&path);
+    client.set("key1", "value1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    drop(client);
+    let client = KvsClient::with_protocol(path, Protocol::Binary);
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A socket file left by a stopped server is replaced
+#[tokio::test(flavor = "multi_thread")]
+async fn async_unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    drop(std::os::unix::net::UnixListener::bind(&path)?);
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    let mut server = AsyncKvsServer::new(path.clone(), engine);
+    server.set_unix_mode(0o600);
+    tokio::spawn(async move {
+        server.handle_connection().await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+    let client = KvsClient::connect_unix(&path);
+    tokio::task::spawn_blocking(move || {
+        client.set("key1", "value1")?;
+        assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+        Ok(())
+    }).await.unwrap()
+}
+
+// Empty values are not missing keys, and errors carry a code
+#[test]
+fn typed_responses() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4029".parse().unwrap();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+        server.handle_connection().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::with_protocol(addr, Protocol::Binary);
+    client.set("empty", "")?;
+    assert_eq!(client.get("empty")?, Some("".to_owned()));
+    assert_eq!(client.get("missing")?, None);
+    assert!(matches!(client.remove("missing"), Err(KvsError::KeyNotFound(key)) if key == "missing"));
+    let stats = client.stats()?;
+    assert_eq!(stats.engine, "memory");
+    assert_eq!(stats.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(stats.engine_stats.keys, Some(1));
+
+    let requests = vec![
+        Request::Get { key: "empty".to_owned() },
+        Request::Get { key: "missing".to_owned() },
+        Request::Rm { key: "empty".to_owned() },
+        Request::Auth { user: None, password: "secret".to_owned() },
+    ];
+    let responses = client.pipeline(requests)?;
+    assert_eq!(responses[0], Response::Value("".to_owned()));
+    assert_eq!(responses[1], Response::NotFound);
+    assert_eq!(responses[2], Response::Ok);
+    assert_eq!(responses[3], Response::Ok);
+    drop(client);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let envelope = serde_json::json!({"version": PROTOCOL_VERSION + 1, "id": 1, "request": {"Get": {"key": "empty"}}});
+    stream.write_all(envelope.to_string().as_bytes())?;
+    let envelope = ResponseEnvelope::deserialize(&mut Deserializer::from_reader(stream))?;
+    match envelope.response {
+        Response::Error { code, message } => {
+            assert_eq!(code, ErrorCode::Protocol);
+            assert!(message.contains("unsupported protocol version"), "{}", message);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::PathBuf;
+
+use tempfile::TempDir;
+
+use kvs::{ConfigFile, KvsError, Result};
+
+const CONFIG: &str = r#"
+data_dir = "data"
+mode = "async"
+
+[listen]
+addr = "127.0.0.1:4000"
+unix = "/tmp/kvs.sock"
+
+[engine]
+name = "kvs"
+options = { compact_dead_ratio = 0.25, snapshot = true, label = "main" }
+
+[pool]
+threads = 4
+
+[durability]
+sync = true
+
+[limits]
+idle_timeout = 300
+
+[log]
+level = "warning"
+file = "kvs.log"
+
+[tls]
+cert = "cert.pem"
+key = "key.pem"
+
+[databases.sessions]
+engine = "memory"
+options = { snapshot = false }
+"#;
+
+#[test]
+fn parse_config() -> Result<()> {
+    let config = ConfigFile::parse(CONFIG)?;
+    assert_eq!(config.mode.as_deref(), Some("async"));
+    assert_eq!(config.listen.addr.as_deref(), Some("127.0.0.1:4000"));
+    assert_eq!(config.engine.name.as_deref(), Some("kvs"));
+    assert_eq!(config.pool.threads, Some(4));
+    assert_eq!(config.pool.kind, None);
+    assert_eq!(config.durability.sync, Some(true));
+    assert_eq!(config.limits.idle_timeout, Some(300));
+    assert_eq!(config.log.level.as_deref(), Some("warning"));
+    let options: Vec<(&str, String)> = config.engine_options().collect();
+    assert_eq!(options, vec![
+        ("compact_dead_ratio", "0.25".to_owned()),
+        ("label", "main".to_owned()),
+        ("snapshot", "true".to_owned()),
+    ]);
+    let sessions = &config.databases["sessions"];
+    assert_eq!(sessions.engine.as_deref(), Some("memory"));
+    assert_eq!(sessions.engine_options().collect::<Vec<_>>(), vec![("snapshot", "false".to_owned())]);
+
+    assert_eq!(ConfigFile::parse("")?, ConfigFile::default());
+    Ok(())
+}
+
+#[test]
+fn invalid_config() {
+    for contents in ["port = 4000", "[limits]\nidle_timeout = \"5m\"", "[listen\naddr = 1"] {
+        match ConfigFile::parse(contents) {
+            Err(KvsError::InvalidConfig(_)) => {}
+            result => panic!("unexpected result {:?} for {}", result, contents),
+        }
+    }
+}
+
+// Relative paths are relative to the directory of the file
+#[test]
+fn open_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(&path, CONFIG)?;
+    let config = ConfigFile::open(&path)?;
+    assert_eq!(config.data_dir, Some(temp_dir.path().join("data")));
+    assert_eq!(config.log.file, Some(temp_dir.path().join("kvs.log")));
+    assert_eq!(config.tls.cert, Some(temp_dir.path().join("cert.pem")));
+    assert_eq!(config.listen.unix, Some(PathBuf::from("/tmp/kvs.sock")));
+
+    assert!(ConfigFile::open(temp_dir.path().join("missing.toml")).is_err());
+    Ok(())
+}
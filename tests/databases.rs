@@ -0,0 +1,88 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{AsyncKvsEngine, AsyncKvsServer, ErrorCode, KvsClient, KvsError, KvsServer, MemoryKvsEngine, PoolKind, ServerProtocol, SharedEngine};
+
+fn expect_unknown_database(result: kvs::Result<()>) {
+    match result {
+        Err(KvsError::ServerError { code: ErrorCode::UnknownDatabase, .. }) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+#[test]
+fn server_select() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4050".parse().unwrap();
+    let resp_addr: SocketAddr = "127.0.0.1:4051".parse().unwrap();
+    let engine: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    let users: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    let mut server = KvsServer::new(addr, engine.clone());
+    // Each client keeps its connection, and so a thread
+    server.set_thread_pool(PoolKind::SharedQueue, 4);
+    server.add_database("users", users.clone());
+    let mut resp_server = KvsServer::with_protocol(resp_addr, engine.clone(), ServerProtocol::Resp);
+    resp_server.add_database("users", users.clone());
+    thread::spawn(move || server.handle_connection());
+    thread::spawn(move || resp_server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    let mut users_client = KvsClient::new(addr);
+    users_client.set_db("users");
+    client.set("key1", "default")?;
+    users_client.set("key1", "users")?;
+    assert_eq!(client.get("key1")?, Some("default".to_owned()));
+    assert_eq!(users_client.get("key1")?, Some("users".to_owned()));
+    assert_eq!(users.get("key1")?, Some("users".to_owned()));
+    users_client.remove("key1")?;
+    assert_eq!(client.get("key1")?, Some("default".to_owned()));
+
+    let mut missing_client = KvsClient::new(addr);
+    missing_client.set_db("missing");
+    expect_unknown_database(missing_client.set("key1", "value1"));
+
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.write_all(b"*2\r\n$6\r\nSELECT\r\n$5\r\nusers\r\n*3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$5\r\nvalue\r\n")?;
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"+OK\r\n+OK\r\n");
+    assert_eq!(users.get("key2")?, Some("value".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+    stream.write_all(b"*2\r\n$6\r\nSELECT\r\n$7\r\nmissing\r\n")?;
+    let mut reply = [0; 31];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"-ERR unknown database `missing`");
+
+    Ok(())
+}
+
+#[test]
+fn async_server_select() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4052".parse().unwrap();
+    let users: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    let mut server = AsyncKvsServer::new(addr, engine);
+    server.add_database("users", users.clone());
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    let mut users_client = KvsClient::new(addr);
+    users_client.set_db("users");
+    users_client.set("key1", "users")?;
+    assert_eq!(client.get("key1")?, None);
+    assert_eq!(users_client.get("key1")?, Some("users".to_owned()));
+    assert_eq!(users.get("key1")?, Some("users".to_owned()));
+
+    let mut missing_client = KvsClient::new(addr);
+    missing_client.set_db("missing");
+    expect_unknown_database(missing_client.remove("key1"));
+
+    Ok(())
+}
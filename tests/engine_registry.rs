@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use tempfile::TempDir;
+
+use kvs::{DynKvsEngine, EngineConfig, EngineRegistry, KvsError, KvStore, MemoryKvsEngine, Result, SharedEngine};
+
+#[test]
+fn open_builtin_engines() -> Result<()> {
+    let registry = EngineRegistry::default();
+    assert_eq!(registry.names(), vec!["kvs", "memory", "sled"]);
+
+    for engine_name in registry.names() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = registry.open(engine_name, temp_dir.path(), &EngineConfig::new())?;
+        store.set("key1", "value1")?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A directory created by one engine can't be opened by another one
+#[test]
+fn open_wrong_engine() -> Result<()> {
+    let registry = EngineRegistry::default();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(registry.open("sled", temp_dir.path(), &EngineConfig::new())?);
+
+    assert!(registry.open("kvs", temp_dir.path(), &EngineConfig::new()).is_err());
+    assert!(registry.open("sled", temp_dir.path(), &EngineConfig::new()).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn open_unknown_engine() {
+    let registry = EngineRegistry::default();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(registry.open("unknown", temp_dir.path(), &EngineConfig::new()).is_err());
+}
+
+#[test]
+fn register_custom_engine() -> Result<()> {
+    let mut registry = EngineRegistry::new();
+    registry.register("custom", |_path, config| {
+        let engine = MemoryKvsEngine::new();
+        if let Some(value) = config.get("preset") {
+            engine.set("preset", value)?;
+        }
+        Ok(Arc::new(engine))
+    });
+    assert!(registry.contains("custom"));
+    assert!(!registry.contains("kvs"));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut config = EngineConfig::new();
+    config.set("preset", "value1");
+    let store = registry.open("custom", temp_dir.path(), &config)?;
+    assert_eq!(store.get("preset")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Engines of different types can be used through the same trait object
+#[test]
+fn dyn_engine_trait_object() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<SharedEngine> = vec![
+        Arc::new(MemoryKvsEngine::new()),
+        Arc::new(KvStore::open(temp_dir.path())?),
+    ];
+
+    for engine in engines.iter() {
+        let engine: &dyn DynKvsEngine = engine.as_ref();
+        engine.set("key1", "value1")?;
+        assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+        engine.remove("key1")?;
+        assert_eq!(engine.get("key1")?, None);
+        assert!(engine.remove("key1").is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn dyn_engine_bytes() -> Result<()> {
+    let engine: SharedEngine = Arc::new(MemoryKvsEngine::new());
+
+    engine.set_bytes(b"key1", "välue1".as_bytes())?;
+    assert_eq!(engine.get("key1")?, Some("välue1".to_owned()));
+    assert_eq!(engine.get_bytes(b"key1")?, Some("välue1".as_bytes().to_vec()));
+    engine.remove_bytes(b"key1")?;
+    assert_eq!(engine.get_bytes(b"key1")?, None);
+
+    assert!(matches!(engine.set_bytes(b"\xff", b"value1"), Err(KvsError::NotUtf8("key"))));
+    assert!(matches!(engine.set_bytes(b"key1", b"\xff"), Err(KvsError::NotUtf8("value"))));
+    assert!(matches!(engine.get_bytes(b"\xff"), Err(KvsError::NotUtf8("key"))));
+    assert!(matches!(engine.remove_bytes(b"\xff"), Err(KvsError::NotUtf8("key"))));
+    assert_eq!(engine.get("key1")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn open_unknown_engine_option() -> Result<()> {
+    let registry = EngineRegistry::default();
+    assert_eq!(registry.options(), vec!["compact_dead_bytes", "compact_dead_ratio", "snapshot", "sync"]);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut config = EngineConfig::new();
+    config.set("snapshott", "true");
+    match registry.open("memory", temp_dir.path(), &config) {
+        Err(KvsError::UnknownEngineOption(key, options)) => {
+            assert_eq!(key, "snapshott");
+            assert_eq!(options, "compact_dead_bytes, compact_dead_ratio, snapshot, sync");
+        }
+        result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+
+    // Options of another engine are left to it
+    let mut config = EngineConfig::new();
+    config.set("compact_dead_ratio", "0.3");
+    drop(registry.open("memory", temp_dir.path(), &config)?);
+
+    Ok(())
+}
+
+// An engine of another crate only has to store keys
+#[derive(Clone)]
+struct MinimalEngine;
+
+impl kvs::KvsEngine for MinimalEngine {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&self, _key: String) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        Err(KvsError::KeyNotFound(key))
+    }
+}
+
+#[test]
+fn minimal_engine() {
+    let engine: SharedEngine = Arc::new(MinimalEngine);
+    assert!(matches!(engine.scan_prefix(""), Err(KvsError::Unsupported(_))));
+    assert!(engine.flush().is_ok());
+    assert!(engine.compact().is_err());
+    assert_eq!(engine.name(), "unknown");
+}
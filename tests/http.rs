@@ -0,0 +1,189 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use kvs::{HttpGateway, KvsClient, KvsServer, MemoryKvsEngine, Metrics, RateLimit, Result, SharedEngine, SlowLog};
+
+struct HttpReply {
+    status: u16,
+    etag: Option<String>,
+    body: String,
+}
+
+fn http(addr: SocketAddr, method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> HttpReply {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, body.len());
+    for (field, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", field, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+    let mut lines = head.lines();
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let etag = lines
+        .filter_map(|line| line.split_once(": "))
+        .find(|(field, _)| field.eq_ignore_ascii_case("ETag"))
+        .map(|(_, value)| value.to_owned());
+    HttpReply { status, etag, body: body.to_owned() }
+}
+
+fn json(reply: &HttpReply) -> Value {
+    serde_json::from_str(&reply.body).unwrap()
+}
+
+#[test]
+fn http_gateway() -> Result<()> {
+    let http_addr: SocketAddr = "127.0.0.1:4020".parse().unwrap();
+    let tcp_addr: SocketAddr = "127.0.0.1:4021".parse().unwrap();
+    let engine: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    let mut gateway = HttpGateway::new(http_addr, engine.clone());
+    thread::spawn(move || gateway.handle_connection().unwrap());
+    thread::spawn(move || KvsServer::new(tcp_addr, engine).handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let reply = http(http_addr, "GET", "/v1/keys/key1", &[], "");
+    assert_eq!(reply.status, 404);
+    assert!(json(&reply)["error"].is_string());
+
+    let reply = http(http_addr, "PUT", "/v1/keys/key1", &[], r#"{"value":"value1"}"#);
+    assert_eq!(reply.status, 201);
+    let etag1 = reply.etag.unwrap();
+    let reply = http(http_addr, "PUT", "/v1/keys/key1", &[], r#"{"value":""}"#);
+    assert_eq!(reply.status, 200);
+    let reply = http(http_addr, "GET", "/v1/keys/key1", &[], "");
+    assert_eq!(reply.status, 200);
+    assert_eq!(json(&reply)["value"], "");
+
+    // Conditional writes
+    let reply = http(http_addr, "PUT", "/v1/keys/key1", &[("If-Match", &etag1)], r#"{"value":"value2"}"#);
+    assert_eq!(reply.status, 412);
+    let etag_empty = reply_etag(http_addr, "key1");
+    let weak_etag = format!("W/{}", etag_empty);
+    let reply = http(http_addr, "PUT", "/v1/keys/key1", &[("If-Match", &weak_etag)], r#"{"value":"value2"}"#);
+    assert_eq!(reply.status, 412);
+    let reply = http(http_addr, "PUT", "/v1/keys/key1", &[("If-Match", &etag_empty)], r#"{"value":"value2"}"#);
+    assert_eq!(reply.status, 200);
+    let reply = http(http_addr, "PUT", "/v1/keys/key1", &[("If-None-Match", "*")], r#"{"value":"value3"}"#);
+    assert_eq!(reply.status, 412);
+    let reply = http(http_addr, "GET", "/v1/keys/key1", &[("If-None-Match", &reply_etag(http_addr, "key1"))], "");
+    assert_eq!(reply.status, 304);
+
+    // The TCP listener serves the same engine
+    let client = KvsClient::new(tcp_addr);
+    assert_eq!(client.get("key1")?, Some("value2".to_owned()));
+    client.set("key 2", "value")?;
+    client.set("other", "value")?;
+    drop(client);
+
+    let reply = http(http_addr, "GET", "/v1/keys?prefix=key", &[], "");
+    assert_eq!(reply.status, 200);
+    assert_eq!(json(&reply)["keys"], serde_json::json!(["key 2", "key1"]));
+    assert_eq!(http(http_addr, "GET", "/v1/keys/key%202", &[], "").status, 200);
+
+    let reply = http(http_addr, "DELETE", "/v1/keys/key1", &[("If-Match", &etag1)], "");
+    assert_eq!(reply.status, 412);
+    assert_eq!(http(http_addr, "DELETE", "/v1/keys/key1", &[], "").status, 204);
+    assert_eq!(http(http_addr, "DELETE", "/v1/keys/key1", &[], "").status, 404);
+
+    assert_eq!(http(http_addr, "PUT", "/v1/keys/key1", &[], "not json").status, 400);
+    assert_eq!(http(http_addr, "POST", "/v1/keys/key1", &[], "").status, 405);
+    assert_eq!(http(http_addr, "GET", "/v2/keys/key1", &[], "").status, 404);
+
+    Ok(())
+}
+
+fn reply_etag(addr: SocketAddr, key: &str) -> String {
+    http(addr, "GET", &format!("/v1/keys/{}", key), &[], "").etag.unwrap()
+}
+
+#[test]
+fn http_request_too_large() {
+    let http_addr: SocketAddr = "127.0.0.1:4061".parse().unwrap();
+    let engine: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    let mut gateway = HttpGateway::new(http_addr, engine.clone());
+    gateway.set_max_request_size(32);
+    thread::spawn(move || gateway.handle_connection().unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    let body = format!(r#"{{"value":"{}"}}"#, "x".repeat(64));
+    assert_eq!(http(http_addr, "PUT", "/v1/keys/key1", &[], &body).status, 413);
+    assert_eq!(engine.get("key1").unwrap(), None);
+    assert_eq!(http(http_addr, "PUT", "/v1/keys/key1", &[], r#"{"value":"value1"}"#).status, 201);
+}
+
+#[test]
+fn http_rate_limit_and_queue() {
+    let addr: SocketAddr = "127.0.0.1:4074".parse().unwrap();
+    let mut gateway = HttpGateway::new(addr, Arc::new(MemoryKvsEngine::new()));
+    gateway.set_rate_limit(RateLimit { rate: 1.0, burst: 2 });
+    thread::spawn(move || gateway.handle_connection().unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(http(addr, "GET", "/v1/keys/key1", &[], "").status, 404);
+    assert_eq!(http(addr, "GET", "/v1/keys/key1", &[], "").status, 404);
+    let reply = http(addr, "GET", "/v1/keys/key1", &[], "");
+    assert_eq!(reply.status, 429);
+    assert!(json(&reply)["error"].is_string());
+
+    // Requests are all refused without a queue
+    let addr: SocketAddr = "127.0.0.1:4075".parse().unwrap();
+    let mut gateway = HttpGateway::new(addr, Arc::new(MemoryKvsEngine::new()));
+    gateway.set_max_queued(0);
+    thread::spawn(move || gateway.handle_connection().unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(http(addr, "GET", "/v1/keys/key1", &[], "").status, 503);
+}
+
+#[test]
+fn http_metrics_and_slow_log() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4076".parse().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let slow_log = Arc::new(SlowLog::new(Duration::ZERO, 8));
+    let mut gateway = HttpGateway::new(addr, Arc::new(MemoryKvsEngine::new()));
+    gateway.set_metrics(metrics.clone());
+    gateway.set_slow_log(slow_log.clone());
+    thread::spawn(move || gateway.handle_connection().unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(http(addr, "PUT", "/v1/keys/key1", &[], r#"{"value":"value1"}"#).status, 201);
+    assert_eq!(http(addr, "GET", "/v1/keys/key1", &[], "").status, 200);
+    assert_eq!(http(addr, "GET", "/v1/keys/key2", &[], "").status, 404);
+    assert_eq!(http(addr, "DELETE", "/v1/keys/key1", &[], "").status, 204);
+    assert_eq!(http(addr, "GET", "/v1/keys", &[], "").status, 200);
+    assert_eq!(http(addr, "PUT", "/v1/keys/key1", &[], "{").status, 400);
+    assert_eq!(http(addr, "POST", "/v1/keys/key1", &[], "").status, 405);
+
+    let text = metrics.render(None)?;
+    assert!(text.contains("kvs_requests_total{type=\"set\"} 2"), "{}", text);
+    assert!(text.contains("kvs_requests_total{type=\"get\"} 2"), "{}", text);
+    assert!(text.contains("kvs_requests_total{type=\"rm\"} 1"), "{}", text);
+    assert!(text.contains("kvs_requests_total{type=\"scan\"} 1"), "{}", text);
+    assert!(text.contains("kvs_errors_total{code=\"Serialization\"} 1"), "{}", text);
+    assert!(text.contains("kvs_errors_total{code=\"Unsupported\"} 1"), "{}", text);
+    assert!(!text.contains("KeyNotFound"), "{}", text);
+    assert!(text.contains("kvs_queued_jobs{pool=\"http\"} 0"), "{}", text);
+
+    let entries = slow_log.entries();
+    assert_eq!(entries.len(), 6);
+    assert_eq!(entries[5].op, "set");
+    assert_eq!(entries[5].key, Some("key1".to_owned()));
+    assert_eq!(entries[5].value_size, Some(6));
+    assert_eq!(entries[5].client, Some(IpAddr::from([127, 0, 0, 1])));
+    assert_eq!(entries[4].op, "get");
+    assert_eq!(entries[4].value_size, Some(6));
+    assert_eq!(entries[3].value_size, None);
+    assert_eq!(entries[1].op, "scan");
+    assert_eq!(entries[1].key, None);
+
+    Ok(())
+}
@@ -4,7 +4,7 @@ use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::{KvsEngine, KvStore, Result};
+use kvs::{CompactionPolicy, KvsEngine, KvStore, Result};
 
 // Should get previously stored value
 #[test]
@@ -83,6 +83,24 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should list keys with a prefix in order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in ["key2", "other", "key1", "key10"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("key10".to_owned())?;
+
+    assert_eq!(store.scan_prefix("key".to_owned())?, vec!["key1".to_owned(), "key2".to_owned()]);
+    assert_eq!(store.scan_prefix("".to_owned())?.len(), 3);
+    assert!(store.scan_prefix("missing".to_owned())?.is_empty());
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -129,6 +147,95 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Stats should follow the keys and the compactions of the store
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy { min_dead_ratio: 0.5, min_dead_bytes: 4096 };
+    let store = KvStore::open_with_policy(temp_dir.path(), policy)?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, Some(0));
+    assert_eq!(stats.compactions, 0);
+
+    for iter in 0..200 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, Some(2));
+    assert!(stats.compactions >= 1);
+    assert!(stats.reclaimed_bytes > 0);
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.since_last_compact_log_num.unwrap() < 200);
+    assert!(stats.dead_bytes.unwrap() < 4096);
+    assert_eq!(stats.live_bytes.unwrap() + stats.dead_bytes.unwrap(), stats.size_bytes.unwrap());
+    assert!(stats.files.contains_key("kvs_log_entry"));
+
+    Ok(())
+}
+
+// The store should not compact before its policy allows it
+#[test]
+fn compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy { min_dead_ratio: 0.8, min_dead_bytes: 1 };
+    let store = KvStore::open_with_policy(temp_dir.path(), policy)?;
+
+    // Half of the log is dead
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.dead_bytes.unwrap() > 0);
+
+    // Five sixths of it
+    for _ in 0..4 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.keys, Some(100));
+
+    Ok(())
+}
+
+// A compaction on demand should free the dead bytes and keep the values
+#[test]
+fn compact_now() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let dead_bytes = store.stats()?.dead_bytes.unwrap();
+    assert!(dead_bytes > 0);
+
+    let reclaimed = store.compact()?;
+    assert!(reclaimed > 0);
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.reclaimed_bytes, reclaimed);
+    assert_eq!(stats.dead_bytes, Some(0));
+    assert_eq!(store.compact()?, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -0,0 +1,251 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{AsyncKvsEngine, AsyncKvsServer, BINARY_HANDSHAKE, ConnectionLimits, ErrorCode, KvsClient, KvsError, KvsServer, MemoryKvsEngine, PoolKind, Protocol, RateLimit, RejectReason, Request, Response};
+
+fn assert_rejected<T: std::fmt::Debug>(result: kvs::Result<T>) {
+    match result {
+        Err(KvsError::ServerError { code, message }) => {
+            assert_eq!(code, ErrorCode::Rejected);
+            assert!(message.contains("connection rejected"), "{}", message);
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+// The server answers with a bare response and closes the connection
+fn read_rejection(mut stream: TcpStream) -> Response {
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).unwrap();
+    serde_json::from_slice(&reply).unwrap()
+}
+
+#[test]
+fn server_max_connections() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4033".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_thread_pool(PoolKind::SharedQueue, 2);
+    server.set_limits(ConnectionLimits { max_connections: Some(1), ..ConnectionLimits::default() });
+    let rejections = server.rejections();
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    assert_rejected(KvsClient::new(addr).get("key1"));
+    assert_rejected(KvsClient::with_protocol(addr, Protocol::Binary).get("key1"));
+    assert_eq!(rejections.count(RejectReason::TooManyConnections), 2);
+
+    drop(client);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(KvsClient::new(addr).get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn server_timeouts_and_request_size() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4034".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_limits(ConnectionLimits {
+        idle_timeout: Some(Duration::from_secs(1)),
+        read_timeout: Some(Duration::from_secs(1)),
+        max_request_size: 1024,
+        ..ConnectionLimits::default()
+    });
+    let rejections = server.rejections();
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    // A connection that never sends anything does not keep the only worker
+    let idle = TcpStream::connect(addr)?;
+    let response = read_rejection(idle);
+    assert!(matches!(response, Response::Error { code: ErrorCode::Rejected, .. }), "{:?}", response);
+    assert_eq!(rejections.count(RejectReason::IdleTimeout), 1);
+
+    let mut slow = TcpStream::connect(addr)?;
+    slow.write_all(br#"{"Get": "#)?;
+    let response = read_rejection(slow);
+    assert!(matches!(response, Response::Error { code: ErrorCode::Rejected, .. }), "{:?}", response);
+    assert_eq!(rejections.count(RejectReason::ReadTimeout), 1);
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    // The rejection answers the request, so the client doesn't send it again
+    assert_rejected(client.set("key2", &"v".repeat(2048)));
+    assert_eq!(rejections.count(RejectReason::RequestTooLarge), 1);
+    // Requests after an idle period are served on a new connection
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn server_write_timeout() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4035".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_limits(ConnectionLimits { write_timeout: Some(Duration::from_secs(1)), ..ConnectionLimits::default() });
+    let rejections = server.rejections();
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("large", &"v".repeat(1024 * 1024))?;
+    drop(client);
+
+    // Ask for far more than the socket buffers hold, and never read the responses
+    let mut stream = TcpStream::connect(addr)?;
+    let request = serde_json::to_vec(&Request::Get { key: "large".to_owned() })?;
+    for _ in 0..64 {
+        if stream.write_all(&request).is_err() {
+            break;
+        }
+    }
+    // The client keeps accepting a little for a while
+    for _ in 0..20 {
+        if rejections.count(RejectReason::WriteTimeout) > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    assert_eq!(rejections.count(RejectReason::WriteTimeout), 1);
+    drop(stream);
+
+    assert!(KvsClient::new(addr).get("large")?.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn async_server_limits() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4036".parse().unwrap();
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    let mut server = AsyncKvsServer::new(addr, engine);
+    server.set_limits(ConnectionLimits {
+        max_connections: Some(1),
+        idle_timeout: Some(Duration::from_secs(1)),
+        max_request_size: 1024,
+        ..ConnectionLimits::default()
+    });
+    let rejections = server.rejections();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    assert_rejected(KvsClient::with_protocol(addr, Protocol::Binary).get("key1"));
+    assert_eq!(rejections.count(RejectReason::TooManyConnections), 1);
+    // The rejection answers the request, so the client doesn't send it again
+    assert_rejected(client.set("key2", &"v".repeat(2048)));
+    assert_eq!(rejections.count(RejectReason::RequestTooLarge), 1);
+
+    // The rejected connection keeps its slot until the server is done lingering on it
+    thread::sleep(Duration::from_millis(600));
+    let idle = TcpStream::connect(addr)?;
+    let response = read_rejection(idle);
+    assert!(matches!(response, Response::Error { code: ErrorCode::Rejected, .. }), "{:?}", response);
+    assert_eq!(rejections.count(RejectReason::IdleTimeout), 1);
+
+    Ok(())
+}
+
+#[test]
+fn server_rate_limit() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4037".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_rate_limit(RateLimit { rate: 1.0, burst: 3 });
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    let requests = (0..5).map(|i| Request::Get { key: format!("key{}", i) }).collect();
+    let responses = client.pipeline(requests)?;
+    assert!(responses[..3].iter().all(|response| *response == Response::NotFound), "{:?}", responses);
+    for response in &responses[3..] {
+        assert!(matches!(response, Response::Error { code: ErrorCode::RateLimited, .. }), "{:?}", response);
+    }
+
+    // The bucket refills over time
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.get("key1")?, None);
+    assert!(matches!(client.get("key1"), Err(KvsError::ServerError { code: ErrorCode::RateLimited, .. })));
+
+    Ok(())
+}
+
+#[test]
+fn async_server_rate_limit_and_queue() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4038".parse().unwrap();
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    let mut server = AsyncKvsServer::new(addr, engine);
+    server.set_rate_limit(RateLimit { rate: 1.0, burst: 2 });
+    server.set_max_queued(0);
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // Requests in envelopes are all refused without a queue, bare ones are answered in order
+    let client = KvsClient::new(addr);
+    assert!(matches!(client.get("key1"), Err(KvsError::ServerError { code: ErrorCode::Busy, .. })));
+    let mut stream = TcpStream::connect(addr)?;
+    for _ in 0..2 {
+        stream.write_all(&serde_json::to_vec(&Request::Get { key: "key1".to_owned() })?)?;
+    }
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    let responses: Vec<Response> = serde_json::Deserializer::from_slice(&reply).into_iter().collect::<serde_json::Result<_>>()?;
+    assert_eq!(responses[0], Response::NotFound);
+    assert!(matches!(responses[1], Response::Error { code: ErrorCode::RateLimited, .. }), "{:?}", responses);
+
+    Ok(())
+}
+
+// Sends a binary frame header announcing more than the limit, and waits for the close
+fn send_large_frame_header(addr: SocketAddr) -> kvs::Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[BINARY_HANDSHAKE])?;
+    stream.write_all(&(32 * 1024 * 1024u32).to_be_bytes())?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    assert_eq!(reply, [BINARY_HANDSHAKE]);
+    Ok(())
+}
+
+// An announced frame length over the limit is rejected before its payload is read
+#[test]
+fn frame_length_over_request_size() -> kvs::Result<()> {
+    let limits = ConnectionLimits { max_request_size: 1024, ..ConnectionLimits::default() };
+    let addr: SocketAddr = "127.0.0.1:4083".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_limits(limits.clone());
+    let rejections = server.rejections();
+    thread::spawn(move || server.handle_connection());
+
+    let async_addr: SocketAddr = "127.0.0.1:4084".parse().unwrap();
+    let mut async_server = AsyncKvsServer::new(async_addr, AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4));
+    async_server.set_limits(limits);
+    let async_rejections = async_server.rejections();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async_server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    send_large_frame_header(addr)?;
+    assert_eq!(rejections.count(RejectReason::RequestTooLarge), 1);
+    send_large_frame_header(async_addr)?;
+    assert_eq!(async_rejections.count(RejectReason::RequestTooLarge), 1);
+
+    Ok(())
+}
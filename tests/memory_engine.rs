@@ -0,0 +1,122 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, MemoryKvsEngine, Result};
+
+// Should get previously stored value
+#[test]
+fn get_stored_value() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+#[test]
+fn overwrite_value() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+#[test]
+fn get_non_existent_value() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should list keys with a prefix in order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemoryKvsEngine::open(temp_dir.path())?;
+
+    for key in ["key2", "other", "key1", "key10"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("key10".to_owned())?;
+
+    assert_eq!(store.scan_prefix("key".to_owned())?, vec!["key1".to_owned(), "key2".to_owned()]);
+    assert_eq!(store.scan_prefix("".to_owned())?.len(), 3);
+    assert!(store.scan_prefix("missing".to_owned())?.is_empty());
+
+    Ok(())
+}
+
+// Data should survive a reopen only when the engine has a snapshot directory
+#[test]
+fn snapshot_to_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemoryKvsEngine::open(temp_dir.path())?;
+    let cloned_store = store.clone();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    // Snapshot is written when the last handle is dropped
+    drop(store);
+    drop(cloned_store);
+    let store = MemoryKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{AsyncKvsEngine, AsyncKvsServer, KvsClient, KvsServer, MemoryKvsEngine, Metrics, MetricsEndpoint, PoolKind, Request, RequestEnvelope, ResponseEnvelope, ServerProtocol, SharedEngine};
+
+fn scrape(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    reply
+}
+
+#[test]
+fn server_metrics() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4039".parse().unwrap();
+    let metrics_addr: SocketAddr = "127.0.0.1:4040".parse().unwrap();
+    let engine: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    let mut server = KvsServer::new(addr, engine.clone());
+    server.set_thread_pool(PoolKind::SharedQueue, 2);
+    let metrics = server.metrics();
+    let mut endpoint = MetricsEndpoint::new(metrics_addr, metrics.clone());
+    endpoint.set_engine(engine);
+    thread::spawn(move || server.handle_connection());
+    thread::spawn(move || endpoint.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    client.set("key2", "value2")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    assert!(client.remove("key3").is_err());
+    // A client speaking a newer protocol gets an error
+    let mut stream = TcpStream::connect(addr)?;
+    let envelope = RequestEnvelope { version: 99, id: 1, request: Request::Get { key: "key2".to_owned() } };
+    stream.write_all(&serde_json::to_vec(&envelope)?)?;
+    let mut reply = serde_json::Deserializer::from_reader(&stream).into_iter::<ResponseEnvelope>();
+    assert!(reply.next().unwrap()?.response.error_code().is_some());
+
+    let text = metrics.render(None)?;
+    assert!(text.contains("kvs_requests_total{type=\"set\"} 2"), "{}", text);
+    assert!(text.contains("kvs_requests_total{type=\"get\"} 2"), "{}", text);
+    assert!(text.contains("kvs_requests_total{type=\"rm\"} 1"), "{}", text);
+    assert!(text.contains("kvs_request_duration_seconds_count{type=\"set\"} 2"), "{}", text);
+    assert!(text.contains("kvs_request_duration_seconds_bucket{type=\"get\",le=\"+Inf\"} 2"), "{}", text);
+    assert!(text.contains("kvs_errors_total{code=\"Protocol\"} 1"), "{}", text);
+    assert!(text.contains("kvs_connections_active 2"), "{}", text);
+    assert!(text.contains("kvs_rejected_connections_total{reason=\"too_many_connections\"} 0"), "{}", text);
+    assert!(text.contains("kvs_queued_jobs{pool=\"requests\"} 0"), "{}", text);
+    assert!(!text.contains("kvs_engine_keys"), "{}", text);
+
+    let reply = scrape(metrics_addr, "/metrics");
+    assert!(reply.starts_with("HTTP/1.1 200"), "{}", reply);
+    assert!(reply.contains("text/plain; version=0.0.4"), "{}", reply);
+    assert!(reply.contains("kvs_engine_keys 2"), "{}", reply);
+    assert!(reply.contains("kvs_compactions_total 0"), "{}", reply);
+    assert!(scrape(metrics_addr, "/other").starts_with("HTTP/1.1 404"));
+
+    drop(client);
+    drop(stream);
+    thread::sleep(Duration::from_millis(500));
+    assert!(metrics.render(None)?.contains("kvs_connections_active 0"));
+
+    Ok(())
+}
+
+#[test]
+fn async_server_shared_metrics() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4041".parse().unwrap();
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    let mut server = AsyncKvsServer::new(addr, engine);
+    let metrics = Arc::new(Metrics::new());
+    server.set_metrics(metrics.clone());
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    assert_eq!(client.get("key2")?, None);
+
+    let text = metrics.render(None)?;
+    assert!(text.contains("kvs_requests_total{type=\"set\"} 1"), "{}", text);
+    assert!(text.contains("kvs_requests_total{type=\"get\"} 1"), "{}", text);
+    assert!(text.contains("kvs_connections_active 1"), "{}", text);
+
+    Ok(())
+}
+
+// RESP errors are counted with the code of their error
+#[test]
+fn resp_error_codes() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4085".parse().unwrap();
+    let mut server = KvsServer::with_protocol(addr, Arc::new(MemoryKvsEngine::new()), ServerProtocol::Resp);
+    let metrics = server.metrics();
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"SELECT missing\r\nFLUSHALL\r\nGET\r\n")?;
+    let mut reply = Vec::new();
+    while reply.iter().filter(|b| **b == b'\n').count() < 3 {
+        let mut buf = [0; 256];
+        let len = stream.read(&mut buf)?;
+        reply.extend_from_slice(&buf[..len]);
+    }
+
+    let text = metrics.render(None)?;
+    assert!(text.contains("kvs_errors_total{code=\"UnknownDatabase\"} 1"), "{}", text);
+    assert!(text.contains("kvs_errors_total{code=\"Unsupported\"} 1"), "{}", text);
+    assert!(text.contains("kvs_errors_total{code=\"Protocol\"} 1"), "{}", text);
+    assert!(!text.contains("Unknown\""), "{}", text);
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{Acl, KvsClient, KvsServer, MemoryKvsEngine, RateLimit, RateLimiter, ReloadHandler, ReloadReport, SlowLog};
+
+#[test]
+fn server_reload() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4056".parse().unwrap();
+    let acl = Arc::new(Acl::parse(r#"{"users": {"admin": {"password": "old", "grants": [{"prefix": "", "access": ["admin"]}]}}}"#)?);
+    let rate_limiter = Arc::new(RateLimiter::new(None));
+    let slow_log = Arc::new(SlowLog::new(Duration::from_secs(1), 8));
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_acl(acl.clone());
+    server.set_rate_limiter(rate_limiter.clone());
+    server.set_slow_log(slow_log.clone());
+    let (reload_limiter, reload_log) = (rate_limiter.clone(), slow_log.clone());
+    let reload: ReloadHandler = Arc::new(move || {
+        acl.replace(Acl::parse(r#"{"users": {"admin": {"password": "new", "grants": [{"prefix": "", "access": ["admin"]}]}}}"#)?);
+        reload_limiter.set_limit(Some(RateLimit::per_second(100)));
+        reload_log.set_threshold(Duration::ZERO);
+        Ok(ReloadReport { applied: vec!["acl".to_owned()], restart_required: vec!["listen.addr".to_owned()] })
+    });
+    server.set_reload_handler(reload);
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(addr);
+    client.set_auth(None, "old");
+    let report = client.reload()?;
+    assert_eq!(report.applied, vec!["acl".to_owned()]);
+    assert_eq!(report.restart_required, vec!["listen.addr".to_owned()]);
+    assert_eq!(rate_limiter.limit(), Some(RateLimit::per_second(100)));
+    // The connection logged in with the old password is closed
+    assert!(client.get("key1").is_err());
+    drop(client);
+
+    // New connections log in with the reloaded ACL
+    let mut client = KvsClient::new(addr);
+    client.set_auth(None, "old");
+    assert!(client.get("key1").is_err());
+    let mut client = KvsClient::new(addr);
+    client.set_auth(None, "new");
+    assert_eq!(client.get("key1")?, None);
+    assert!(!slow_log.entries().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn server_reload_without_handler() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4057".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    assert!(KvsClient::new(addr).reload().is_err());
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{AsyncKvsEngine, AsyncKvsServer, KvsServer, MemoryKvsEngine, ServerProtocol};
+
+fn encode_command(command: &[&str]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", command.len()).into_bytes();
+    for arg in command {
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    buf
+}
+
+fn assert_reply(stream: &mut TcpStream, command: &[&str], expected: &str) {
+    stream.write_all(&encode_command(command)).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected, "reply to {:?}", command);
+}
+
+fn check_commands(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_reply(&mut stream, &["PING"], "+PONG\r\n");
+    assert_reply(&mut stream, &["ping", "hello"], "$5\r\nhello\r\n");
+
+    assert_reply(&mut stream, &["GET", "key1"], "$-1\r\n");
+    assert_reply(&mut stream, &["SET", "key1", "value1"], "+OK\r\n");
+    assert_reply(&mut stream, &["GET", "key1"], "$6\r\nvalue1\r\n");
+    assert_reply(&mut stream, &["SET", "key1", "value2", "NX"], "$-1\r\n");
+    assert_reply(&mut stream, &["SET", "key2", "value2", "XX"], "$-1\r\n");
+    assert_reply(&mut stream, &["SET", "key2", "value2", "NX"], "+OK\r\n");
+    assert_reply(&mut stream, &["SET", "key2", "value3", "XX"], "+OK\r\n");
+    assert_reply(&mut stream, &["SET", "key2", "value3", "EX"], "-ERR invalid expire time in 'set' command\r\n");
+
+    assert_reply(&mut stream, &["MSET", "key3", "value3", "key4", "value4"], "+OK\r\n");
+    assert_reply(&mut stream, &["MGET", "key3", "missing", "key4"], "*3\r\n$6\r\nvalue3\r\n$-1\r\n$6\r\nvalue4\r\n");
+    assert_reply(&mut stream, &["EXISTS", "key1", "missing", "key3"], ":2\r\n");
+    assert_reply(&mut stream, &["DEL", "key3", "key4", "missing"], ":2\r\n");
+    assert_reply(&mut stream, &["EXISTS", "key3"], ":0\r\n");
+
+    assert_reply(&mut stream, &["INCR", "counter"], ":1\r\n");
+    assert_reply(&mut stream, &["INCR", "counter"], ":2\r\n");
+    assert_reply(&mut stream, &["INCR", "key1"], "-ERR value is not an integer or out of range\r\n");
+
+    assert_reply(&mut stream, &["SCAN", "0", "COUNT", "2"], "*2\r\n$1\r\n2\r\n*2\r\n$7\r\ncounter\r\n$4\r\nkey1\r\n");
+    assert_reply(&mut stream, &["SCAN", "2", "COUNT", "2"], "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey2\r\n");
+    assert_reply(&mut stream, &["SCAN", "0", "MATCH", "key*"], "*2\r\n$1\r\n0\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n");
+    assert_reply(&mut stream, &["SCAN", "0", "MATCH", "k?y[^2]"], "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey1\r\n");
+    assert_reply(&mut stream, &["SCAN", "0", "MATCH", "*o*t*r"], "*2\r\n$1\r\n0\r\n*1\r\n$7\r\ncounter\r\n");
+    assert_reply(&mut stream, &["SCAN", "0", "MATCH", "\\k*[2-9]"], "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey2\r\n");
+
+    assert_reply(&mut stream, &["SET", "temp", "value", "PX", "100"], "+OK\r\n");
+    assert_reply(&mut stream, &["EXISTS", "temp"], ":1\r\n");
+    thread::sleep(Duration::from_millis(200));
+    assert_reply(&mut stream, &["GET", "temp"], "$-1\r\n");
+
+    assert_reply(&mut stream, &["GET"], "-ERR wrong number of arguments for 'get' command\r\n");
+    assert_reply(&mut stream, &["FLUSHALL"], "-ERR unknown command 'FLUSHALL'\r\n");
+
+    stream.write_all(&encode_command(&["INFO"])).unwrap();
+    let mut reply = [0; 256];
+    let len = stream.read(&mut reply).unwrap();
+    assert!(String::from_utf8_lossy(&reply[..len]).contains("db0:keys=3"));
+
+    // Inline commands and pipelined commands
+    stream.write_all(b"PING\r\nGET key1\r\n").unwrap();
+    let expected = "+PONG\r\n$6\r\nvalue1\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+}
+
+#[test]
+fn resp_commands() {
+    let addr: SocketAddr = "127.0.0.1:4018".parse().unwrap();
+    thread::spawn(move || {
+        let mut server = KvsServer::with_protocol(addr, Arc::new(MemoryKvsEngine::new()), ServerProtocol::Resp);
+        server.handle_connection().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    check_commands(addr);
+}
+
+#[test]
+fn async_resp_commands() {
+    let addr: SocketAddr = "127.0.0.1:4019".parse().unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+        let mut server = AsyncKvsServer::with_protocol(addr, engine, ServerProtocol::Resp);
+        runtime.block_on(server.handle_connection()).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    check_commands(addr);
+}
+
+// A pattern with many stars must not take exponential time
+#[test]
+fn resp_scan_many_stars() {
+    let addr: SocketAddr = "127.0.0.1:4060".parse().unwrap();
+    thread::spawn(move || {
+        let mut server = KvsServer::with_protocol(addr, Arc::new(MemoryKvsEngine::new()), ServerProtocol::Resp);
+        server.handle_connection().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let key = "a".repeat(100);
+    assert_reply(&mut stream, &["SET", &key, "value"], "+OK\r\n");
+    let pattern = format!("{}b", "a*".repeat(20));
+    assert_reply(&mut stream, &["SCAN", "0", "MATCH", &pattern], "*2\r\n$1\r\n0\r\n*0\r\n");
+}
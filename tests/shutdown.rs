@@ -0,0 +1,210 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assert_cmd::prelude::*;
+use tempfile::TempDir;
+
+use kvs::{AsyncKvsEngine, AsyncKvsServer, HttpGateway, KvsClient, KvsEngine, KvsError, KvsServer, MemoryKvsEngine, Metrics, MetricsEndpoint, Request, Result};
+
+#[test]
+fn shutdown_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = MemoryKvsEngine::open(temp_dir.path())?;
+    let addr: SocketAddr = "127.0.0.1:4030".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(engine.clone()));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    // The idle connection kept by the client must not hold the server
+    let client = KvsClient::new(addr);
+    let requests = (0..100)
+        .map(|i| Request::Set { key: format!("key{}", i), value: format!("value{}", i) })
+        .collect();
+    assert_eq!(client.pipeline(requests)?.len(), 100);
+
+    let start = Instant::now();
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(handle.is_shutdown());
+    assert!(KvsClient::new(addr).get("key1").is_err());
+
+    // The engine was flushed while this handle still keeps it open
+    let reopened = MemoryKvsEngine::open(temp_dir.path())?;
+    assert_eq!(kvs::KvsEngine::get(&reopened, "key99".to_owned())?, Some("value99".to_owned()));
+    drop(engine);
+
+    Ok(())
+}
+
+#[test]
+fn shutdown_before_serve() {
+    let addr: SocketAddr = "127.0.0.1:4062".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.shutdown_handle().shutdown();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(server.handle_connection()).unwrap());
+    assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap().is_ok());
+
+    let addr: SocketAddr = "127.0.0.1:4063".parse().unwrap();
+    let mut server = AsyncKvsServer::new(addr, AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4));
+    server.shutdown_handle().shutdown();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        sender.send(runtime.block_on(server.handle_connection())).unwrap();
+    });
+    assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap().is_ok());
+}
+
+// Counts its flushes, which all fail
+#[derive(Clone, Default)]
+struct FailingFlush {
+    flushes: Arc<AtomicUsize>,
+}
+
+impl KvsEngine for FailingFlush {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&self, _key: String) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        Err(KvsError::KeyNotFound(key))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        Err(KvsError::ServerBusy)
+    }
+}
+
+// A failed flush is returned once every database was flushed
+#[test]
+fn shutdown_flush_error() {
+    let engine = FailingFlush::default();
+    let mut server = KvsServer::new("127.0.0.1:4078".parse::<SocketAddr>().unwrap(), Arc::new(engine.clone()));
+    server.add_database("other", Arc::new(engine.clone()));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+    handle.shutdown();
+    assert!(server_thread.join().unwrap().is_err());
+    assert_eq!(engine.flushes.load(Ordering::SeqCst), 2);
+
+    let engine = FailingFlush::default();
+    let async_engine = AsyncKvsEngine::new(Arc::new(engine.clone()), 4);
+    let mut server = AsyncKvsServer::new("127.0.0.1:4079".parse::<SocketAddr>().unwrap(), async_engine);
+    server.add_database("other", Arc::new(engine.clone()));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+    handle.shutdown();
+    assert!(server_thread.join().unwrap().is_err());
+    assert_eq!(engine.flushes.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn shutdown_http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = MemoryKvsEngine::open(temp_dir.path())?;
+    let addr: SocketAddr = "127.0.0.1:4070".parse().unwrap();
+    let mut gateway = HttpGateway::new(addr, Arc::new(engine.clone()));
+    let handle = gateway.shutdown_handle();
+    let gateway_thread = thread::spawn(move || gateway.handle_connection());
+    let metrics_addr: SocketAddr = "127.0.0.1:4071".parse().unwrap();
+    let mut endpoint = MetricsEndpoint::new(metrics_addr, Arc::new(Metrics::new()));
+    let metrics_handle = endpoint.shutdown_handle();
+    let metrics_thread = thread::spawn(move || endpoint.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let body = r#"{"value":"value1"}"#;
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "PUT /v1/keys/key1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert!(reply.starts_with("HTTP/1.1 201"), "{}", reply);
+
+    let start = Instant::now();
+    handle.shutdown();
+    metrics_handle.shutdown();
+    gateway_thread.join().unwrap()?;
+    metrics_thread.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // The listener is closed by an accept thread of tiny_http, shortly after the gateway returns
+    assert!((0..10).any(|_| {
+        thread::sleep(Duration::from_millis(100));
+        TcpStream::connect(addr).is_err()
+    }));
+
+    // The gateway flushed the engine before returning
+    let reopened = MemoryKvsEngine::open(temp_dir.path())?;
+    assert_eq!(kvs::KvsEngine::get(&reopened, "key1".to_owned())?, Some("value1".to_owned()));
+    drop(engine);
+
+    Ok(())
+}
+
+#[test]
+fn shutdown_async_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    let mut server = AsyncKvsServer::new(path.clone(), engine);
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::connect_unix(&path);
+    client.set("key1", "value1")?;
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    assert!(client.get("key1").is_err());
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn cli_shutdown_on_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4031";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--engine-opt", "snapshot=true", "--addr", addr])
+        .args(["--http", "127.0.0.1:4072", "--metrics", "127.0.0.1:4073"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    let status = child.wait().expect("unable to wait for server");
+    assert!(status.success());
+    assert!(temp_dir.path().join("memory_snapshot").exists());
+}
@@ -0,0 +1,101 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{Acl, AsyncKvsEngine, AsyncKvsServer, KvsClient, KvsServer, MemoryKvsEngine, ServerProtocol, SharedEngine, SlowLog};
+
+#[test]
+fn server_slow_log() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4044".parse().unwrap();
+    let resp_addr: SocketAddr = "127.0.0.1:4045".parse().unwrap();
+    let engine: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    // Every request is slow, and the last two are kept
+    let slow_log = Arc::new(SlowLog::new(Duration::ZERO, 2));
+    let mut server = KvsServer::new(addr, engine.clone());
+    server.set_slow_log(slow_log.clone());
+    let mut resp_server = KvsServer::with_protocol(resp_addr, engine, ServerProtocol::Resp);
+    resp_server.set_slow_log(slow_log.clone());
+    thread::spawn(move || server.handle_connection());
+    thread::spawn(move || resp_server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    client.remove("key1")?;
+    let entries = client.slow_log()?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].op, "rm");
+    assert_eq!(entries[1].op, "get");
+    assert_eq!(entries[1].key, Some("key1".to_owned()));
+    assert_eq!(entries[1].value_size, Some(6));
+    assert_eq!(entries[1].client, Some(IpAddr::from([127, 0, 0, 1])));
+    assert!(entries[0].time >= entries[1].time);
+
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$7\r\nvalue22\r\n")?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply)?;
+    let entries = slow_log.entries();
+    assert_eq!(entries[0].op, "set");
+    assert_eq!(entries[0].key, Some("key2".to_owned()));
+    assert_eq!(entries[0].value_size, Some(7));
+    // The request for the slow log is slow as well
+    assert_eq!(entries[1].op, "slowlog");
+
+    Ok(())
+}
+
+#[test]
+fn resp_slow_auth() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4066".parse().unwrap();
+    let acl = Acl::parse(r#"{"users": {"admin": {"password": "secret", "grants": [{"prefix": "", "access": ["read", "write"]}]}}}"#)?;
+    let slow_log = Arc::new(SlowLog::new(Duration::ZERO, 8));
+    let mut server = KvsServer::with_protocol(addr, Arc::new(MemoryKvsEngine::new()), ServerProtocol::Resp);
+    server.set_acl(Arc::new(acl));
+    server.set_slow_log(slow_log.clone());
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    // With and without the user name
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$4\r\nAUTH\r\n$5\r\nadmin\r\n$6\r\nsecret\r\n")?;
+    stream.write_all(b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n")?;
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"+OK\r\n+OK\r\n");
+    let entries = slow_log.entries();
+    assert_eq!(entries.len(), 2);
+    for entry in entries.iter() {
+        assert_eq!(entry.op, "auth");
+        assert_eq!(entry.key, None);
+        assert_eq!(entry.value_size, None);
+    }
+    assert!(!format!("{:?}", entries).contains("secret"));
+
+    Ok(())
+}
+
+#[test]
+fn async_server_slow_log_threshold() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4046".parse().unwrap();
+    let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+    let mut server = AsyncKvsServer::new(addr, engine);
+    let slow_log = Arc::new(SlowLog::new(Duration::from_secs(60), 8));
+    server.set_slow_log(slow_log.clone());
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection())
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    assert!(client.slow_log()?.is_empty());
+    assert_eq!(slow_log.threshold(), Duration::from_secs(60));
+
+    Ok(())
+}
@@ -64,7 +64,41 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn any_thread_pool_spawn_counter() -> Result<()> {
+    for kind in ["naive", "shared", "rayon"] {
+        let pool = AnyThreadPool::with_kind(kind.parse()?, 4)?;
+        spawn_counter(pool)?;
+    }
+    assert!("fifo".parse::<PoolKind>().is_err());
+    Ok(())
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn bounded_thread_pool_refuses_when_full() -> Result<()> {
+    let pool = BoundedThreadPool::new(SharedQueueThreadPool::new(1)?, 1);
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    assert!(pool.try_spawn(move || {
+        started_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+    }));
+    started_rx.recv().unwrap();
+
+    // The only thread is busy, so one job waits and the next one is refused
+    let wg = WaitGroup::new();
+    let waiting = wg.clone();
+    assert!(pool.try_spawn(move || drop(waiting)));
+    assert!(!pool.try_spawn(|| {}));
+    assert_eq!(pool.waiting(), 1);
+
+    release_tx.send(()).unwrap();
+    wg.wait();
+    assert!(pool.try_spawn(|| {}));
+    Ok(())
+}
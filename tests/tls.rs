@@ -0,0 +1,221 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::iter;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assert_cmd::prelude::*;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use tempfile::TempDir;
+
+use kvs::{AsyncKvsEngine, AsyncKvsServer, ConnectionLimits, KvsClient, KvsServer, MemoryKvsEngine, Protocol, RejectReason, Result, tls};
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(dir: &Path, name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        Ca { cert, key }
+    }
+
+    // Writes `{name}.pem` and `{name}.key` for a certificate valid for `names`.
+    fn issue(&self, dir: &Path, name: &str, names: &[&str]) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+#[test]
+fn tls_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = Ca::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost", "127.0.0.1"]);
+    Ca::new(dir, "other_ca");
+
+    let addr: SocketAddr = "127.0.0.1:4022".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_tls(tls::server_config(&cert, &key, None)?);
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(addr);
+    client.set_tls(tls::client_config(&dir.join("ca.pem"), None)?, "localhost")?;
+    client.set("key1", "value1")?;
+    let requests = (0..100).map(|i| kvs::Request::Get { key: format!("key{}", i) }).collect();
+    assert_eq!(client.pipeline(requests)?.len(), 100);
+    drop(client);
+
+    let mut client = KvsClient::with_protocol(addr, Protocol::Binary);
+    client.set_tls(tls::client_config(&dir.join("ca.pem"), None)?, "127.0.0.1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    drop(client);
+
+    // The certificate is not valid for this name
+    let mut client = KvsClient::new(addr);
+    client.set_tls(tls::client_config(&dir.join("ca.pem"), None)?, "example.com")?;
+    assert!(client.get("key1").is_err());
+    drop(client);
+
+    // The certificate is not signed by a trusted CA
+    let mut client = KvsClient::new(addr);
+    client.set_tls(tls::client_config(&dir.join("other_ca.pem"), None)?, "localhost")?;
+    assert!(client.get("key1").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mutual_tls() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = Ca::new(dir, "ca");
+    let (server_cert, server_key) = ca.issue(dir, "server", &["localhost"]);
+    let (client_cert, client_key) = ca.issue(dir, "client", &["client"]);
+    let other_ca = Ca::new(dir, "other_ca");
+    let (other_cert, other_key) = other_ca.issue(dir, "other_client", &["client"]);
+
+    let addr: SocketAddr = "127.0.0.1:4023".parse().unwrap();
+    let config = tls::server_config(&server_cert, &server_key, Some(&dir.join("ca.pem")))?;
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let engine = AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4);
+        let mut server = AsyncKvsServer::new(addr, engine);
+        server.set_tls(config);
+        runtime.block_on(server.handle_connection()).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let ca_path = dir.join("ca.pem");
+    let mut client = KvsClient::new(addr);
+    client.set_tls(tls::client_config(&ca_path, Some((&client_cert, &client_key)))?, "localhost")?;
+    client.set("key1", "value1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    let mut client = KvsClient::new(addr);
+    client.set_tls(tls::client_config(&ca_path, None)?, "localhost")?;
+    assert!(client.get("key1").is_err());
+
+    let mut client = KvsClient::new(addr);
+    client.set_tls(tls::client_config(&ca_path, Some((&other_cert, &other_key)))?, "localhost")?;
+    assert!(client.get("key1").is_err());
+
+    // Plain connections are refused as well
+    assert!(KvsClient::new(addr).get("key1").is_err());
+
+    Ok(())
+}
+
+// Sends the start of a ClientHello a byte at a time, returns how long the server kept the connection.
+fn trickle_handshake(addr: SocketAddr) -> Duration {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        for b in [0x16, 0x03, 0x01, 0x01, 0x00].into_iter().chain(iter::repeat(0)) {
+            if writer.write_all(&[b]).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    });
+    let start = Instant::now();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let _ = stream.read_to_end(&mut Vec::new());
+    start.elapsed()
+}
+
+#[test]
+fn tls_handshake_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = Ca::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost"]);
+    let config = tls::server_config(&cert, &key, None)?;
+    let limits = ConnectionLimits { idle_timeout: Some(Duration::from_secs(1)), ..ConnectionLimits::default() };
+
+    let addr: SocketAddr = "127.0.0.1:4064".parse().unwrap();
+    let mut server = KvsServer::new(addr, Arc::new(MemoryKvsEngine::new()));
+    server.set_tls(config.clone());
+    server.set_limits(limits.clone());
+    let rejections = server.rejections();
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+    assert!(trickle_handshake(addr) < Duration::from_secs(3));
+    assert_eq!(rejections.count(RejectReason::IdleTimeout), 1);
+
+    let addr: SocketAddr = "127.0.0.1:4065".parse().unwrap();
+    let mut server = AsyncKvsServer::new(addr, AsyncKvsEngine::new(Arc::new(MemoryKvsEngine::new()), 4));
+    server.set_tls(config);
+    server.set_limits(limits);
+    let rejections = server.rejections();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.handle_connection()).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+    assert!(trickle_handshake(addr) < Duration::from_secs(3));
+    assert_eq!(rejections.count(RejectReason::IdleTimeout), 1);
+
+    Ok(())
+}
+
+#[test]
+fn cli_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = Ca::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost", "127.0.0.1"]);
+
+    let addr = "127.0.0.1:4024";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .arg("--tls-cert").arg(&cert)
+        .arg("--tls-key").arg(&key)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--ca", "ca.pem", "set", "key1", "value1"])
+        .current_dir(dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--ca", "ca.pem", "--sni", "localhost", "get", "key1"])
+        .current_dir(dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
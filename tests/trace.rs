@@ -0,0 +1,56 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+use tempfile::TempDir;
+
+use kvs::{KvsClient, KvsServer, MemoryKvsEngine, SharedEngine, SpanExporter};
+
+#[test]
+fn server_span_export() -> kvs::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4054".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("spans.jsonl");
+    let engine: SharedEngine = Arc::new(MemoryKvsEngine::new());
+    let mut server = KvsServer::new(addr, engine);
+    server.set_span_exporter(Arc::new(SpanExporter::open(&path)?));
+    thread::spawn(move || server.handle_connection());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr);
+    client.set("key1", "value1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    drop(client);
+    let mut missing_client = KvsClient::new(addr);
+    missing_client.set_db("missing");
+    assert!(missing_client.get("key1").is_err());
+
+    let spans: Vec<Value> = fs::read_to_string(&path)?.lines()
+        .map(|line| {
+            let request: Value = serde_json::from_str(line).unwrap();
+            request["resourceSpans"][0]["scopeSpans"][0]["spans"][0].clone()
+        })
+        .collect();
+    assert_eq!(spans.len(), 3);
+    let names: Vec<&str> = spans.iter().map(|span| span["name"].as_str().unwrap()).collect();
+    assert_eq!(names, vec!["set", "get", "select"]);
+    // The requests of a connection share its trace
+    assert_eq!(spans[0]["traceId"], spans[1]["traceId"]);
+    assert_ne!(spans[0]["traceId"], spans[2]["traceId"]);
+    assert_eq!(spans[0]["traceId"].as_str().unwrap().len(), 32);
+    assert_ne!(spans[0]["spanId"], spans[1]["spanId"]);
+    assert_eq!(spans[1]["status"]["code"], 1);
+    assert_eq!(spans[2]["status"]["code"], 2);
+    assert_eq!(spans[2]["status"]["message"], "UnknownDatabase");
+    let start: u128 = spans[0]["startTimeUnixNano"].as_str().unwrap().parse().unwrap();
+    let end: u128 = spans[0]["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+    assert!(start <= end);
+    let attributes = spans[1]["attributes"].as_array().unwrap();
+    assert!(attributes.contains(&serde_json::json!({ "key": "kvs.request_id", "value": { "intValue": "2" } })));
+    assert!(attributes.contains(&serde_json::json!({ "key": "client.address", "value": { "stringValue": "127.0.0.1" } })));
+
+    Ok(())
+}